@@ -1,46 +1,2080 @@
-use futures::{SinkExt, StreamExt};
+use base64::{prelude::BASE64_STANDARD as standard, Engine};
+use chrono::Utc;
+use clap::Parser;
+use directories::ProjectDirs;
+use ed25519_dalek::{Signature, Verifier, VerifyingKey};
+use futures::{SinkExt, Stream, StreamExt};
+use rand::{rngs::OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs;
+use std::io::IsTerminal;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
-use tokio::net::TcpListener;
-use tokio_tungstenite::accept_async;
-use tokio_tungstenite::tungstenite::protocol::Message;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, oneshot, watch};
+use tokio::time::Instant;
+use tokio_native_tls::TlsAcceptor;
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::protocol::WebSocketConfig;
+use tokio_tungstenite::tungstenite::protocol::{CloseFrame, Message};
+use tokio_tungstenite::tungstenite::Error as WsError;
+use tokio_tungstenite::{accept_async_with_config, MaybeTlsStream, WebSocketStream};
+use tracing::{debug, error, info, warn, Instrument};
+use tracing_subscriber::EnvFilter;
+use vaultsyn::protocol::{
+    auth_message, key_id, negotiate_version, x25519_proof, ErrorCode, Frame, PrekeyBundle,
+    PROTOCOL_VERSIONS,
+};
+use x25519_dalek::{EphemeralSecret, PublicKey as X25519Public};
 
-type Tx =
-    futures::stream::SplitSink<tokio_tungstenite::WebSocketStream<tokio::net::TcpStream>, Message>;
-type Clients = Arc<Mutex<Vec<Tx>>>;
+/// Where the relay listens by default.
+const DEFAULT_BIND: &str = "127.0.0.1:9001";
+/// Frames waiting to be written to one connection.
+const DEFAULT_QUEUE_CAPACITY: usize = 256;
+/// How long a new connection has to answer the auth challenge.
+const AUTH_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long envelopes for offline recipients are kept, in seconds.
+const DEFAULT_OFFLINE_TTL: i64 = 7 * 24 * 60 * 60;
+/// Envelopes kept per offline recipient.
+const DEFAULT_OFFLINE_QUOTA: usize = 500;
+/// Envelopes kept per offline recipient from any one sender.
+const DEFAULT_OFFLINE_SENDER_QUOTA: usize = 100;
+/// Largest frame a client may send, in bytes.
+const DEFAULT_MAX_FRAME_SIZE: usize = 256 * 1024;
+/// Frames per second, and burst, allowed on one connection.
+const DEFAULT_RATE: u32 = 10;
+const DEFAULT_BURST: u32 = 50;
+/// Frames per second, and burst, allowed across an identity's connections.
+const DEFAULT_IDENTITY_RATE: u32 = 20;
+const DEFAULT_IDENTITY_BURST: u32 = 100;
+/// Open connections allowed in total, and from one IP address.
+const DEFAULT_MAX_CONNECTIONS: usize = 1024;
+const DEFAULT_MAX_CONNECTIONS_PER_IP: usize = 32;
+/// Violations within VIOLATION_WINDOW after which an address is banned.
+const DEFAULT_BAN_AFTER: u32 = 10;
+/// Seconds a ban lasts.
+const DEFAULT_BAN_DURATION: u64 = 300;
+/// How long violations count towards a ban.
+const VIOLATION_WINDOW: Duration = Duration::from_secs(60);
+/// Seconds between pings to each connection.
+const DEFAULT_HEARTBEAT_INTERVAL: u64 = 20;
+/// Seconds of silence after which a connection is presumed dead.
+const DEFAULT_HEARTBEAT_TIMEOUT: u64 = 60;
+/// Seconds connections get to drain their queues when the relay stops.
+const DEFAULT_SHUTDOWN_TIMEOUT: u64 = 10;
+/// Time after the shutdown deadline for close frames and requeuing.
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(2);
+/// Recipients one connection may watch for presence.
+const MAX_WATCHES: usize = 64;
+/// Log filter used unless one is configured.
+const DEFAULT_LOG: &str = "info";
+
+/// What to do with a connection whose outbound queue is full.
+#[derive(Clone, Copy, Debug, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "snake_case")]
+enum SlowConsumerPolicy {
+    /// Drop the frame for that connection and keep it open.
+    Drop,
+    /// Close the connection.
+    Disconnect,
+}
+
+/// Vaultsyn WebSocket relay.
+///
+/// Each setting is taken from its flag, then its environment variable, then
+/// the config file, then the default.
+#[derive(Parser)]
+#[command(name = "server", version)]
+struct Args {
+    /// TOML config file
+    #[arg(long, short, env = "VAULTSYN_RELAY_CONFIG")]
+    config: Option<PathBuf>,
+    /// Address to listen on [default: 127.0.0.1:9001]
+    #[arg(long, env = "VAULTSYN_RELAY_BIND")]
+    bind: Option<String>,
+    /// PEM certificate chain; with --key, the relay serves wss://
+    #[arg(long, env = "VAULTSYN_RELAY_CERT")]
+    cert: Option<PathBuf>,
+    /// PKCS#8 PEM private key of --cert
+    #[arg(long, env = "VAULTSYN_RELAY_KEY")]
+    key: Option<PathBuf>,
+    /// Directory of the offline queue and published prekeys [default: relay/ in the data directory]
+    #[arg(long, env = "VAULTSYN_RELAY_STORE")]
+    store: Option<PathBuf>,
+    /// Frames queued per connection before it counts as slow [default: 256]
+    #[arg(long, env = "VAULTSYN_RELAY_QUEUE")]
+    queue_capacity: Option<usize>,
+    /// What to do with connections whose queue is full [default: disconnect]
+    #[arg(long, value_enum, env = "VAULTSYN_SLOW_CONSUMER")]
+    slow_consumer: Option<SlowConsumerPolicy>,
+    /// Largest frame a client may send, in bytes [default: 262144]
+    #[arg(long, env = "VAULTSYN_RELAY_MAX_FRAME_SIZE")]
+    max_frame_size: Option<usize>,
+    /// Frames per second allowed on one connection [default: 10]
+    #[arg(long, env = "VAULTSYN_RELAY_RATE")]
+    rate: Option<u32>,
+    /// Frames one connection may send in a burst [default: 50]
+    #[arg(long, env = "VAULTSYN_RELAY_BURST")]
+    burst: Option<u32>,
+    /// Frames per second allowed across all connections of an identity [default: 20]
+    #[arg(long, env = "VAULTSYN_RELAY_IDENTITY_RATE")]
+    identity_rate: Option<u32>,
+    /// Frames an identity may send in a burst [default: 100]
+    #[arg(long, env = "VAULTSYN_RELAY_IDENTITY_BURST")]
+    identity_burst: Option<u32>,
+    /// Open connections allowed in total [default: 1024]
+    #[arg(long, env = "VAULTSYN_RELAY_MAX_CONNECTIONS")]
+    max_connections: Option<usize>,
+    /// Open connections allowed from one IP address [default: 32]
+    #[arg(long, env = "VAULTSYN_RELAY_MAX_CONNECTIONS_PER_IP")]
+    max_connections_per_ip: Option<usize>,
+    /// Violations within a minute after which an IP address is banned [default: 10]
+    #[arg(long, env = "VAULTSYN_RELAY_BAN_AFTER")]
+    ban_after: Option<u32>,
+    /// Seconds an IP address stays banned [default: 300]
+    #[arg(long, env = "VAULTSYN_RELAY_BAN_DURATION")]
+    ban_duration: Option<u64>,
+    /// Seconds to keep envelopes for offline recipients [default: 604800]
+    #[arg(long, env = "VAULTSYN_RELAY_TTL")]
+    offline_ttl: Option<i64>,
+    /// Envelopes kept per offline recipient [default: 500]
+    #[arg(long, env = "VAULTSYN_RELAY_QUOTA")]
+    offline_quota: Option<usize>,
+    /// Envelopes kept per offline recipient from any one sender [default: 100]
+    #[arg(long, env = "VAULTSYN_RELAY_SENDER_QUOTA")]
+    offline_sender_quota: Option<usize>,
+    /// Seconds between pings to each connection [default: 20]
+    #[arg(long, env = "VAULTSYN_RELAY_HEARTBEAT")]
+    heartbeat_interval: Option<u64>,
+    /// Seconds without any frame, pongs included, before a connection is dropped [default: 60]
+    #[arg(long, env = "VAULTSYN_RELAY_HEARTBEAT_TIMEOUT")]
+    heartbeat_timeout: Option<u64>,
+    /// Seconds to drain connections on SIGINT or SIGTERM before closing them [default: 10]
+    #[arg(long, env = "VAULTSYN_RELAY_SHUTDOWN_TIMEOUT")]
+    shutdown_timeout: Option<u64>,
+    /// Log filter, such as "debug" or "warn,server=info" [default: info]
+    #[arg(long, env = "VAULTSYN_LOG")]
+    log: Option<String>,
+}
+
+/// The --config file. Every key is optional.
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct FileConfig {
+    bind: Option<String>,
+    log: Option<String>,
+    tls: TlsSection,
+    limits: LimitsSection,
+    heartbeat: HeartbeatSection,
+    shutdown: ShutdownSection,
+    storage: StorageSection,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct TlsSection {
+    cert: Option<PathBuf>,
+    key: Option<PathBuf>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct LimitsSection {
+    queue_capacity: Option<usize>,
+    slow_consumer: Option<SlowConsumerPolicy>,
+    offline_ttl: Option<i64>,
+    offline_quota: Option<usize>,
+    offline_sender_quota: Option<usize>,
+    max_frame_size: Option<usize>,
+    rate: Option<u32>,
+    burst: Option<u32>,
+    identity_rate: Option<u32>,
+    identity_burst: Option<u32>,
+    max_connections: Option<usize>,
+    max_connections_per_ip: Option<usize>,
+    ban_after: Option<u32>,
+    ban_duration: Option<u64>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct HeartbeatSection {
+    interval: Option<u64>,
+    timeout: Option<u64>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ShutdownSection {
+    timeout: Option<u64>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct StorageSection {
+    store: Option<PathBuf>,
+}
+
+/// Limits on what a client may send and how many connections it may open.
+#[derive(Clone, Copy)]
+struct Limits {
+    max_frame_size: usize,
+    rate: u32,
+    burst: u32,
+    identity_rate: u32,
+    identity_burst: u32,
+    max_connections: usize,
+    max_connections_per_ip: usize,
+    ban_after: u32,
+    ban_duration: Duration,
+}
+
+struct Config {
+    bind: String,
+    log: String,
+    /// PEM certificate chain and PKCS#8 key; the relay speaks wss:// when set.
+    tls: Option<(PathBuf, PathBuf)>,
+    queue_capacity: usize,
+    slow_consumer: SlowConsumerPolicy,
+    store_dir: PathBuf,
+    offline_ttl: i64,
+    offline_quota: usize,
+    offline_sender_quota: usize,
+    limits: Limits,
+    heartbeat_interval: Duration,
+    heartbeat_timeout: Duration,
+    shutdown_timeout: Duration,
+}
+
+fn positive<T: PartialOrd + Default>(
+    name: &str,
+    value: Option<T>,
+    default: T,
+) -> Result<T, String> {
+    match value {
+        Some(value) if value <= T::default() => Err(format!("❌ {} must be positive", name)),
+        Some(value) => Ok(value),
+        None => Ok(default),
+    }
+}
+
+impl Config {
+    fn load(args: Args) -> Result<Self, String> {
+        let file: FileConfig = match &args.config {
+            Some(path) => {
+                let text = fs::read_to_string(path)
+                    .map_err(|e| format!("❌ Cannot read config {}: {}", path.display(), e))?;
+                toml::from_str(&text)
+                    .map_err(|e| format!("❌ Invalid config {}: {}", path.display(), e))?
+            }
+            None => FileConfig::default(),
+        };
+        let tls = match (args.cert.or(file.tls.cert), args.key.or(file.tls.key)) {
+            (Some(cert), Some(key)) => Some((cert, key)),
+            (None, None) => None,
+            _ => return Err("❌ The TLS certificate and key must be given together".into()),
+        };
+        let store_dir = match args.store.or(file.storage.store) {
+            Some(dir) => dir,
+            None => ProjectDirs::from("com", "vaultsyn", "vaultsyn")
+                .ok_or("❌ No data directory found, set the relay store explicitly")?
+                .data_local_dir()
+                .join("relay"),
+        };
+        let limits = file.limits;
+        let heartbeat_interval = positive(
+            "heartbeat_interval",
+            args.heartbeat_interval.or(file.heartbeat.interval),
+            DEFAULT_HEARTBEAT_INTERVAL,
+        )?;
+        let heartbeat_timeout = positive(
+            "heartbeat_timeout",
+            args.heartbeat_timeout.or(file.heartbeat.timeout),
+            DEFAULT_HEARTBEAT_TIMEOUT,
+        )?;
+        // Otherwise connections are dropped before they can answer a ping
+        if heartbeat_timeout <= heartbeat_interval {
+            return Err("❌ heartbeat_timeout must be longer than heartbeat_interval".into());
+        }
+        Ok(Config {
+            bind: args
+                .bind
+                .or(file.bind)
+                .unwrap_or_else(|| DEFAULT_BIND.into()),
+            log: args.log.or(file.log).unwrap_or_else(|| DEFAULT_LOG.into()),
+            tls,
+            queue_capacity: positive(
+                "queue_capacity",
+                args.queue_capacity.or(limits.queue_capacity),
+                DEFAULT_QUEUE_CAPACITY,
+            )?,
+            slow_consumer: args
+                .slow_consumer
+                .or(limits.slow_consumer)
+                .unwrap_or(SlowConsumerPolicy::Disconnect),
+            store_dir,
+            offline_ttl: positive(
+                "offline_ttl",
+                args.offline_ttl.or(limits.offline_ttl),
+                DEFAULT_OFFLINE_TTL,
+            )?,
+            offline_quota: positive(
+                "offline_quota",
+                args.offline_quota.or(limits.offline_quota),
+                DEFAULT_OFFLINE_QUOTA,
+            )?,
+            offline_sender_quota: positive(
+                "offline_sender_quota",
+                args.offline_sender_quota.or(limits.offline_sender_quota),
+                DEFAULT_OFFLINE_SENDER_QUOTA,
+            )?,
+            limits: Limits {
+                max_frame_size: positive(
+                    "max_frame_size",
+                    args.max_frame_size.or(limits.max_frame_size),
+                    DEFAULT_MAX_FRAME_SIZE,
+                )?,
+                rate: positive("rate", args.rate.or(limits.rate), DEFAULT_RATE)?,
+                burst: positive("burst", args.burst.or(limits.burst), DEFAULT_BURST)?,
+                identity_rate: positive(
+                    "identity_rate",
+                    args.identity_rate.or(limits.identity_rate),
+                    DEFAULT_IDENTITY_RATE,
+                )?,
+                identity_burst: positive(
+                    "identity_burst",
+                    args.identity_burst.or(limits.identity_burst),
+                    DEFAULT_IDENTITY_BURST,
+                )?,
+                max_connections: positive(
+                    "max_connections",
+                    args.max_connections.or(limits.max_connections),
+                    DEFAULT_MAX_CONNECTIONS,
+                )?,
+                max_connections_per_ip: positive(
+                    "max_connections_per_ip",
+                    args.max_connections_per_ip
+                        .or(limits.max_connections_per_ip),
+                    DEFAULT_MAX_CONNECTIONS_PER_IP,
+                )?,
+                ban_after: positive(
+                    "ban_after",
+                    args.ban_after.or(limits.ban_after),
+                    DEFAULT_BAN_AFTER,
+                )?,
+                ban_duration: Duration::from_secs(positive(
+                    "ban_duration",
+                    args.ban_duration.or(limits.ban_duration),
+                    DEFAULT_BAN_DURATION,
+                )?),
+            },
+            heartbeat_interval: Duration::from_secs(heartbeat_interval),
+            heartbeat_timeout: Duration::from_secs(heartbeat_timeout),
+            shutdown_timeout: Duration::from_secs(positive(
+                "shutdown_timeout",
+                args.shutdown_timeout.or(file.shutdown.timeout),
+                DEFAULT_SHUTDOWN_TIMEOUT,
+            )?),
+        })
+    }
+}
+
+/// SHA-256 of a DER certificate, as colon separated hex like openssl prints.
+fn cert_fingerprint(der: &[u8]) -> String {
+    Sha256::digest(der)
+        .iter()
+        .map(|byte| format!("{:02X}", byte))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+// Builds the TLS acceptor, and returns it with the fingerprint of the leaf
+// certificate, which clients can pin.
+fn load_tls(cert: &Path, key: &Path) -> Result<(TlsAcceptor, String), String> {
+    let cert_pem = fs::read(cert)
+        .map_err(|e| format!("❌ Cannot read certificate {}: {}", cert.display(), e))?;
+    let key_pem =
+        fs::read(key).map_err(|e| format!("❌ Cannot read key {}: {}", key.display(), e))?;
+    let identity = native_tls::Identity::from_pkcs8(&cert_pem, &key_pem)
+        .map_err(|e| format!("❌ Invalid certificate or PKCS#8 key: {}", e))?;
+    let leaf = native_tls::Certificate::from_pem(&cert_pem)
+        .and_then(|cert| cert.to_der())
+        .map_err(|e| format!("❌ Invalid certificate: {}", e))?;
+    let acceptor = native_tls::TlsAcceptor::new(identity)
+        .map_err(|e| format!("❌ Cannot set up TLS: {}", e))?;
+    Ok((acceptor.into(), cert_fingerprint(&leaf)))
+}
+
+type WsConnection = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+// Runs the TLS handshake when the relay has a certificate, then the
+// WebSocket one. Both share AUTH_TIMEOUT, so a peer that goes quiet halfway
+// does not hold its task forever.
+async fn accept_connection(
+    stream: TcpStream,
+    tls: Option<&TlsAcceptor>,
+    max_frame_size: usize,
+) -> Result<WsConnection, String> {
+    let handshake = async {
+        let stream = match tls {
+            Some(acceptor) => MaybeTlsStream::NativeTls(
+                acceptor
+                    .accept(stream)
+                    .await
+                    .map_err(|e| format!("TLS handshake failed: {}", e))?,
+            ),
+            None => MaybeTlsStream::Plain(stream),
+        };
+        let config = WebSocketConfig {
+            max_message_size: Some(max_frame_size),
+            max_frame_size: Some(max_frame_size),
+            ..WebSocketConfig::default()
+        };
+        accept_async_with_config(stream, Some(config))
+            .await
+            .map_err(|e| format!("WebSocket handshake failed: {}", e))
+    };
+    tokio::time::timeout(AUTH_TIMEOUT, handshake)
+        .await
+        .map_err(|_| String::from("handshake timed out"))?
+}
+
+/// Log entries an offline queue may hold before it is compacted, on top of
+/// twice its live envelopes.
+const COMPACTION_SLACK: usize = 64;
+
+enum DiskOp {
+    Append(PathBuf, String),
+    Replace(PathBuf, String),
+    Remove(PathBuf),
+    Flush(std::sync::mpsc::Sender<()>),
+    /// Answers whether every write to the path since its last `Sync` reached
+    /// the disk.
+    Sync(PathBuf, oneshot::Sender<bool>),
+}
+
+// Makes a file's creation, removal or renaming durable
+fn sync_dir(path: &Path) -> std::io::Result<()> {
+    match path.parent() {
+        Some(dir) => fs::File::open(dir)?.sync_all(),
+        None => Ok(()),
+    }
+}
+
+/// Writes relay store files on a thread of its own, in the order the
+/// operations were sent, so nobody waits on the disk while holding a lock.
+/// Every write is synced to the disk before the next one starts.
+#[derive(Clone)]
+struct DiskWriter {
+    ops: std::sync::mpsc::Sender<DiskOp>,
+}
+
+impl DiskWriter {
+    fn spawn() -> Self {
+        let (ops, queue) = std::sync::mpsc::channel::<DiskOp>();
+        std::thread::spawn(move || {
+            // Paths with a failed write since their last sync
+            let mut failed = HashSet::new();
+            for op in queue {
+                let (path, result) = match op {
+                    DiskOp::Append(path, line) => {
+                        let created = !path.exists();
+                        let result = fs::OpenOptions::new()
+                            .create(true)
+                            .append(true)
+                            .open(&path)
+                            .and_then(|mut file| {
+                                std::io::Write::write_all(&mut file, line.as_bytes())?;
+                                file.sync_data()
+                            })
+                            .and_then(|_| if created { sync_dir(&path) } else { Ok(()) });
+                        (path, result)
+                    }
+                    DiskOp::Replace(path, contents) => {
+                        // Write, sync then rename, so a crash leaves either
+                        // the old file or the new one, never a torn one
+                        let tmp = path.with_extension("tmp");
+                        let result = fs::File::create(&tmp)
+                            .and_then(|mut file| {
+                                std::io::Write::write_all(&mut file, contents.as_bytes())?;
+                                file.sync_all()
+                            })
+                            .and_then(|_| fs::rename(&tmp, &path))
+                            .and_then(|_| sync_dir(&path));
+                        (path, result)
+                    }
+                    DiskOp::Remove(path) => {
+                        let result = match fs::remove_file(&path) {
+                            Ok(()) => sync_dir(&path),
+                            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+                            Err(e) => Err(e),
+                        };
+                        (path, result)
+                    }
+                    DiskOp::Flush(done) => {
+                        let _ = done.send(());
+                        continue;
+                    }
+                    DiskOp::Sync(path, done) => {
+                        let _ = done.send(!failed.remove(&path));
+                        continue;
+                    }
+                };
+                if let Err(e) = result {
+                    error!(path = %path.display(), error = %e, "failed to write relay store");
+                    failed.insert(path);
+                }
+            }
+        });
+        DiskWriter { ops }
+    }
+
+    fn send(&self, op: DiskOp) {
+        // The thread only stops once every writer is dropped
+        let _ = self.ops.send(op);
+    }
+
+    /// Blocks until everything sent so far has been written.
+    fn flush(&self) {
+        let (done, wait) = std::sync::mpsc::channel();
+        self.send(DiskOp::Flush(done));
+        let _ = wait.recv();
+    }
+
+    /// Resolves to whether everything sent so far for `path` reached the
+    /// disk.
+    fn synced(&self, path: PathBuf) -> oneshot::Receiver<bool> {
+        let (done, synced) = oneshot::channel();
+        self.send(DiskOp::Sync(path, done));
+        synced
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct QueuedFrame {
+    message_id: String,
+    /// The sender's Ed25519 key; empty in logs written before it was kept.
+    #[serde(default)]
+    sender: String,
+    queued_at: i64,
+    frame: String,
+}
+
+impl QueuedFrame {
+    // The frame as sent to the recipient, stamped with when it was queued
+    // so the recipient checks the envelope's age against that.
+    fn into_delivery(self) -> String {
+        match serde_json::from_str::<Frame>(&self.frame) {
+            Ok(Frame::Deliver { transport, .. }) => serde_json::to_string(&Frame::Deliver {
+                transport,
+                queued_at: Some(self.queued_at),
+            })
+            .unwrap_or(self.frame),
+            _ => self.frame,
+        }
+    }
+}
+
+/// One line of an offline queue log.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum LogEntry {
+    Push(QueuedFrame),
+    Ack(String),
+}
+
+/// Envelopes for recipients that were offline, oldest first. Each recipient's
+/// queue is mirrored to the append-only log `<dir>/<recipient_key_id>.log`,
+/// which is rewritten with only the live envelopes once it has grown well
+/// past them. An envelope is only removed once the recipient acks it or it
+/// expires.
+struct OfflineQueue {
+    dir: PathBuf,
+    ttl: i64,
+    quota: usize,
+    sender_quota: usize,
+    pending: HashMap<String, VecDeque<QueuedFrame>>,
+    /// Lines in each recipient's log, live or not.
+    log_lines: HashMap<String, usize>,
+    disk: DiskWriter,
+}
+
+// Replays a log, skipping lines that do not parse, such as one torn by a crash
+fn read_log(path: &Path) -> Option<(VecDeque<QueuedFrame>, usize)> {
+    let text = fs::read_to_string(path).ok()?;
+    let mut frames = VecDeque::new();
+    let mut lines = 0;
+    for line in text.lines().filter(|line| !line.is_empty()) {
+        lines += 1;
+        match serde_json::from_str(line) {
+            Ok(LogEntry::Push(frame)) => frames.push_back(frame),
+            Ok(LogEntry::Ack(message_id)) => {
+                frames.retain(|queued: &QueuedFrame| queued.message_id != message_id)
+            }
+            Err(_) => warn!(path = %path.display(), "skipping unreadable offline queue entry"),
+        }
+    }
+    Some((frames, lines))
+}
+
+impl OfflineQueue {
+    fn open(
+        dir: PathBuf,
+        ttl: i64,
+        quota: usize,
+        sender_quota: usize,
+        disk: DiskWriter,
+    ) -> Result<Self, String> {
+        fs::create_dir_all(&dir)
+            .map_err(|e| format!("❌ Cannot create relay store {}: {}", dir.display(), e))?;
+        let mut queue = OfflineQueue {
+            dir,
+            ttl,
+            quota,
+            sender_quota,
+            pending: HashMap::new(),
+            log_lines: HashMap::new(),
+            disk,
+        };
+        let mut legacy = Vec::new();
+        for entry in fs::read_dir(&queue.dir).into_iter().flatten().flatten() {
+            let path = entry.path();
+            let Some(recipient) = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .filter(|stem| valid_key_id(stem))
+                .map(str::to_string)
+            else {
+                continue;
+            };
+            match path.extension().and_then(|ext| ext.to_str()) {
+                Some("log") => match read_log(&path) {
+                    Some((frames, lines)) => {
+                        queue.pending.insert(recipient.clone(), frames);
+                        queue.log_lines.insert(recipient, lines);
+                    }
+                    None => warn!(path = %path.display(), "skipping unreadable offline queue"),
+                },
+                // Whole-queue snapshots written by earlier versions
+                Some("json") => match fs::read_to_string(&path)
+                    .ok()
+                    .and_then(|json| serde_json::from_str::<VecDeque<QueuedFrame>>(&json).ok())
+                {
+                    Some(frames) => {
+                        queue
+                            .pending
+                            .entry(recipient.clone())
+                            .or_default()
+                            .extend(frames);
+                        legacy.push((recipient, path));
+                    }
+                    None => warn!(path = %path.display(), "skipping unreadable offline queue"),
+                },
+                _ => {}
+            }
+        }
+        for (recipient, path) in legacy {
+            queue.compact(&recipient);
+            queue.disk.send(DiskOp::Remove(path));
+        }
+        let recipients: Vec<String> = queue.pending.keys().cloned().collect();
+        for recipient in recipients {
+            queue.expire(&recipient);
+            if queue
+                .pending
+                .get(&recipient)
+                .is_some_and(VecDeque::is_empty)
+            {
+                queue.compact(&recipient);
+            }
+        }
+        Ok(queue)
+    }
+
+    fn log_path(&self, recipient: &str) -> PathBuf {
+        self.dir.join(format!("{}.log", recipient))
+    }
+
+    // Rewrites the log with only the live envelopes, or removes it
+    fn compact(&mut self, recipient: &str) {
+        let path = self.log_path(recipient);
+        match self
+            .pending
+            .get(recipient)
+            .filter(|frames| !frames.is_empty())
+        {
+            Some(frames) => {
+                let mut contents = String::new();
+                for frame in frames {
+                    contents
+                        .push_str(&serde_json::to_string(&LogEntry::Push(frame.clone())).unwrap());
+                    contents.push('\n');
+                }
+                self.log_lines.insert(recipient.to_string(), frames.len());
+                self.disk.send(DiskOp::Replace(path, contents));
+            }
+            None => {
+                self.pending.remove(recipient);
+                self.log_lines.remove(recipient);
+                self.disk.send(DiskOp::Remove(path));
+            }
+        }
+    }
+
+    fn append(&mut self, recipient: &str, entry: &LogEntry) {
+        let live = self.pending.get(recipient).map_or(0, VecDeque::len);
+        let lines = self.log_lines.entry(recipient.to_string()).or_default();
+        *lines += 1;
+        if *lines > 2 * live + COMPACTION_SLACK {
+            self.compact(recipient);
+            return;
+        }
+        let mut line = serde_json::to_string(entry).unwrap();
+        line.push('\n');
+        self.disk
+            .send(DiskOp::Append(self.log_path(recipient), line));
+    }
+
+    fn expire(&mut self, recipient: &str) {
+        let cutoff = Utc::now().timestamp() - self.ttl;
+        if let Some(frames) = self.pending.get_mut(recipient) {
+            let before = frames.len();
+            frames.retain(|frame| frame.queued_at > cutoff);
+            if frames.len() != before {
+                self.compact(recipient);
+            }
+        }
+    }
+
+    fn push(
+        &mut self,
+        recipient: &str,
+        sender: &str,
+        message_id: &str,
+        frame: &str,
+    ) -> Result<(), (ErrorCode, String)> {
+        self.expire(recipient);
+        let frames = self.pending.entry(recipient.to_string()).or_default();
+        if frames.iter().any(|queued| queued.message_id == message_id) {
+            return Ok(());
+        }
+        if frames.len() >= self.quota {
+            return Err((
+                ErrorCode::QueueFull,
+                format!("Too many messages are waiting for recipient {}", recipient),
+            ));
+        }
+        // So no one sender can fill a recipient's queue
+        if frames
+            .iter()
+            .filter(|queued| queued.sender == sender)
+            .count()
+            >= self.sender_quota
+        {
+            return Err((
+                ErrorCode::QueueFull,
+                format!(
+                    "Too many of your messages are waiting for recipient {}",
+                    recipient
+                ),
+            ));
+        }
+        let queued = QueuedFrame {
+            message_id: message_id.to_string(),
+            sender: sender.to_string(),
+            queued_at: Utc::now().timestamp(),
+            frame: frame.to_string(),
+        };
+        frames.push_back(queued.clone());
+        self.append(recipient, &LogEntry::Push(queued));
+        Ok(())
+    }
+
+    fn pending(&mut self, recipient: &str) -> Vec<QueuedFrame> {
+        self.expire(recipient);
+        self.pending
+            .get(recipient)
+            .map(|frames| frames.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    fn ack(&mut self, recipient: &str, message_id: &str) {
+        if let Some(frames) = self.pending.get_mut(recipient) {
+            let before = frames.len();
+            frames.retain(|queued| queued.message_id != message_id);
+            if frames.is_empty() {
+                self.compact(recipient);
+            } else if frames.len() != before {
+                self.append(recipient, &LogEntry::Ack(message_id.to_string()));
+            }
+        }
+    }
+}
+
+type Offline = Arc<Mutex<OfflineQueue>>;
+
+/// Handle to a connection's outbound queue, drained by its writer task.
+#[derive(Clone)]
+struct Outbound {
+    conn_id: usize,
+    queue: mpsc::Sender<Message>,
+    // Set to close the connection with this frame
+    kick: Arc<watch::Sender<Option<CloseFrame<'static>>>>,
+}
+
+impl Outbound {
+    /// Closes the connection after the frames already queued for it. If the
+    /// queue is full, closes it straight away instead.
+    fn finish(&self, code: CloseCode, reason: &'static str) {
+        let close = CloseFrame {
+            code,
+            reason: reason.into(),
+        };
+        if self.queue.try_send(Message::Close(Some(close))).is_err() {
+            self.close(code, reason);
+        }
+    }
+
+    /// Closes the connection, skipping anything still queued for it.
+    fn close(&self, code: CloseCode, reason: &'static str) {
+        let _ = self.kick.send(Some(CloseFrame {
+            code,
+            reason: reason.into(),
+        }));
+    }
+
+    /// Queues a frame without waiting. Returns false if the connection is
+    /// gone or was disconnected for being too slow.
+    fn deliver(&self, msg: Message, policy: SlowConsumerPolicy) -> bool {
+        match self.queue.try_send(msg) {
+            Ok(()) => true,
+            Err(mpsc::error::TrySendError::Closed(_)) => false,
+            Err(mpsc::error::TrySendError::Full(_)) => match policy {
+                SlowConsumerPolicy::Drop => {
+                    warn!(
+                        to_conn = self.conn_id,
+                        "outbound queue full, dropped a frame"
+                    );
+                    true
+                }
+                SlowConsumerPolicy::Disconnect => {
+                    warn!(to_conn = self.conn_id, "outbound queue full, disconnecting");
+                    self.close(CloseCode::Policy, "too slow to keep up");
+                    false
+                }
+            },
+        }
+    }
+
+    fn respond(&self, response: &Frame, policy: SlowConsumerPolicy) {
+        let json = serde_json::to_string(response).unwrap();
+        self.deliver(Message::Text(json), policy);
+    }
+}
+
+fn error_frame(code: ErrorCode, message: impl Into<String>) -> Frame {
+    Frame::Error {
+        code,
+        message: message.into(),
+    }
+}
+
+/// The keys a connection proved it holds during the handshake.
+struct AuthedIdentity {
+    ed25519_public: String,
+    /// `key_id` of its Ed25519 key, which names it in logs and rate limits.
+    key_id: String,
+    x25519_public: String,
+    /// Where envelopes for this identity are routed: `key_id` of its X25519 key.
+    recipient_key_id: String,
+}
+
+fn decode_32(encoded: &str) -> Option<[u8; 32]> {
+    <[u8; 32]>::try_from(standard.decode(encoded).ok()?).ok()
+}
+
+fn verify_auth(
+    frame: Frame,
+    challenge: &str,
+    relay_x25519: &str,
+    relay_secret: EphemeralSecret,
+) -> Result<AuthedIdentity, (ErrorCode, String)> {
+    let Frame::Auth {
+        version,
+        ed25519_public,
+        x25519_public,
+        signature,
+        x25519_proof: proof,
+    } = frame
+    else {
+        return Err((
+            ErrorCode::AuthFailed,
+            String::from("Expected an auth frame"),
+        ));
+    };
+    if negotiate_version(&PROTOCOL_VERSIONS, &[version]).is_none() {
+        return Err((
+            ErrorCode::UnsupportedVersion,
+            format!(
+                "Protocol version {} is not supported, this relay speaks {:?}",
+                version, PROTOCOL_VERSIONS
+            ),
+        ));
+    }
+    let failed = |message: &str| (ErrorCode::AuthFailed, message.to_string());
+    let verifying_key = decode_32(&ed25519_public)
+        .and_then(|bytes| VerifyingKey::from_bytes(&bytes).ok())
+        .ok_or_else(|| failed("Invalid Ed25519 public key"))?;
+    let signature = standard
+        .decode(&signature)
+        .ok()
+        .and_then(|bytes| Signature::from_slice(&bytes).ok())
+        .ok_or_else(|| failed("Invalid signature encoding"))?;
+    verifying_key
+        .verify(
+            &auth_message(
+                version,
+                challenge,
+                relay_x25519,
+                &ed25519_public,
+                &x25519_public,
+            ),
+            &signature,
+        )
+        .map_err(|_| failed("Signature does not match the challenge"))?;
+    let client_x25519 =
+        decode_32(&x25519_public).ok_or_else(|| failed("Invalid X25519 public key"))?;
+    let shared = relay_secret.diffie_hellman(&X25519Public::from(client_x25519));
+    if x25519_proof(shared.as_bytes(), challenge) != proof {
+        return Err(failed("X25519 proof does not match"));
+    }
+    Ok(AuthedIdentity {
+        key_id: key_id(&ed25519_public).map_err(|_| failed("Invalid Ed25519 public key"))?,
+        recipient_key_id: key_id(&x25519_public)
+            .map_err(|_| failed("Invalid X25519 public key"))?,
+        ed25519_public,
+        x25519_public,
+    })
+}
+
+/// Greets a new connection with the versions we speak and a challenge to
+/// prove which identity it is. Nothing else is accepted before that; a
+/// wrong or late answer closes the connection.
+async fn authenticate<S, E>(
+    rx: &mut S,
+    outbound: &Outbound,
+    policy: SlowConsumerPolicy,
+) -> Option<AuthedIdentity>
+where
+    S: Stream<Item = Result<Message, E>> + Unpin,
+{
+    let mut nonce = [0u8; 32];
+    OsRng.fill_bytes(&mut nonce);
+    let challenge = standard.encode(nonce);
+    let relay_secret = EphemeralSecret::random_from_rng(OsRng);
+    let relay_x25519 = standard.encode(X25519Public::from(&relay_secret).as_bytes());
+    outbound.respond(
+        &Frame::Hello {
+            versions: PROTOCOL_VERSIONS.to_vec(),
+            challenge: challenge.clone(),
+            relay_x25519: relay_x25519.clone(),
+        },
+        policy,
+    );
+
+    let answer = tokio::time::timeout(AUTH_TIMEOUT, async {
+        loop {
+            match rx.next().await {
+                Some(Ok(Message::Text(txt))) => return Some(txt),
+                Some(Ok(_)) => continue,
+                _ => return None,
+            }
+        }
+    })
+    .await;
+    // A frame we cannot even parse most likely comes from a client that
+    // predates version negotiation
+    let result = match answer {
+        Ok(Some(txt)) => serde_json::from_str::<Frame>(&txt)
+            .map_err(|_| {
+                (
+                    ErrorCode::UnsupportedVersion,
+                    format!(
+                        "Unrecognised frame, this relay speaks protocol versions {:?}",
+                        PROTOCOL_VERSIONS
+                    ),
+                )
+            })
+            .and_then(|frame| verify_auth(frame, &challenge, &relay_x25519, relay_secret)),
+        Ok(None) => return None,
+        Err(_) => Err((
+            ErrorCode::AuthFailed,
+            String::from("Timed out waiting for auth"),
+        )),
+    };
+    match result {
+        Ok(identity) => {
+            outbound.respond(
+                &Frame::Authenticated {
+                    recipient_key_id: identity.recipient_key_id.clone(),
+                },
+                policy,
+            );
+            Some(identity)
+        }
+        Err((code, message)) => {
+            info!(reason = %message, "authentication failed");
+            outbound.respond(&error_frame(code, message), policy);
+            None
+        }
+    }
+}
+
+// Connections listening for each recipient key id
+type Clients = Arc<Mutex<HashMap<String, Vec<Outbound>>>>;
+// Connections following each recipient key id's presence
+type Watchers = Clients;
+/// The part of a message frame the relay reads to route it.
+#[derive(Deserialize)]
+struct RoutingInfo {
+    envelope: RoutingHeader,
+    #[serde(default)]
+    sender_ed25519_pub: String,
+}
+
+#[derive(Deserialize)]
+struct RoutingHeader {
+    recipient_key_id: String,
+    #[serde(default)]
+    message_id: String,
+}
+
+enum Routed {
+    Live(Vec<Outbound>),
+    /// Queued offline, durably once the receiver yields true.
+    Queued(String, oneshot::Receiver<bool>),
+}
+
+// A key id as `key_id` makes it, which is what watches must name
+fn is_key_id(id: &str) -> bool {
+    id.len() == 16 && id.chars().all(|c| matches!(c, '0'..='9' | 'a'..='f'))
+}
+
+// Key ids name queue files, so only plain ids are accepted
+fn valid_key_id(id: &str) -> bool {
+    !id.is_empty() && id.len() <= 64 && id.chars().all(|c| c.is_ascii_alphanumeric())
+}
+
+#[derive(Serialize, Deserialize)]
+struct StoredBundle {
+    bundle: PrekeyBundle,
+    /// Public halves of one-time prekeys already handed out. They are never
+    /// served again, even when the owner republishes them before learning
+    /// they were used.
+    #[serde(default)]
+    handed_out: HashSet<String>,
+}
+
+/// The latest published bundle per Ed25519 identity, each mirrored to
+/// `<dir>/<identity_key_id>.json` so it survives a restart.
+struct PrekeyDirectory {
+    dir: PathBuf,
+    bundles: HashMap<String, StoredBundle>,
+    disk: DiskWriter,
+}
+
+impl PrekeyDirectory {
+    fn open(dir: PathBuf, disk: DiskWriter) -> Result<Self, String> {
+        fs::create_dir_all(&dir)
+            .map_err(|e| format!("❌ Cannot create prekey store {}: {}", dir.display(), e))?;
+        let mut bundles = HashMap::new();
+        for entry in fs::read_dir(&dir).into_iter().flatten().flatten() {
+            let path = entry.path();
+            if path.extension().is_some_and(|ext| ext == "json") {
+                match fs::read_to_string(&path)
+                    .ok()
+                    .and_then(|json| serde_json::from_str::<StoredBundle>(&json).ok())
+                {
+                    Some(stored) => {
+                        bundles.insert(stored.bundle.ed25519_public.clone(), stored);
+                    }
+                    None => warn!(path = %path.display(), "skipping unreadable prekey bundle"),
+                }
+            }
+        }
+        Ok(PrekeyDirectory { dir, bundles, disk })
+    }
+
+    fn save(&self, ed25519_public: &str) {
+        // Only bundles whose keys checked out are stored
+        if let (Some(stored), Ok(id)) = (self.bundles.get(ed25519_public), key_id(ed25519_public)) {
+            let path = self.dir.join(format!("{}.json", id));
+            self.disk.send(DiskOp::Replace(
+                path,
+                serde_json::to_string(stored).unwrap(),
+            ));
+        }
+    }
+
+    fn publish(&mut self, mut bundle: PrekeyBundle) {
+        let ed25519_public = bundle.ed25519_public.clone();
+        let mut handed_out = self
+            .bundles
+            .remove(&ed25519_public)
+            .map(|stored| stored.handed_out)
+            .unwrap_or_default();
+        // Prekeys missing from the new bundle were used and retired, so
+        // only the ones still offered need remembering
+        handed_out.retain(|public| {
+            bundle
+                .one_time_prekeys
+                .iter()
+                .any(|prekey| prekey.public == *public)
+        });
+        bundle
+            .one_time_prekeys
+            .retain(|prekey| !handed_out.contains(&prekey.public));
+        self.bundles
+            .insert(ed25519_public.clone(), StoredBundle { bundle, handed_out });
+        self.save(&ed25519_public);
+    }
+
+    /// The bundle for an Ed25519 or X25519 key, with at most one one-time
+    /// prekey, which is then never handed out again.
+    fn fetch(&mut self, identity: &str) -> Option<PrekeyBundle> {
+        let stored = self.bundles.values_mut().find(|stored| {
+            stored.bundle.ed25519_public == identity || stored.bundle.x25519_public == identity
+        })?;
+        let one_time_prekey = stored.bundle.one_time_prekeys.pop();
+        let mut handed_out = stored.bundle.clone();
+        handed_out.one_time_prekeys = Vec::new();
+        if let Some(prekey) = one_time_prekey {
+            stored.handed_out.insert(prekey.public.clone());
+            handed_out.one_time_prekeys.push(prekey);
+            let ed25519_public = stored.bundle.ed25519_public.clone();
+            self.save(&ed25519_public);
+        }
+        Some(handed_out)
+    }
+}
+
+type Bundles = Arc<Mutex<PrekeyDirectory>>;
+
+// Answers the frames that only concern this connection. Deliver, Ack and
+// Watch need more state and are handled by the caller.
+fn handle_frame(frame: Frame, identity: &AuthedIdentity, bundles: &Bundles) -> Option<Frame> {
+    match frame {
+        // Only the identity's own bundle may be replaced
+        Frame::PublishPrekeys { bundle }
+            if bundle.ed25519_public != identity.ed25519_public
+                || bundle.x25519_public != identity.x25519_public =>
+        {
+            Some(error_frame(
+                ErrorCode::Forbidden,
+                "Bundle does not belong to the authenticated identity",
+            ))
+        }
+        Frame::PublishPrekeys { bundle } => {
+            bundles.lock().unwrap().publish(bundle);
+            None
+        }
+        Frame::FetchPrekeys { identity } => {
+            let found = bundles.lock().unwrap().fetch(&identity);
+            Some(match found {
+                Some(bundle) => Frame::PrekeyBundle { bundle },
+                None => Frame::PrekeysNotFound { identity },
+            })
+        }
+        Frame::Ping => Some(Frame::Pong),
+        Frame::Pong => None,
+        _ => Some(error_frame(
+            ErrorCode::BadFrame,
+            "Frame is not expected from a client",
+        )),
+    }
+}
+
+// Connections for the recipient named in the transport, or the frame is
+// queued for them if none is registered. Fails with an error code and message.
+fn route(
+    txt: &str,
+    transport: serde_json::Value,
+    sender: &AuthedIdentity,
+    clients: &Clients,
+    offline: &Offline,
+) -> Result<Routed, (ErrorCode, String)> {
+    let routing = serde_json::from_value::<RoutingInfo>(transport)
+        .ok()
+        .filter(|routing| valid_key_id(&routing.envelope.recipient_key_id))
+        .ok_or((
+            ErrorCode::BadFrame,
+            String::from("Transport does not name a recipient"),
+        ))?;
+    if routing.sender_ed25519_pub != sender.ed25519_public {
+        return Err((
+            ErrorCode::SenderMismatch,
+            String::from("Envelope sender is not the authenticated identity"),
+        ));
+    }
+    let RoutingHeader {
+        recipient_key_id: recipient,
+        message_id,
+    } = routing.envelope;
+    // Queued while holding the clients lock, so a registration in progress
+    // either sees this frame in the queue or is already live
+    let clients = clients.lock().unwrap();
+    match clients.get(&recipient) {
+        Some(outbounds) if !outbounds.is_empty() => Ok(Routed::Live(outbounds.clone())),
+        _ if message_id.is_empty() => Err((
+            ErrorCode::UnknownRecipient,
+            format!("No client is registered for recipient {}", recipient),
+        )),
+        _ => {
+            let mut offline = offline.lock().unwrap();
+            offline.push(&recipient, &sender.ed25519_public, &message_id, txt)?;
+            // Asked under the lock, so no other write to the log comes between
+            let synced = offline.disk.synced(offline.log_path(&recipient));
+            Ok(Routed::Queued(message_id, synced))
+        }
+    }
+}
+
+// Tells everyone watching `recipient` whether it is connected
+fn announce_presence(
+    recipient: &str,
+    clients: &Clients,
+    watchers: &Watchers,
+    policy: SlowConsumerPolicy,
+) {
+    let online = clients.lock().unwrap().contains_key(recipient);
+    let presence = Frame::Presence {
+        recipient_key_id: recipient.to_string(),
+        online,
+    };
+    if let Some(watching) = watchers.lock().unwrap().get(recipient) {
+        for watcher in watching {
+            watcher.respond(&presence, policy);
+        }
+    }
+}
+
+/// Sends what was queued for `recipient` in order, then registers the
+/// connection. Frames queued during the flush are sent before going live.
+async fn register(recipient: &str, outbound: &Outbound, clients: &Clients, offline: &Offline) {
+    let mut sent = HashSet::new();
+    loop {
+        let batch: Vec<QueuedFrame> = offline
+            .lock()
+            .unwrap()
+            .pending(recipient)
+            .into_iter()
+            .filter(|queued| !sent.contains(&queued.message_id))
+            .collect();
+        if batch.is_empty() {
+            let mut clients = clients.lock().unwrap();
+            let caught_up = offline
+                .lock()
+                .unwrap()
+                .pending(recipient)
+                .iter()
+                .all(|queued| sent.contains(&queued.message_id));
+            if caught_up {
+                clients
+                    .entry(recipient.to_string())
+                    .or_default()
+                    .push(outbound.clone());
+                return;
+            }
+            continue;
+        }
+        for queued in batch {
+            let message_id = queued.message_id.clone();
+            // Waits on this connection's own queue only
+            if outbound
+                .queue
+                .send(Message::Text(queued.into_delivery()))
+                .await
+                .is_err()
+            {
+                return;
+            }
+            sent.insert(message_id);
+        }
+    }
+}
+
+// Removes a connection from every recipient, or every watch list
+fn unregister(clients: &Clients, conn_id: usize) {
+    let mut clients = clients.lock().unwrap();
+    for outbounds in clients.values_mut() {
+        outbounds.retain(|outbound| outbound.conn_id != conn_id);
+    }
+    clients.retain(|_, outbounds| !outbounds.is_empty());
+}
+
+// Writes queued frames to the socket until the queue closes or the
+// connection is kicked. A kick also interrupts a write that is stuck on a
+// peer that stopped reading. During shutdown it gives up at the deadline.
+// The queue's receiver is dropped on return, which tells the reader the
+// connection is gone, and the frames that were never sent are returned.
+async fn write_frames<S>(
+    mut sink: S,
+    mut queue: mpsc::Receiver<Message>,
+    mut kicked: watch::Receiver<Option<CloseFrame<'static>>>,
+    mut shutdown: watch::Receiver<Option<Instant>>,
+) -> Vec<Message>
+where
+    S: futures::Sink<Message> + Unpin,
+{
+    let pump = async {
+        while let Some(frame) = queue.recv().await {
+            if sink.send(frame).await.is_err() {
+                break;
+            }
+        }
+    };
+    // The queue closes once the connection's handler is done with it
+    let deadline = async {
+        let deadline = shutdown_started(&mut shutdown).await;
+        tokio::time::sleep_until(deadline).await;
+    };
+    let close = tokio::select! {
+        _ = pump => None,
+        Ok(()) = kicked.changed() => kicked.borrow().clone(),
+        _ = deadline => Some(going_away()),
+    };
+    let _ = tokio::time::timeout(Duration::from_secs(1), sink.send(Message::Close(close))).await;
+    queue.close();
+    let mut left = Vec::new();
+    while let Ok(msg) = queue.try_recv() {
+        left.push(msg);
+    }
+    left
+}
+
+/// Refills `rate` tokens per second, up to `burst`. Each frame takes one.
+struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: u32, burst: u32) -> Self {
+        TokenBucket {
+            rate: rate.into(),
+            burst: burst.into(),
+            tokens: burst.into(),
+            updated: Instant::now(),
+        }
+    }
+
+    fn take(&mut self) -> bool {
+        let now = Instant::now();
+        let refill = now.duration_since(self.updated).as_secs_f64() * self.rate;
+        self.tokens = (self.tokens + refill).min(self.burst);
+        self.updated = now;
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+
+    // Whether the bucket has refilled since it was last used, and so is no
+    // different from a new one
+    fn is_full(&self, now: Instant) -> bool {
+        self.tokens + now.duration_since(self.updated).as_secs_f64() * self.rate >= self.burst
+    }
+}
+
+// Rate limits shared by all connections of an identity, by its key id
+type IdentityBuckets = Arc<Mutex<HashMap<String, TokenBucket>>>;
+
+/// Why a new connection was turned away.
+enum Refusal {
+    Banned,
+    Full,
+    TooManyFromAddress,
+}
+
+/// Open connections, recent violations and bans, per IP address.
+#[derive(Default)]
+struct Gate {
+    total: usize,
+    open: HashMap<IpAddr, usize>,
+    violations: HashMap<IpAddr, (u32, Instant)>,
+    banned: HashMap<IpAddr, Instant>,
+}
+
+type Gates = Arc<Mutex<Gate>>;
+
+/// A connection's place in the gate, given back when dropped.
+struct Slot {
+    gate: Gates,
+    ip: IpAddr,
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        let mut gate = self.gate.lock().unwrap();
+        gate.total -= 1;
+        if let Some(open) = gate.open.get_mut(&self.ip) {
+            *open -= 1;
+            if *open == 0 {
+                gate.open.remove(&self.ip);
+            }
+        }
+    }
+}
+
+fn admit(gates: &Gates, ip: IpAddr, limits: &Limits) -> Result<Slot, Refusal> {
+    let mut gate = gates.lock().unwrap();
+    let now = Instant::now();
+    gate.banned.retain(|_, until| *until > now);
+    if gate.banned.contains_key(&ip) {
+        return Err(Refusal::Banned);
+    }
+    if gate.total >= limits.max_connections {
+        return Err(Refusal::Full);
+    }
+    let open = gate.open.entry(ip).or_default();
+    if *open >= limits.max_connections_per_ip {
+        return Err(Refusal::TooManyFromAddress);
+    }
+    *open += 1;
+    gate.total += 1;
+    Ok(Slot {
+        gate: gates.clone(),
+        ip,
+    })
+}
+
+// Counts a violation against an address. Returns true once it has too many
+// and is banned.
+fn violation(gates: &Gates, ip: IpAddr, limits: &Limits) -> bool {
+    let mut gate = gates.lock().unwrap();
+    let now = Instant::now();
+    gate.violations
+        .retain(|_, (_, since)| now.duration_since(*since) < VIOLATION_WINDOW);
+    let (count, _) = gate.violations.entry(ip).or_insert((0, now));
+    *count += 1;
+    if *count < limits.ban_after {
+        return false;
+    }
+    gate.violations.remove(&ip);
+    gate.banned.insert(ip, now + limits.ban_duration);
+    warn!(%ip, secs = limits.ban_duration.as_secs(), "banned after repeated violations");
+    true
+}
+
+// Answers a connection over its address's limit with an error frame, then
+// closes it. Bounded by the handshake timeout, like any connection.
+async fn refuse(stream: TcpStream, tls: Option<TlsAcceptor>, max_frame_size: usize) {
+    let Ok(ws_stream) = accept_connection(stream, tls.as_ref(), max_frame_size).await else {
+        return;
+    };
+    let (mut sink, _) = ws_stream.split();
+    let error = error_frame(
+        ErrorCode::TooManyConnections,
+        "Too many connections from your address",
+    );
+    let close = CloseFrame {
+        code: CloseCode::Policy,
+        reason: "too many connections".into(),
+    };
+    let _ = tokio::time::timeout(Duration::from_secs(1), async {
+        sink.send(Message::Text(serde_json::to_string(&error).unwrap()))
+            .await?;
+        sink.send(Message::Close(Some(close))).await
+    })
+    .await;
+}
+
+/// State shared by every connection.
+#[derive(Clone)]
+struct Relay {
+    clients: Clients,
+    watchers: Watchers,
+    bundles: Bundles,
+    offline: Offline,
+    tls: Option<TlsAcceptor>,
+    queue_capacity: usize,
+    policy: SlowConsumerPolicy,
+    heartbeat_interval: Duration,
+    heartbeat_timeout: Duration,
+    limits: Limits,
+    gates: Gates,
+    identity_buckets: IdentityBuckets,
+    /// Set to the shutdown deadline once the relay is stopping.
+    shutdown: watch::Receiver<Option<Instant>>,
+    // Held by every connection task, so the relay can wait for them all
+    _done: mpsc::Sender<()>,
+}
+
+// Resolves on SIGINT, or SIGTERM on Unix, with the signal's name.
+async fn shutdown_signal() -> &'static str {
+    let interrupt = async {
+        if tokio::signal::ctrl_c().await.is_err() {
+            std::future::pending::<()>().await;
+        }
+    };
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let terminate = async {
+            match signal(SignalKind::terminate()) {
+                Ok(mut terminate) => {
+                    terminate.recv().await;
+                }
+                Err(_) => std::future::pending::<()>().await,
+            }
+        };
+        tokio::select! {
+            _ = interrupt => "SIGINT",
+            _ = terminate => "SIGTERM",
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        interrupt.await;
+        "SIGINT"
+    }
+}
+
+// Resolves once the relay starts shutting down, with the deadline.
+async fn shutdown_started(shutdown: &mut watch::Receiver<Option<Instant>>) -> Instant {
+    loop {
+        if let Some(deadline) = *shutdown.borrow_and_update() {
+            return deadline;
+        }
+        if shutdown.changed().await.is_err() {
+            std::future::pending::<()>().await;
+        }
+    }
+}
+
+fn going_away() -> CloseFrame<'static> {
+    CloseFrame {
+        code: CloseCode::Away,
+        reason: "server going away".into(),
+    }
+}
+
+// Hands an envelope to each of the recipient's connections, dropping those
+// that are gone or too slow. Returns whether any connection took it.
+fn deliver_live(recipients: &[Outbound], txt: &str, relay: &Relay) -> bool {
+    let mut delivered = 0;
+    for recipient in recipients {
+        if recipient.deliver(Message::Text(txt.to_string()), relay.policy) {
+            delivered += 1;
+        } else {
+            unregister(&relay.clients, recipient.conn_id);
+        }
+    }
+    debug!(connections = delivered, "delivered");
+    delivered > 0
+}
+
+// Puts envelopes the writer could not send back in the recipient's offline
+// queue, so they are delivered on its next connection. Envelopes that came
+// from the offline queue are still there, and are skipped as duplicates.
+fn requeue(recipient: &str, left: Vec<Message>, offline: &Offline) {
+    let mut offline = offline.lock().unwrap();
+    let mut requeued = 0;
+    for msg in left {
+        let Message::Text(txt) = msg else {
+            continue;
+        };
+        let Ok(Frame::Deliver { transport, .. }) = serde_json::from_str::<Frame>(&txt) else {
+            continue;
+        };
+        let Ok(routing) = serde_json::from_value::<RoutingInfo>(transport) else {
+            continue;
+        };
+        let RoutingHeader {
+            recipient_key_id,
+            message_id,
+        } = routing.envelope;
+        let sender = routing.sender_ed25519_pub;
+        if recipient_key_id != recipient || message_id.is_empty() {
+            continue;
+        }
+        match offline.push(recipient, &sender, &message_id, &txt) {
+            Ok(()) => requeued += 1,
+            Err((_, e)) => warn!(error = %e, "could not requeue an undelivered envelope"),
+        }
+    }
+    if requeued > 0 {
+        info!(requeued, "moved undelivered envelopes to the offline queue");
+    }
+}
+
+async fn serve_connection(stream: TcpStream, slot: Slot, conn_id: usize, mut relay: Relay) {
+    info!("connection opened");
+    let handshake = tokio::select! {
+        handshake = accept_connection(stream, relay.tls.as_ref(), relay.limits.max_frame_size) => handshake,
+        _ = shutdown_started(&mut relay.shutdown) => return,
+    };
+    let ws_stream = match handshake {
+        Ok(ws_stream) => ws_stream,
+        Err(e) => {
+            info!(error = %e, "handshake failed");
+            return;
+        }
+    };
+    let (sink, mut rx) = ws_stream.split();
+    let (queue, queued) = mpsc::channel(relay.queue_capacity);
+    let (kick, kicked) = watch::channel(None);
+    let outbound = Outbound {
+        conn_id,
+        queue,
+        kick: Arc::new(kick),
+    };
+    let writer = tokio::spawn(write_frames(
+        sink,
+        queued,
+        kicked.clone(),
+        relay.shutdown.clone(),
+    ));
+
+    let identity = handle_connection(&mut rx, &outbound, slot.ip, kicked, &mut relay).await;
+    // The writer sends what is still queued, then stops
+    drop(outbound);
+    let left = writer.await.unwrap_or_default();
+    if let Some(identity) = identity {
+        requeue(&identity.recipient_key_id, left, &relay.offline);
+        // Buckets outlive connections, so reconnecting does not refill one.
+        // Only those idle long enough to be full again are forgotten
+        let now = Instant::now();
+        relay
+            .identity_buckets
+            .lock()
+            .unwrap()
+            .retain(|_, bucket| !bucket.is_full(now));
+    }
+    info!("disconnected");
+}
+
+// Authenticates the connection and handles its frames until it closes, is
+// kicked or the relay shuts down. Returns the identity it authenticated as.
+async fn handle_connection<S>(
+    rx: &mut S,
+    outbound: &Outbound,
+    ip: IpAddr,
+    mut kicked: watch::Receiver<Option<CloseFrame<'static>>>,
+    relay: &mut Relay,
+) -> Option<AuthedIdentity>
+where
+    S: Stream<Item = Result<Message, WsError>> + Unpin,
+{
+    let identity = tokio::select! {
+        identity = authenticate(rx, outbound, relay.policy) => identity?,
+        Ok(()) = kicked.changed() => return None,
+        _ = shutdown_started(&mut relay.shutdown) => {
+            let _ = outbound.queue.try_send(Message::Close(Some(going_away())));
+            return None;
+        }
+    };
+    info!(
+        identity = %identity.key_id,
+        recipient = %identity.recipient_key_id,
+        "authenticated"
+    );
+    register(
+        &identity.recipient_key_id,
+        outbound,
+        &relay.clients,
+        &relay.offline,
+    )
+    .await;
+    announce_presence(
+        &identity.recipient_key_id,
+        &relay.clients,
+        &relay.watchers,
+        relay.policy,
+    );
+    // Any frame counts as a sign of life, not just pongs
+    let mut last_seen = Instant::now();
+    let mut heartbeat = tokio::time::interval_at(
+        Instant::now() + relay.heartbeat_interval,
+        relay.heartbeat_interval,
+    );
+    heartbeat.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    let identity_id = identity.key_id.clone();
+    let mut bucket = TokenBucket::new(relay.limits.rate, relay.limits.burst);
+    let mut shutting_down = false;
+    let mut watching = HashSet::new();
+    loop {
+        let msg = tokio::select! {
+            msg = rx.next() => match msg {
+                Some(Ok(msg)) => msg,
+                Some(Err(WsError::Capacity(e))) => {
+                    info!(error = %e, "frame too large");
+                    outbound.respond(
+                        &error_frame(
+                            ErrorCode::FrameTooLarge,
+                            format!("Frames are limited to {} bytes", relay.limits.max_frame_size),
+                        ),
+                        relay.policy,
+                    );
+                    violation(&relay.gates, ip, &relay.limits);
+                    outbound.finish(CloseCode::Size, "frame too large");
+                    // The rest of the frame is never read, and closing a
+                    // socket with unread input resets it, which can destroy
+                    // the error before the client reads it
+                    tokio::time::sleep(Duration::from_millis(500)).await;
+                    break;
+                }
+                Some(Err(e)) => {
+                    info!(error = %e, "read failed");
+                    break;
+                }
+                None => break,
+            },
+            _ = heartbeat.tick() => {
+                if last_seen.elapsed() >= relay.heartbeat_timeout {
+                    info!("missed heartbeats, evicting");
+                    outbound.close(CloseCode::Policy, "missed heartbeats");
+                    break;
+                }
+                outbound.deliver(Message::Ping(Vec::new()), relay.policy);
+                continue;
+            }
+            Ok(()) = kicked.changed() => break,
+            // The writer stopped, so the socket is unusable
+            _ = outbound.queue.closed() => break,
+            _ = shutdown_started(&mut relay.shutdown) => {
+                shutting_down = true;
+                break;
+            }
+        };
+        last_seen = Instant::now();
+        // Every frame counts, pings and pongs included
+        let allowed = bucket.take()
+            && relay
+                .identity_buckets
+                .lock()
+                .unwrap()
+                .entry(identity_id.clone())
+                .or_insert_with(|| {
+                    TokenBucket::new(relay.limits.identity_rate, relay.limits.identity_burst)
+                })
+                .take();
+        if !allowed {
+            debug!("rate limited");
+            outbound.respond(
+                &error_frame(ErrorCode::RateLimited, "Too many frames, slow down"),
+                relay.policy,
+            );
+            if violation(&relay.gates, ip, &relay.limits) {
+                outbound.finish(CloseCode::Policy, "temporarily banned");
+                break;
+            }
+            continue;
+        }
+        let Message::Text(txt) = msg else {
+            continue;
+        };
+        let frame = match serde_json::from_str::<Frame>(&txt) {
+            Ok(frame) => frame,
+            Err(e) => {
+                debug!(error = %e, "unparseable frame");
+                outbound.respond(
+                    &error_frame(ErrorCode::BadFrame, "Unknown frame"),
+                    relay.policy,
+                );
+                continue;
+            }
+        };
+        match frame {
+            // Envelopes go only to the recipient named in their
+            // header. Frames are queued, so a slow recipient never
+            // holds up the sender or anyone else.
+            Frame::Deliver { transport, .. } => {
+                let routed = match route(
+                    &txt,
+                    transport.clone(),
+                    &identity,
+                    &relay.clients,
+                    &relay.offline,
+                ) {
+                    Ok(Routed::Live(recipients)) if deliver_live(&recipients, &txt, relay) => {
+                        continue;
+                    }
+                    // Every connection was cut off for being too slow, or
+                    // is gone, so routing again queues the envelope offline
+                    Ok(Routed::Live(_)) => {
+                        route(&txt, transport, &identity, &relay.clients, &relay.offline)
+                    }
+                    routed => routed,
+                };
+                match routed {
+                    Ok(Routed::Live(recipients)) => {
+                        if !deliver_live(&recipients, &txt, relay) {
+                            outbound.respond(
+                                &error_frame(
+                                    ErrorCode::UnknownRecipient,
+                                    "The recipient's connections could not take the envelope",
+                                ),
+                                relay.policy,
+                            );
+                        }
+                    }
+                    // Confirmed only once the envelope is on disk
+                    Ok(Routed::Queued(message_id, synced)) => {
+                        if synced.await.unwrap_or(false) {
+                            debug!(%message_id, "queued for offline recipient");
+                            outbound.respond(&Frame::Queued { message_id }, relay.policy);
+                        } else {
+                            outbound.respond(
+                                &error_frame(
+                                    ErrorCode::StoreFailed,
+                                    String::from("The relay could not store the envelope"),
+                                ),
+                                relay.policy,
+                            );
+                        }
+                    }
+                    Err((code, message)) => {
+                        debug!(?code, reason = %message, "envelope refused");
+                        outbound.respond(&error_frame(code, message), relay.policy);
+                    }
+                }
+            }
+            Frame::Ack { message_id } => {
+                debug!(%message_id, "acked");
+                relay
+                    .offline
+                    .lock()
+                    .unwrap()
+                    .ack(&identity.recipient_key_id, &message_id);
+            }
+            Frame::Watch { recipient_key_id } => {
+                if !is_key_id(&recipient_key_id) {
+                    outbound.respond(
+                        &error_frame(ErrorCode::BadFrame, "Invalid recipient key id"),
+                        relay.policy,
+                    );
+                    continue;
+                }
+                let repeat = watching.contains(&recipient_key_id);
+                if !repeat && watching.len() >= MAX_WATCHES {
+                    outbound.respond(
+                        &error_frame(
+                            ErrorCode::Forbidden,
+                            format!("At most {} recipients can be watched", MAX_WATCHES),
+                        ),
+                        relay.policy,
+                    );
+                    continue;
+                }
+                let online = relay
+                    .clients
+                    .lock()
+                    .unwrap()
+                    .contains_key(&recipient_key_id);
+                // A repeated watch only asks for the current presence again
+                if !repeat {
+                    debug!(recipient = %recipient_key_id, "watching");
+                    watching.insert(recipient_key_id.clone());
+                    relay
+                        .watchers
+                        .lock()
+                        .unwrap()
+                        .entry(recipient_key_id.clone())
+                        .or_default()
+                        .push(outbound.clone());
+                }
+                outbound.respond(
+                    &Frame::Presence {
+                        recipient_key_id,
+                        online,
+                    },
+                    relay.policy,
+                );
+            }
+            // Everything else is answered to this connection only
+            frame => {
+                if let Some(response) = handle_frame(frame, &identity, &relay.bundles) {
+                    outbound.respond(&response, relay.policy);
+                }
+            }
+        }
+    }
+    unregister(&relay.clients, outbound.conn_id);
+    unregister(&relay.watchers, outbound.conn_id);
+    announce_presence(
+        &identity.recipient_key_id,
+        &relay.clients,
+        &relay.watchers,
+        relay.policy,
+    );
+    if shutting_down {
+        // Queued behind everything else, so those frames still go out first.
+        // If the queue is full, the writer closes at the deadline instead.
+        outbound.finish(CloseCode::Away, "server going away");
+    }
+    Some(identity)
+}
+
+// Prints why the relay cannot start and exits. Used before and right after
+// logging is set up, when there is nothing to contain the error in.
+fn fail(message: String) -> ! {
+    eprintln!("{}", message);
+    std::process::exit(1);
+}
 
 #[tokio::main]
 async fn main() {
-    let listener = TcpListener::bind("127.0.0.1:9001").await.unwrap();
-    let clients: Clients = Arc::new(Mutex::new(Vec::new()));
-    println!("🧪 Vaultsyn WebSocket Server running at ws://localhost:9001");
-
-    while let Ok((stream, _)) = listener.accept().await {
-        let peer = stream.peer_addr().unwrap();
-        let ws_stream = accept_async(stream).await.unwrap();
-        let (tx, mut rx) = ws_stream.split();
-
-        let client_list = clients.clone();
-        client_list.lock().unwrap().push(tx);
-
-        let broadcast_clients = clients.clone();
-        tokio::spawn(async move {
-            while let Some(Ok(msg)) = rx.next().await {
-                if let Message::Text(txt) = msg {
-                    // Remove all sinks from the clients list, send, then put them back
-                    let mut sinks = {
-                        let mut clients = broadcast_clients.lock().unwrap();
-                        std::mem::take(&mut *clients)
-                    };
-                    for client in sinks.iter_mut() {
-                        let _ = client.send(Message::Text(txt.clone())).await;
-                    }
-                    // Put the sinks back into the clients list
-                    let mut clients = broadcast_clients.lock().unwrap();
-                    clients.extend(sinks);
+    let config = Config::load(Args::parse()).unwrap_or_else(|e| fail(e));
+    let filter = EnvFilter::try_new(&config.log)
+        .unwrap_or_else(|e| fail(format!("❌ Invalid log filter {:?}: {}", config.log, e)));
+    tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_ansi(std::io::stdout().is_terminal())
+        .init();
+
+    let tls = config
+        .tls
+        .as_ref()
+        .map(|(cert, key)| load_tls(cert, key).unwrap_or_else(|e| fail(e)));
+    let disk = DiskWriter::spawn();
+    let offline = OfflineQueue::open(
+        config.store_dir.clone(),
+        config.offline_ttl,
+        config.offline_quota,
+        config.offline_sender_quota,
+        disk.clone(),
+    )
+    .unwrap_or_else(|e| fail(e));
+    let offline: Offline = Arc::new(Mutex::new(offline));
+    let listener = TcpListener::bind(&config.bind)
+        .await
+        .unwrap_or_else(|e| fail(format!("❌ Cannot listen on {}: {}", config.bind, e)));
+    let local = listener
+        .local_addr()
+        .unwrap_or_else(|e| fail(format!("❌ Cannot listen on {}: {}", config.bind, e)));
+    let clients: Clients = Arc::new(Mutex::new(HashMap::new()));
+    let watchers: Watchers = Arc::new(Mutex::new(HashMap::new()));
+    let bundles = PrekeyDirectory::open(config.store_dir.join("prekeys"), disk.clone())
+        .unwrap_or_else(|e| fail(e));
+    let bundles: Bundles = Arc::new(Mutex::new(bundles));
+    match &tls {
+        Some((_, fingerprint)) => {
+            info!("Vaultsyn relay listening on wss://{}", local);
+            info!(sha256 = %fingerprint, "TLS certificate");
+        }
+        None => {
+            info!("Vaultsyn relay listening on ws://{}", local);
+            if !local.ip().is_loopback() {
+                warn!("serving plain ws:// beyond localhost, so who connects and when is visible on the network; configure a TLS certificate and key");
+            }
+        }
+    }
+    let tls = tls.map(|(acceptor, _)| acceptor);
+    info!(
+        capacity = config.queue_capacity,
+        slow_consumers = ?config.slow_consumer,
+        "outbound queues"
+    );
+    info!(
+        store = %config.store_dir.display(),
+        quota = config.offline_quota,
+        sender_quota = config.offline_sender_quota,
+        ttl_secs = config.offline_ttl,
+        "offline queue"
+    );
+
+    let (shutdown_tx, shutdown) = watch::channel(None);
+    let (done, mut all_done) = mpsc::channel::<()>(1);
+    let relay = Relay {
+        clients,
+        watchers,
+        bundles,
+        offline,
+        tls,
+        queue_capacity: config.queue_capacity,
+        policy: config.slow_consumer,
+        heartbeat_interval: config.heartbeat_interval,
+        heartbeat_timeout: config.heartbeat_timeout,
+        limits: config.limits,
+        gates: Gates::default(),
+        identity_buckets: IdentityBuckets::default(),
+        shutdown,
+        _done: done,
+    };
+
+    let signal = shutdown_signal();
+    tokio::pin!(signal);
+    let mut next_conn_id: usize = 0;
+    loop {
+        let (stream, peer) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    // Usually out of file descriptors; back off rather than spin
+                    warn!(error = %e, "accept failed");
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    continue;
                 }
+            },
+            signal = &mut signal => {
+                info!(signal, "shutting down");
+                break;
             }
-        });
+        };
+        let slot = match admit(&relay.gates, peer.ip(), &relay.limits) {
+            Ok(slot) => slot,
+            // Dropped without a word, so refusing costs as little as possible
+            Err(Refusal::Banned) => {
+                debug!(%peer, "refused banned address");
+                continue;
+            }
+            Err(Refusal::Full) => {
+                warn!(%peer, "connection limit reached, refused");
+                continue;
+            }
+            Err(Refusal::TooManyFromAddress) => {
+                info!(%peer, "too many connections from address, refused");
+                violation(&relay.gates, peer.ip(), &relay.limits);
+                tokio::spawn(refuse(
+                    stream,
+                    relay.tls.clone(),
+                    relay.limits.max_frame_size,
+                ));
+                continue;
+            }
+        };
+        let conn_id = next_conn_id;
+        next_conn_id += 1;
+        // Handshakes run in the connection's task, so a slow one never
+        // holds up the accept loop.
+        tokio::spawn(
+            serve_connection(stream, slot, conn_id, relay.clone())
+                .instrument(tracing::info_span!("conn", id = conn_id, %peer)),
+        );
+    }
 
-        println!("🔗 New connection from {}", peer);
+    drop(listener);
+    let deadline = Instant::now() + config.shutdown_timeout;
+    let _ = shutdown_tx.send(Some(deadline));
+    drop(relay);
+    // Writers give up at the deadline; the grace period covers their close
+    // frames and moving what they could not send to the offline queue
+    tokio::select! {
+        finished = tokio::time::timeout_at(deadline + SHUTDOWN_GRACE, all_done.recv()) => {
+            match finished {
+                Ok(_) => info!("all connections closed"),
+                Err(_) => warn!("connections still open after the shutdown deadline"),
+            }
+        }
+        signal = shutdown_signal() => warn!(signal, "stopping without waiting for connections"),
     }
+    let _ = tokio::task::spawn_blocking(move || disk.flush()).await;
 }
@@ -0,0 +1,437 @@
+//! Client side of the relay connection: TLS, authentication, and frames in
+//! both directions. Rendering messages is left to the caller.
+
+use crate::crypto::b64::decode_key;
+use crate::crypto::keygen::Identity;
+use crate::crypto::x3dh::PrekeyStore;
+use crate::io::storage::KeyStore;
+use crate::message::VaultsynTransport;
+use crate::protocol::{
+    auth_message, negotiate_version, x25519_proof, ErrorCode, Frame, PrekeyBundle,
+    PROTOCOL_VERSIONS,
+};
+use crate::{Error, Result};
+
+use base64::{prelude::BASE64_STANDARD as standard, Engine};
+use ed25519_dalek::Signer;
+use futures::{SinkExt, StreamExt};
+use sha2::{Digest, Sha256};
+use tokio::task;
+use tokio_tungstenite::{client_async, connect_async, tungstenite::Message, MaybeTlsStream};
+use url::Url;
+use x25519_dalek::PublicKey as X25519Public;
+
+use std::collections::VecDeque;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+type WsStream = tokio_tungstenite::WebSocketStream<MaybeTlsStream<tokio::net::TcpStream>>;
+type WsWrite = futures::stream::SplitSink<WsStream, Message>;
+type WsRead = futures::stream::SplitStream<WsStream>;
+
+/// How often the client pings the relay.
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(15);
+/// Silence after which the relay is presumed dead. The relay answers every
+/// ping, so this only runs out if it or the network is gone.
+pub const HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(45);
+/// How long the relay gets to answer during the handshake and bundle fetches.
+const REPLY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How a wss:// relay's certificate is checked.
+#[derive(Clone, Default)]
+pub struct TlsTrust {
+    /// PEM certificate of a CA to trust besides the system roots, such as a
+    /// self-signed relay certificate.
+    pub ca_cert: Option<PathBuf>,
+    /// SHA-256 fingerprint of the relay's certificate. When set, only that
+    /// certificate is accepted, whoever signed it.
+    pub pinned_sha256: Option<String>,
+}
+
+impl TlsTrust {
+    /// True if any of the options is set, which only matters for wss://.
+    pub fn is_configured(&self) -> bool {
+        self.ca_cert.is_some() || self.pinned_sha256.is_some()
+    }
+}
+
+// Fingerprints are compared as bare uppercase hex, so both the colon
+// separated form openssl prints and plain hex are accepted.
+fn normalize_fingerprint(fingerprint: &str) -> Result<String> {
+    let hex: String = fingerprint
+        .chars()
+        .filter(|c| *c != ':' && !c.is_whitespace())
+        .collect::<String>()
+        .to_uppercase();
+    if hex.len() != 64 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(Error::encoding(
+            "Certificate pin must be a SHA-256 fingerprint (64 hex digits)",
+        ));
+    }
+    Ok(hex)
+}
+
+async fn connect(url: Url, tls: &TlsTrust) -> Result<WsStream> {
+    if url.scheme() != "wss" {
+        let (ws_stream, _) = connect_async(url)
+            .await
+            .map_err(|e| Error::relay(format!("Failed to connect: {}", e)))?;
+        return Ok(ws_stream);
+    }
+
+    let mut builder = native_tls::TlsConnector::builder();
+    if let Some(path) = &tls.ca_cert {
+        let pem = std::fs::read(path).map_err(|e| {
+            std::io::Error::new(
+                e.kind(),
+                format!("Cannot read CA certificate {}: {}", path.display(), e),
+            )
+        })?;
+        let cert = native_tls::Certificate::from_pem(&pem)
+            .map_err(|e| Error::encoding(format!("Invalid CA certificate: {}", e)))?;
+        builder.add_root_certificate(cert);
+    }
+    let pin = tls
+        .pinned_sha256
+        .as_deref()
+        .map(normalize_fingerprint)
+        .transpose()?;
+    if pin.is_some() {
+        // The pin replaces the chain and hostname checks. It is compared
+        // right after the handshake, before anything is sent.
+        builder
+            .danger_accept_invalid_certs(true)
+            .danger_accept_invalid_hostnames(true);
+    }
+    let connector = builder
+        .build()
+        .map_err(|e| Error::relay(format!("Cannot set up TLS: {}", e)))?;
+
+    let host = url
+        .host_str()
+        .ok_or_else(|| Error::encoding("Relay URL has no host"))?;
+    let port = url.port_or_known_default().unwrap_or(443);
+    let tcp = tokio::net::TcpStream::connect((host, port))
+        .await
+        .map_err(|e| Error::relay(format!("Failed to connect: {}", e)))?;
+    let stream = tokio_native_tls::TlsConnector::from(connector)
+        .connect(host, tcp)
+        .await
+        .map_err(|e| Error::relay(format!("TLS handshake with the relay failed: {}", e)))?;
+
+    if let Some(pin) = pin {
+        let der = stream
+            .get_ref()
+            .peer_certificate()
+            .ok()
+            .flatten()
+            .and_then(|cert| cert.to_der().ok())
+            .ok_or_else(|| Error::auth("Relay presented no certificate"))?;
+        let actual: String = Sha256::digest(der)
+            .iter()
+            .map(|byte| format!("{:02X}", byte))
+            .collect::<Vec<_>>()
+            .join(":");
+        if actual.replace(':', "") != pin {
+            return Err(Error::auth(format!(
+                "Relay certificate does not match the pinned fingerprint (it has {})",
+                actual
+            )));
+        }
+    }
+
+    let (ws_stream, _) = client_async(url.as_str(), MaybeTlsStream::NativeTls(stream))
+        .await
+        .map_err(|e| Error::relay(format!("WebSocket handshake failed: {}", e)))?;
+    Ok(ws_stream)
+}
+
+/// Loads the identity's prekeys, creating them for identities made before
+/// prekeys existed, and tops up the one-time prekeys.
+pub fn prepare_prekeys(store: &dyn KeyStore, identity: &Identity) -> Result<PrekeyStore> {
+    let mut prekeys = match store.load_prekeys(identity)? {
+        Some(prekeys) => prekeys,
+        None => PrekeyStore::generate(identity)?,
+    };
+    prekeys.replenish();
+    store.save_prekeys(identity, &prekeys)?;
+    Ok(prekeys)
+}
+
+fn to_message(frame: &Frame) -> Message {
+    Message::Text(serde_json::to_string(frame).expect("frames serialize"))
+}
+
+// Signs the challenge with our Ed25519 key, and proves we hold our X25519
+// key through a secret shared with the relay's one-off key.
+fn answer_challenge(
+    identity: &Identity,
+    version: u32,
+    challenge: &str,
+    relay_x25519: &str,
+) -> Result<Frame> {
+    let ed25519_public = identity.ed25519_public.to_base64();
+    let x25519_public = identity.x25519_public.to_base64();
+    let signature = identity.signing_key().sign(&auth_message(
+        version,
+        challenge,
+        relay_x25519,
+        &ed25519_public,
+        &x25519_public,
+    ));
+    let shared = identity
+        .x25519_secret()
+        .diffie_hellman(&X25519Public::from(decode_key(relay_x25519)?));
+    Ok(Frame::Auth {
+        version,
+        ed25519_public,
+        x25519_public,
+        signature: standard.encode(signature.to_bytes()),
+        x25519_proof: x25519_proof(shared.as_bytes(), challenge),
+    })
+}
+
+/// An authenticated connection to a relay. Use it whole for request and
+/// response exchanges, or `split` it to read and write from separate tasks.
+pub struct RelayClient {
+    sink: RelaySink,
+    stream: RelayStream,
+}
+
+/// Sending half of a relay connection. Clones share the connection.
+#[derive(Clone)]
+pub struct RelaySink {
+    write: Arc<tokio::sync::Mutex<WsWrite>>,
+}
+
+/// Receiving half of a relay connection.
+pub struct RelayStream {
+    read: WsRead,
+    /// Frames that arrived while waiting for a specific reply.
+    backlog: VecDeque<String>,
+}
+
+impl RelayClient {
+    /// Connects to the relay at `url`, agrees on a protocol version and
+    /// answers its auth challenge as `identity`. From then on the relay
+    /// routes envelopes for this identity to us.
+    pub async fn connect(url: &str, identity: &Identity, tls: &TlsTrust) -> Result<Self> {
+        let url =
+            Url::parse(url).map_err(|e| Error::encoding(format!("Invalid relay URL: {}", e)))?;
+        let (write, read) = connect(url, tls).await?.split();
+        let mut client = RelayClient {
+            sink: RelaySink {
+                write: Arc::new(tokio::sync::Mutex::new(write)),
+            },
+            stream: RelayStream {
+                read,
+                backlog: VecDeque::new(),
+            },
+        };
+        client.authenticate(identity).await?;
+        Ok(client)
+    }
+
+    async fn authenticate(&mut self, identity: &Identity) -> Result<()> {
+        let (versions, challenge, relay_x25519) = match self.reply().await? {
+            Frame::Hello {
+                versions,
+                challenge,
+                relay_x25519,
+            } => (versions, challenge, relay_x25519),
+            Frame::Error { message, .. } => {
+                return Err(Error::relay(format!(
+                    "The relay refused the connection: {}",
+                    message
+                )));
+            }
+            _ => return Err(Error::relay("The relay did not greet with a hello")),
+        };
+        let version = negotiate_version(&PROTOCOL_VERSIONS, &versions).ok_or_else(|| {
+            Error::relay(format!(
+                "Incompatible relay: it speaks protocol versions {:?}, this client speaks {:?}",
+                versions, PROTOCOL_VERSIONS
+            ))
+        })?;
+        let auth = answer_challenge(identity, version, &challenge, &relay_x25519)?;
+        self.sink.send(&auth).await?;
+        match self.reply().await? {
+            Frame::Authenticated { .. } => Ok(()),
+            Frame::Error {
+                code: ErrorCode::UnsupportedVersion,
+                message,
+            } => Err(Error::relay(format!("Incompatible relay: {}", message))),
+            Frame::Error { message, .. } => Err(Error::auth(format!(
+                "The relay refused authentication: {}",
+                message
+            ))),
+            _ => Err(Error::relay("Unexpected response to auth")),
+        }
+    }
+
+    // The next frame during the handshake, which must come promptly.
+    async fn reply(&mut self) -> Result<Frame> {
+        let text = self.stream.recv(REPLY_TIMEOUT).await?;
+        serde_json::from_str(&text).map_err(|_| {
+            Error::relay("Unrecognised frame from the relay, it may run an incompatible version")
+        })
+    }
+
+    pub fn sink(&self) -> &RelaySink {
+        &self.sink
+    }
+
+    /// Asks the relay for a peer's prekey bundle. `None` if the peer has not
+    /// published one or the relay does not answer in time. Unrelated frames
+    /// that arrive in the meantime are kept for the next read.
+    pub async fn fetch_prekeys(&mut self, peer_x25519: &str) -> Result<Option<PrekeyBundle>> {
+        let request = Frame::FetchPrekeys {
+            identity: peer_x25519.to_string(),
+        };
+        self.sink.send(&request).await?;
+
+        let mut unrelated = Vec::new();
+        let wait = async {
+            loop {
+                let text = self.stream.recv(REPLY_TIMEOUT).await?;
+                match serde_json::from_str::<Frame>(&text) {
+                    Ok(Frame::PrekeyBundle { bundle }) if bundle.x25519_public == peer_x25519 => {
+                        return Ok(Some(bundle));
+                    }
+                    Ok(Frame::PrekeysNotFound { identity }) if identity == peer_x25519 => {
+                        return Ok(None);
+                    }
+                    Ok(
+                        Frame::PrekeyBundle { .. } | Frame::PrekeysNotFound { .. } | Frame::Pong,
+                    ) => {}
+                    _ => unrelated.push(text),
+                }
+            }
+        };
+        let result = tokio::time::timeout(REPLY_TIMEOUT, wait)
+            .await
+            .unwrap_or(Ok(None));
+        self.stream.backlog.extend(unrelated);
+        result
+    }
+
+    pub fn split(self) -> (RelaySink, RelayStream) {
+        (self.sink, self.stream)
+    }
+}
+
+impl RelaySink {
+    pub async fn send(&self, frame: &Frame) -> Result<()> {
+        self.send_message(to_message(frame)).await
+    }
+
+    /// Sends text as is. The relay answers anything that is not a frame
+    /// with a `bad_frame` error, so this is for debugging.
+    pub async fn send_text(&self, text: String) -> Result<()> {
+        self.send_message(Message::Text(text)).await
+    }
+
+    async fn send_message(&self, message: Message) -> Result<()> {
+        self.write
+            .lock()
+            .await
+            .send(message)
+            .await
+            .map_err(|e| Error::relay(format!("Failed to send: {}", e)))
+    }
+
+    pub async fn deliver(&self, transport: &VaultsynTransport) -> Result<()> {
+        self.send(&Frame::Deliver {
+            transport: serde_json::to_value(transport)?,
+            queued_at: None,
+        })
+        .await
+    }
+
+    /// Tells the relay a delivered envelope was handled, so it drops its copy.
+    pub async fn ack(&self, message_id: &str) -> Result<()> {
+        self.send(&Frame::Ack {
+            message_id: message_id.to_string(),
+        })
+        .await
+    }
+
+    /// Replaces the relay's copy of our prekey bundle.
+    pub async fn publish_prekeys(&self, bundle: PrekeyBundle) -> Result<()> {
+        self.send(&Frame::PublishPrekeys { bundle }).await
+    }
+
+    /// Pings the relay every `HEARTBEAT_INTERVAL` until the connection
+    /// fails, so that even a quiet connection hears back from it regularly.
+    pub fn spawn_heartbeat(&self) -> task::JoinHandle<()> {
+        let write = self.write.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(HEARTBEAT_INTERVAL);
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                if write
+                    .lock()
+                    .await
+                    .send(Message::Ping(Vec::new()))
+                    .await
+                    .is_err()
+                {
+                    break;
+                }
+            }
+        })
+    }
+
+    /// Sends a close frame, giving up after a second.
+    pub async fn close(&self) {
+        let _ = tokio::time::timeout(Duration::from_secs(1), async {
+            self.write.lock().await.close().await
+        })
+        .await;
+    }
+}
+
+impl RelayStream {
+    /// The next text message from the relay, unparsed. Fails with
+    /// `Error::Relay` once the relay closes the connection, giving the
+    /// reason it sent if any, or stays silent past `HEARTBEAT_TIMEOUT`.
+    pub async fn next_text(&mut self) -> Result<String> {
+        self.recv(HEARTBEAT_TIMEOUT).await
+    }
+
+    /// The next frame from the relay. A message that is not a frame fails
+    /// with `Error::Encoding`, after which reading can go on.
+    pub async fn next_frame(&mut self) -> Result<Frame> {
+        let text = self.next_text().await?;
+        serde_json::from_str(&text)
+            .map_err(|e| Error::encoding(format!("Unrecognised frame from the relay: {}", e)))
+    }
+
+    // Pings and pongs do not count as messages but do reset the timeout.
+    async fn recv(&mut self, timeout: Duration) -> Result<String> {
+        if let Some(text) = self.backlog.pop_front() {
+            return Ok(text);
+        }
+        loop {
+            match tokio::time::timeout(timeout, self.read.next()).await {
+                Ok(Some(Ok(Message::Text(text)))) => return Ok(text),
+                Ok(Some(Ok(Message::Close(Some(frame))))) if !frame.reason.is_empty() => {
+                    return Err(Error::relay(format!(
+                        "The relay closed the connection: {}.",
+                        frame.reason
+                    )));
+                }
+                Ok(Some(Ok(Message::Close(_)))) | Ok(None) => {
+                    return Err(Error::relay("The relay closed the connection."));
+                }
+                Ok(Some(Ok(_))) => continue,
+                Ok(Some(Err(e))) => {
+                    return Err(Error::relay(format!("Connection error: {}", e)));
+                }
+                Err(_) => return Err(Error::relay("The relay stopped responding.")),
+            }
+        }
+    }
+}
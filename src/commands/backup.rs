@@ -0,0 +1,119 @@
+use crate::commands::prompt::{load_identity, read_backup_passphrase, read_new_passphrase};
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use vaultsyn::io::backup::{create_backup, restore_backup, BackupArchive};
+use vaultsyn::{Error, KeyStore, Result};
+
+pub fn backup_identity(
+    store: &dyn KeyStore,
+    username: &str,
+    output: &Path,
+    force: bool,
+) -> Result<()> {
+    // Checked again when the file is created; this only avoids asking for
+    // passphrases first.
+    if output.exists() && !force {
+        return Err(already_exists(output));
+    }
+    let identity = load_identity(store, username)?;
+    let state = store.load_identity_state(&identity)?;
+    let contacts = store.list_contacts()?;
+    let passphrase = read_backup_passphrase(true)?;
+    let archive = create_backup(&identity, &state, &contacts, &passphrase)?;
+    write_archive(output, archive.to_json()?.as_bytes(), force).map_err(|e| {
+        if e.kind() == std::io::ErrorKind::AlreadyExists {
+            return already_exists(output);
+        }
+        std::io::Error::new(
+            e.kind(),
+            format!("Cannot write backup {}: {}", output.display(), e),
+        )
+        .into()
+    })?;
+
+    println!(
+        "💾 Backup of '{}' written to {} ({} sessions, {} contacts).",
+        username,
+        output.display(),
+        state.sessions.len(),
+        contacts.len()
+    );
+    println!("ℹ️  Message history is not included; Vaultsyn keeps none locally.");
+    println!("⚠️  Keep it and its passphrase safe: together they hold your private keys.");
+    Ok(())
+}
+
+fn already_exists(output: &Path) -> Error {
+    Error::rejected(format!(
+        "{} already exists. Pass --force to overwrite it",
+        output.display()
+    ))
+}
+
+// Readable by the owner only, like the store's own records. An existing file
+// is only replaced with `overwrite`.
+fn write_archive(path: &Path, contents: &[u8], overwrite: bool) -> std::io::Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true);
+    if overwrite {
+        options.create(true).truncate(true);
+    } else {
+        options.create_new(true);
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(path)?;
+    file.write_all(contents)?;
+    file.sync_all()
+}
+
+pub fn restore_identity(store: &dyn KeyStore, input: &Path, force: bool) -> Result<()> {
+    let json = fs::read(input).map_err(|e| {
+        std::io::Error::new(
+            e.kind(),
+            format!("Cannot read backup {}: {}", input.display(), e),
+        )
+    })?;
+    let archive = BackupArchive::from_json(&json)?;
+    println!(
+        "📦 Backup of '{}' from {}.",
+        archive.username,
+        archive.created_at.format("%Y-%m-%d %H:%M UTC")
+    );
+    // Checked again on restore; this only avoids asking for passphrases first.
+    if !force && store.list_identities()?.contains(&archive.username) {
+        return Err(Error::rejected(format!(
+            "User '{}' already exists. Pass --force to overwrite it",
+            archive.username
+        )));
+    }
+
+    let backup = archive.open(&read_backup_passphrase(false)?)?;
+    let passphrase = read_new_passphrase()?;
+    if passphrase.is_none() {
+        println!("⚠️  No passphrase given, private keys will be stored unencrypted.");
+    }
+    let report = restore_backup(store, &backup, passphrase.as_deref(), force)?;
+
+    println!(
+        "✅ Identity '{}' restored with {} sessions; {} contacts added.",
+        archive.username,
+        backup.state.sessions.len(),
+        report.contacts_added
+    );
+    for name in report.contacts_kept {
+        println!(
+            "⚠️  Contact '{}' has different keys in the backup; the pinned keys were kept.",
+            name
+        );
+    }
+    if store.default_identity()?.is_none() {
+        store.set_default_identity(Some(&archive.username))?;
+        println!("⭐ '{}' is now the default identity.", archive.username);
+    }
+    Ok(())
+}
@@ -1,10 +1,32 @@
+use crate::commands::relay::UnverifiedPolicy;
+use crate::commands::store::Backend;
 use clap::{Parser, Subcommand};
+use std::path::PathBuf;
+use vaultsyn::crypto::suite::CipherSuite;
+use vaultsyn::message::DEFAULT_MAX_CLOCK_SKEW;
 
+/// Storage settings are taken from their flag, then their environment
+/// variable, then the config file, then the default.
 #[derive(Parser)]
 #[command(name = "vaultsyn", version = "0.1", author = "Gamingstein")]
 pub struct VaultsynCli {
     #[command(subcommand)]
     pub command: Commands,
+    /// Directory holding identities and contacts [default: the platform data directory]
+    #[arg(long, global = true, env = "VAULTSYN_HOME")]
+    pub home: Option<PathBuf>,
+    /// How records are stored [default: files]
+    #[arg(long, global = true, value_enum, env = "VAULTSYN_BACKEND")]
+    pub backend: Option<Backend>,
+    /// TOML config file setting home and backend
+    #[arg(long, global = true, env = "VAULTSYN_CONFIG")]
+    pub config: Option<PathBuf>,
+    /// PEM certificate of a CA to trust for wss:// relays, e.g. a self-signed relay certificate
+    #[arg(long, global = true)]
+    pub tls_ca: Option<PathBuf>,
+    /// Only accept a wss:// relay presenting the certificate with this SHA-256 fingerprint
+    #[arg(long, global = true)]
+    pub tls_pin: Option<String>,
 }
 
 #[derive(Subcommand)]
@@ -12,30 +34,152 @@ pub enum Commands {
     /// Create a new user identity
     CreateUser {
         username: String,
+        /// Replace an existing identity of the same name, deleting its keys
+        #[arg(long)]
+        force: bool,
+    },
+    /// List identities, marking the default one
+    ListUsers,
+    /// Delete an identity with its prekeys and sessions
+    DeleteUser {
+        username: String,
+        /// Do not ask for confirmation
+        #[arg(long)]
+        yes: bool,
+    },
+    /// Rename an identity, keeping its keys and sessions
+    RenameUser { old: String, new: String },
+    /// Show the default identity, or set it
+    DefaultUser { username: Option<String> },
+    /// Write an identity, its sessions and the contacts to an encrypted backup file
+    #[command(allow_missing_positional = true)]
+    BackupIdentity {
+        /// [default: the default identity]
+        username: Option<String>,
+        output: PathBuf,
+        /// Overwrite an existing file
+        #[arg(long)]
+        force: bool,
+    },
+    /// Restore an identity from a backup file
+    RestoreIdentity {
+        input: PathBuf,
+        /// Replace an existing identity of the same name, deleting its keys
+        #[arg(long)]
+        force: bool,
+    },
+    /// Set, change or remove the passphrase protecting an identity
+    ChangePassphrase {
+        /// [default: the default identity]
+        username: Option<String>,
     },
     /// Export a public key
     ExportPublicKey {
-        username: String,
+        /// [default: the default identity]
+        username: Option<String>,
     },
     /// Send Message
+    #[command(allow_missing_positional = true)]
     SendMessage {
-        sender: String,
-        receiver_public_key: String,
+        /// [default: the default identity]
+        sender: Option<String>,
+        /// Contact name or base64 X25519 public key
+        receiver: Option<String>,
         message: String,
+        /// Derive the key from the sender's long-term X25519 key (legacy, no forward secrecy)
+        #[arg(long)]
+        static_key: bool,
+        /// Cipher suite: aes256-gcm or chacha20-poly1305
+        #[arg(long, default_value_t = CipherSuite::preferred())]
+        suite: CipherSuite,
     },
     /// Receive Message
+    #[command(allow_missing_positional = true)]
     ReceiveMessage {
-        receiver: String,
-        sender_ed25519_pub: String,
-        sender_x25519_pub: String,
+        /// [default: the default identity]
+        receiver: Option<String>,
         envelope_json: String,
+        /// Contact who sent the message; by default it is looked up from the envelope
+        #[arg(long, conflicts_with_all = ["sender_ed25519_pub", "sender_x25519_pub"])]
+        from: Option<String>,
+        /// Sender's keys, for senders who are not in the contacts
+        #[arg(long, requires = "sender_x25519_pub")]
+        sender_ed25519_pub: Option<String>,
+        #[arg(long, requires = "sender_ed25519_pub")]
+        sender_x25519_pub: Option<String>,
+        /// Largest accepted difference between message and local time, in seconds
+        #[arg(long, default_value_t = DEFAULT_MAX_CLOCK_SKEW)]
+        max_clock_skew: i64,
     },
     Connect {
         url: String,
+        /// Identity to authenticate to the relay as [default: the default identity]
+        username: Option<String>,
+    },
+    /// Publish a user's prekey bundle to the relay
+    PublishPrekeys {
+        url: String,
+        /// [default: the default identity]
+        username: Option<String>,
+    },
+    /// Compare a contact's fingerprint or your safety number, and mark it verified
+    #[command(allow_missing_positional = true)]
+    Verify {
+        /// [default: the default identity]
+        username: Option<String>,
+        contact: String,
+        /// Fingerprint or safety number obtained out of band, as digits or words
+        #[arg(long)]
+        fingerprint: Option<String>,
     },
+    /// Manage the contacts address book
+    Contact {
+        #[command(subcommand)]
+        command: ContactCommand,
+    },
+    #[command(allow_missing_positional = true)]
     Chat {
         url: String,
-        sender: String,
-        receiver_x25519_pub: String,
+        /// [default: the default identity]
+        sender: Option<String>,
+        /// Contact name or base64 X25519 public key
+        receiver: String,
+        /// Preferred cipher suite for new sessions, if the peer supports it
+        #[arg(long, default_value_t = CipherSuite::preferred())]
+        suite: CipherSuite,
+        /// Largest accepted difference between message and local time, in seconds
+        #[arg(long, default_value_t = DEFAULT_MAX_CLOCK_SKEW)]
+        max_clock_skew: i64,
+        /// Also accept messages from this contact (repeatable)
+        #[arg(long)]
+        allow: Vec<String>,
+        /// What to do with messages from anyone else
+        #[arg(long, value_enum, default_value_t = UnverifiedPolicy::Show)]
+        unverified: UnverifiedPolicy,
+        /// Without peer prekeys, start from both static keys; messages before
+        /// the first reply are then not forward secret
+        #[arg(long)]
+        static_session: bool,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum ContactCommand {
+    /// Import and pin the keys printed by export-public-key
+    Add {
+        /// File with the export-public-key output, "-" for stdin, or the JSON itself
+        source: String,
+        /// Save under this name instead of the exported username
+        #[arg(long)]
+        name: Option<String>,
+        /// Overwrite keys already pinned for this name
+        #[arg(long)]
+        replace: bool,
     },
+    /// List contacts and their key ids
+    List,
+    /// Show a contact's pinned keys
+    Show { name: String },
+    /// Remove a contact
+    Remove { name: String },
 }
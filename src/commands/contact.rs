@@ -0,0 +1,182 @@
+use crate::commands::prompt::load_identity;
+use chrono::Utc;
+use std::io::{Read, Write};
+use std::path::Path;
+use vaultsyn::contacts::{key_change_warning, parse_public_info, validate_name, Contact};
+use vaultsyn::crypto::fingerprint::SafetyNumber;
+use vaultsyn::crypto::keygen::key_id;
+use vaultsyn::{Error, KeyStore, Result};
+
+// `source` is a file, "-" for stdin, or the JSON itself.
+fn read_source(source: &str) -> Result<String> {
+    if source == "-" {
+        let mut input = String::new();
+        std::io::stdin().read_to_string(&mut input)?;
+        Ok(input)
+    } else if Path::new(source).is_file() {
+        Ok(std::fs::read_to_string(source)?)
+    } else {
+        Ok(source.to_string())
+    }
+}
+
+fn find_contact(store: &dyn KeyStore, name: &str) -> Result<Contact> {
+    store
+        .load_contact(name)?
+        .ok_or_else(|| Error::not_found(format!("Contact '{}'", name)))
+}
+
+pub fn add(store: &dyn KeyStore, source: &str, name: Option<&str>, replace: bool) -> Result<()> {
+    let info = parse_public_info(&read_source(source)?)?;
+    let name = name.unwrap_or(&info.username);
+    validate_name(name)?;
+
+    if let Some(existing) = store.load_contact(name)? {
+        if existing.matches(&info.ed25519_public, &info.x25519_public) {
+            println!("✅ Contact '{}' is already pinned to these keys.", name);
+            return Ok(());
+        }
+        println!(
+            "{}",
+            key_change_warning(&existing, &info.ed25519_public, &info.x25519_public)
+        );
+        if !replace {
+            return Err(Error::rejected(
+                "Keeping the pinned keys. Re-run with --replace to overwrite them.",
+            ));
+        }
+    }
+
+    if let Some(other) = store
+        .list_contacts()?
+        .into_iter()
+        .find(|c| c.name != name && c.ed25519_public == info.ed25519_public)
+    {
+        println!("ℹ️  These keys are also saved as contact '{}'.", other.name);
+    }
+
+    let contact = Contact::new(name, &info);
+    store.save_contact(&contact)?;
+    println!(
+        "📇 Pinned contact '{}' (ed25519 {}, x25519 {})",
+        contact.name,
+        key_id(&contact.ed25519_public)?,
+        key_id(&contact.x25519_public)?
+    );
+    println!(
+        "🔏 Fingerprint: {}\nℹ️  Compare it with {} out of band, then run: vaultsyn verify <username> {}",
+        contact.fingerprint()?.digits(),
+        contact.name,
+        contact.name
+    );
+    Ok(())
+}
+
+pub fn list(store: &dyn KeyStore) -> Result<()> {
+    let contacts = store.list_contacts()?;
+    if contacts.is_empty() {
+        println!("📭 No contacts yet. Import one with: vaultsyn contact add <file>");
+        return Ok(());
+    }
+    for contact in contacts {
+        println!(
+            "{} {}  ed25519 {}  x25519 {}  pinned {}",
+            if contact.verified_at.is_some() {
+                "✅"
+            } else {
+                "📇"
+            },
+            contact.name,
+            key_id(&contact.ed25519_public)?,
+            key_id(&contact.x25519_public)?,
+            contact.pinned_at.format("%Y-%m-%d")
+        );
+    }
+    Ok(())
+}
+
+pub fn show(store: &dyn KeyStore, name: &str) -> Result<()> {
+    let contact = find_contact(store, name)?;
+    let fingerprint = contact.fingerprint()?;
+    println!("{}", serde_json::to_string_pretty(&contact)?);
+    println!(
+        "🔏 Fingerprint: {}\n   Words:       {}",
+        fingerprint.digits(),
+        fingerprint.words()
+    );
+    match contact.verified_at {
+        Some(at) => println!("✅ Verified on {}", at.format("%Y-%m-%d %H:%M UTC")),
+        None => println!("⚠️  Not verified yet"),
+    }
+    Ok(())
+}
+
+pub fn remove(store: &dyn KeyStore, name: &str) -> Result<()> {
+    find_contact(store, name)?;
+    store.remove_contact(name)?;
+    println!("🗑️  Removed contact '{}'", name);
+    Ok(())
+}
+
+/// Shows the contact's fingerprint and the safety number shared with
+/// `username`, and marks the contact verified once either one is confirmed:
+/// given with `--fingerprint`, or confirmed at the prompt.
+pub fn verify(
+    store: &dyn KeyStore,
+    username: &str,
+    name: &str,
+    expected: Option<&str>,
+) -> Result<()> {
+    let mut contact = find_contact(store, name)?;
+    let identity = load_identity(store, username)?;
+    let fingerprint = contact.fingerprint()?;
+    let safety_number = SafetyNumber::new(&identity.fingerprint(), &fingerprint);
+
+    println!(
+        "🔏 Fingerprint of {}:\n   {}\n   {}",
+        contact.name,
+        fingerprint.digits(),
+        fingerprint.words()
+    );
+    println!(
+        "🔐 Safety number for {} and {}:\n   {}\n   {}",
+        username,
+        contact.name,
+        safety_number.digits().replace('\n', "\n   "),
+        safety_number.words()
+    );
+
+    let confirmed = match expected {
+        Some(value) => {
+            let matched = fingerprint.matches(value) || safety_number.matches(value);
+            if !matched {
+                println!(
+                    "🚨 MISMATCH: the value you entered is not {}'s fingerprint or your safety number.",
+                    contact.name
+                );
+            }
+            matched
+        }
+        None => {
+            print!(
+                "❓ Does this match what {} sees on their side? [y/N] ",
+                contact.name
+            );
+            std::io::stdout().flush()?;
+            let mut answer = String::new();
+            std::io::stdin().read_line(&mut answer)?;
+            matches!(answer.trim(), "y" | "Y" | "yes")
+        }
+    };
+
+    if !confirmed {
+        return Err(Error::rejected(format!(
+            "'{}' was not marked as verified.",
+            contact.name
+        )));
+    }
+    contact.verified_at = Some(Utc::now());
+    store.save_contact(&contact)?;
+    println!("✅ Marked '{}' as verified.", contact.name);
+    Ok(())
+}
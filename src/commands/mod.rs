@@ -1,2 +1,7 @@
+pub mod backup;
 pub mod cli;
+pub mod contact;
+pub mod prompt;
+pub mod relay;
+pub mod store;
 pub mod user;
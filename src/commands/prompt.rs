@@ -0,0 +1,128 @@
+use std::env;
+use std::io::{self, Write};
+use std::path::Path;
+use vaultsyn::crypto::vault::open_identity;
+use vaultsyn::io::storage::IdentityFile;
+use vaultsyn::{EncryptedFileStore, Error, Identity, KeyStore, Result};
+
+/// Non-interactive overrides, mainly for scripts and CI.
+pub const PASSPHRASE_ENV: &str = "VAULTSYN_PASSPHRASE";
+pub const NEW_PASSPHRASE_ENV: &str = "VAULTSYN_NEW_PASSPHRASE";
+pub const STORE_PASSPHRASE_ENV: &str = "VAULTSYN_STORE_PASSPHRASE";
+pub const BACKUP_PASSPHRASE_ENV: &str = "VAULTSYN_BACKUP_PASSPHRASE";
+
+pub fn read_passphrase(prompt: &str) -> Result<String> {
+    if let Ok(passphrase) = env::var(PASSPHRASE_ENV) {
+        return Ok(passphrase);
+    }
+    Ok(rpassword::prompt_password(prompt)?)
+}
+
+/// Prompts twice and returns `None` if the user chose an empty passphrase.
+pub fn read_new_passphrase() -> Result<Option<String>> {
+    if let Ok(passphrase) = env::var(NEW_PASSPHRASE_ENV).or_else(|_| env::var(PASSPHRASE_ENV)) {
+        return Ok((!passphrase.is_empty()).then_some(passphrase));
+    }
+
+    loop {
+        let first = rpassword::prompt_password("🔐 New passphrase (empty for none): ")?;
+        if first.is_empty() {
+            return Ok(None);
+        }
+        let second = rpassword::prompt_password("🔐 Repeat passphrase: ")?;
+        if first == second {
+            return Ok(Some(first));
+        }
+        println!("❌ Passphrases do not match, try again.");
+    }
+}
+
+/// Asks for the passphrase of the encrypted store at `home`, twice if the
+/// store is about to be created.
+pub fn read_store_passphrase(home: &Path) -> Result<String> {
+    if let Ok(passphrase) = env::var(STORE_PASSPHRASE_ENV) {
+        return Ok(passphrase);
+    }
+    if EncryptedFileStore::exists(home) {
+        return Ok(rpassword::prompt_password("🔐 Store passphrase: ")?);
+    }
+
+    println!("🗄️  Creating an encrypted store in {}", home.display());
+    loop {
+        let first = rpassword::prompt_password("🔐 New store passphrase: ")?;
+        if first.is_empty() {
+            println!("❌ The store passphrase must not be empty.");
+            continue;
+        }
+        let second = rpassword::prompt_password("🔐 Repeat passphrase: ")?;
+        if first == second {
+            return Ok(first);
+        }
+        println!("❌ Passphrases do not match, try again.");
+    }
+}
+
+/// Asks for a backup passphrase, twice when a new backup is written.
+pub fn read_backup_passphrase(new: bool) -> Result<String> {
+    if let Ok(passphrase) = env::var(BACKUP_PASSPHRASE_ENV) {
+        return Ok(passphrase);
+    }
+    if !new {
+        return Ok(rpassword::prompt_password("🔐 Backup passphrase: ")?);
+    }
+    loop {
+        let first = rpassword::prompt_password("🔐 New backup passphrase: ")?;
+        if first.is_empty() {
+            println!("❌ The backup passphrase must not be empty.");
+            continue;
+        }
+        let second = rpassword::prompt_password("🔐 Repeat passphrase: ")?;
+        if first == second {
+            return Ok(first);
+        }
+        println!("❌ Passphrases do not match, try again.");
+    }
+}
+
+/// Loads an identity, prompting for its passphrase if the file is encrypted.
+pub fn load_identity(store: &dyn KeyStore, username: &str) -> Result<Identity> {
+    load_identity_with_passphrase(store, username).map(|(identity, _)| identity)
+}
+
+/// Like [`load_identity`], also returning the passphrase so the identity can
+/// be saved again under it.
+pub fn load_identity_with_passphrase(
+    store: &dyn KeyStore,
+    username: &str,
+) -> Result<(Identity, Option<String>)> {
+    let sealed = match store.read_identity_file(username)? {
+        IdentityFile::Plain(identity) => return Ok((identity, None)),
+        IdentityFile::Encrypted(sealed) => sealed,
+    };
+
+    // Only retry when the passphrase is typed interactively.
+    let attempts = if env::var(PASSPHRASE_ENV).is_ok() {
+        1
+    } else {
+        3
+    };
+    let mut last_error = Error::auth("No passphrase given");
+    for attempt in 1..=attempts {
+        let passphrase = read_passphrase(&format!("🔐 Passphrase for '{}': ", username))?;
+        match open_identity(&sealed, &passphrase) {
+            Ok(identity) => return Ok((identity, Some(passphrase))),
+            Err(e) if attempt < attempts => println!("❌ {}", e),
+            Err(e) => last_error = e,
+        }
+    }
+    Err(last_error)
+}
+
+/// Asks the user to type `expected` back before a destructive action.
+pub fn confirm(prompt: &str, expected: &str) -> Result<bool> {
+    print!("{}", prompt);
+    io::stdout().flush()?;
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    Ok(answer.trim() == expected)
+}
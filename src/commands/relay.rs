@@ -0,0 +1,733 @@
+use crate::commands::prompt::load_identity;
+use vaultsyn::client::prepare_prekeys;
+use vaultsyn::contacts::{check_pin, key_change_warning, resolve_x25519, Contact, PinCheck};
+use vaultsyn::crypto::keygen::{key_id, Identity};
+use vaultsyn::crypto::ratchet::{Session, SessionRecord};
+use vaultsyn::crypto::suite::CipherSuite;
+use vaultsyn::message::{
+    decrypt_and_verify_message, decrypt_with_sessions, encrypt_with_session, MessageEnvelope,
+    VaultsynTransport,
+};
+use vaultsyn::protocol::{ErrorCode, Frame};
+use vaultsyn::{Error, KeyStore, RelayClient, Result, TlsTrust};
+
+use crossterm::style::*;
+use rustyline::Editor;
+use tokio::task;
+
+use std::io::{stdout, Stdout, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+async fn connect(url: &str, identity: &Identity, tls: &TlsTrust) -> Result<RelayClient> {
+    if !url.starts_with("wss://") && tls.is_configured() {
+        println!("⚠️  --tls-ca and --tls-pin only apply to wss:// relays");
+    }
+    RelayClient::connect(url, identity, tls).await
+}
+
+pub async fn publish_prekeys(
+    store: &dyn KeyStore,
+    url: &str,
+    username: &str,
+    tls: &TlsTrust,
+) -> Result<()> {
+    let identity = load_identity(store, username)?;
+    let prekeys = prepare_prekeys(store, &identity)?;
+
+    let client = connect(url, &identity, tls).await?;
+    client
+        .sink()
+        .publish_prekeys(prekeys.bundle(&identity))
+        .await?;
+    client.sink().close().await;
+    println!("✅ Published prekeys for '{}' to {}", username, url);
+    Ok(())
+}
+
+/// What to do with messages from senders the chat does not trust.
+#[derive(Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum UnverifiedPolicy {
+    /// Display them, clearly marked as unverified.
+    Show,
+    /// Discard them silently.
+    Drop,
+}
+
+pub struct ChatOptions {
+    /// Preferred cipher suite for new sessions.
+    pub suite: CipherSuite,
+    pub max_clock_skew: i64,
+    /// Contacts whose messages are accepted besides the peer's.
+    pub allow: Vec<String>,
+    pub unverified: UnverifiedPolicy,
+    /// Whether a peer without prekeys may get a session bootstrapped from
+    /// both static keys, which is not forward secret until the first reply.
+    pub static_session: bool,
+    pub tls: TlsTrust,
+}
+
+/// Senders a chat accepts messages from.
+struct TrustedSenders {
+    peer_x25519: String,
+    /// The peer's pinned keys, if it was given as a contact.
+    peer: Option<Contact>,
+    allowed: Vec<Contact>,
+}
+
+enum SenderTrust {
+    /// The chat peer, shown under this name.
+    Peer(String),
+    /// A contact on the allow-list.
+    Allowed(String),
+    Unverified,
+}
+
+impl TrustedSenders {
+    fn classify(&self, transport: &VaultsynTransport) -> SenderTrust {
+        let (ed, x) = (&transport.sender_ed25519_pub, &transport.sender_x25519_pub);
+        let header = &transport.envelope.header;
+        match &self.peer {
+            Some(contact) if contact.matches(ed, x) => {
+                return SenderTrust::Peer(contact.name.clone())
+            }
+            // Without a pinned signing key only the session can vouch for the
+            // sender: ratchet messages decrypt only for the holder of the peer key
+            None if *x == self.peer_x25519 && header.ratchet.is_some() => {
+                return SenderTrust::Peer(header.from.clone())
+            }
+            _ => {}
+        }
+        match self.allowed.iter().find(|contact| contact.matches(ed, x)) {
+            Some(contact) => SenderTrust::Allowed(contact.name.clone()),
+            None => SenderTrust::Unverified,
+        }
+    }
+}
+
+/// The stored session record for an allowed contact. A contact that starts
+/// without an X3DH handshake uses the session both sides derive from their
+/// static keys, as the chat peer does.
+fn load_contact_record(
+    store: &dyn KeyStore,
+    identity: &Identity,
+    envelope: &MessageEnvelope,
+    sender_x25519: &str,
+) -> Result<SessionRecord> {
+    let mut record = store.load_session_record(identity, sender_x25519)?;
+    if record.current.is_none() && envelope.header.prekey.is_none() {
+        if let Ok(session) = Session::from_static_keys(identity, sender_x25519) {
+            record.promote(session);
+        }
+    }
+    Ok(record)
+}
+
+// Reported in place of a message the reader could not handle, above the
+// prompt.
+fn print_failure(out: &Mutex<Stdout>, prompt: &str, label: &str, e: &Error) {
+    let mut out = out.lock().unwrap();
+    writeln!(&mut *out, "\r{} {}\n{} ❯ ", label.red(), e, prompt.blue()).unwrap();
+}
+
+pub async fn chat(
+    store: Arc<dyn KeyStore>,
+    url: &str,
+    sender_id: &str,
+    receiver: &str,
+    options: ChatOptions,
+) -> Result<()> {
+    let (receiver_pub_x25519, contact) = resolve_x25519(&*store, receiver)?;
+    let receiver_pub_x25519 = receiver_pub_x25519.as_str();
+    let peer_key_id = key_id(receiver_pub_x25519)?;
+    let mut allowed = Vec::new();
+    for name in &options.allow {
+        allowed.push(
+            store
+                .load_contact(name)?
+                .ok_or_else(|| Error::not_found(format!("Contact '{}'", name)))?,
+        );
+    }
+    let trusted = TrustedSenders {
+        peer_x25519: receiver_pub_x25519.to_string(),
+        peer: contact.clone(),
+        allowed,
+    };
+    let ChatOptions {
+        suite: preferred_suite,
+        max_clock_skew,
+        unverified,
+        static_session,
+        tls,
+        ..
+    } = options;
+    let identity = load_identity(&*store, sender_id)?;
+    let prekeys = prepare_prekeys(&*store, &identity)?;
+    let mut record = store.load_session_record(&identity, receiver_pub_x25519)?;
+
+    let mut client = connect(url, &identity, &tls).await?;
+
+    println!("📡 Connected securely to Vaultsyn at {}", url);
+    if let Some(contact) = contact.as_ref().filter(|c| c.verified_at.is_none()) {
+        println!(
+            "ℹ️  {}'s keys are pinned but not verified. Compare safety numbers with: vaultsyn verify {} {}",
+            contact.name, sender_id, contact.name
+        );
+    }
+
+    // Hear when the peer connects or leaves
+    let watch = Frame::Watch {
+        recipient_key_id: peer_key_id.clone(),
+    };
+    client.sink().send(&watch).await?;
+
+    // Publish our bundle so peers can start sessions while we are offline
+    if let Err(e) = client
+        .sink()
+        .publish_prekeys(prekeys.bundle(&identity))
+        .await
+    {
+        println!("{} {}", "⚠️  Failed to publish prekeys:".red(), e);
+    }
+
+    // Start a session via X3DH if the peer published prekeys. Falling back to
+    // a ratchet bootstrapped from both static keys needs --static-session
+    if record.current.is_none() {
+        let bundle =
+            client
+                .fetch_prekeys(receiver_pub_x25519)
+                .await?
+                .filter(|bundle| match &contact {
+                    Some(contact)
+                        if !contact.matches(&bundle.ed25519_public, &bundle.x25519_public) =>
+                    {
+                        println!(
+                            "{}\n{}",
+                            key_change_warning(
+                                contact,
+                                &bundle.ed25519_public,
+                                &bundle.x25519_public
+                            )
+                            .red(),
+                            "⚠️  Ignoring prekeys that do not match the pinned contact.".red()
+                        );
+                        false
+                    }
+                    _ => true,
+                });
+        let session = match bundle.map(|bundle| {
+            Session::from_bundle(&identity, &bundle).map(|mut session| {
+                session.suite = CipherSuite::negotiate(preferred_suite, &bundle.suites);
+                session
+            })
+        }) {
+            Some(Ok(session)) => session,
+            fallback => {
+                if let Some(Err(e)) = fallback {
+                    println!("{} {}", "⚠️  Ignoring peer prekeys:".red(), e);
+                }
+                if !static_session {
+                    return Err(Error::rejected(
+                        "The peer has no usable prekeys on the relay. Ask them to run publish-prekeys, or pass --static-session to start without forward secrecy for the first messages",
+                    ));
+                }
+                println!(
+                    "{}",
+                    "⚠️  Starting from both static keys: messages before the peer's first reply are not forward secret."
+                        .yellow()
+                );
+                Session::from_static_keys(&identity, receiver_pub_x25519)?
+            }
+        };
+        record.promote(session);
+        store.save_session_record(&identity, receiver_pub_x25519, &record)?;
+    }
+    let identity = Arc::new(identity);
+    let record = Arc::new(Mutex::new(record));
+    let prekeys = Arc::new(Mutex::new(prekeys));
+    // Shared with the reader, which acks delivered envelopes
+    let (sink, mut stream) = client.split();
+    let heartbeat = sink.spawn_heartbeat();
+    // Set by the reader once the relay is gone, so the prompt stops
+    let relay_lost = Arc::new(AtomicBool::new(false));
+
+    // Shared stdout for sync printing
+    let stdout = Arc::new(Mutex::new(stdout()));
+
+    // Read incoming messages
+    let sender_id_reader = Arc::new(sender_id.to_string());
+    let stdout_reader = stdout.clone();
+    let reader_task = {
+        let sender_id_reader = sender_id_reader.clone();
+        // Unlocked once up front; reloading per message would re-prompt for the passphrase.
+        let identity = identity.clone();
+        let store = store.clone();
+        let record = record.clone();
+        let prekeys = prekeys.clone();
+        let own_key_id = identity.x25519_public.key_id();
+        let sink_reader = sink.clone();
+        let peer_label = contact
+            .as_ref()
+            .map(|contact| contact.name.clone())
+            .unwrap_or_else(|| format!("Peer {}", peer_key_id));
+        let relay_lost = relay_lost.clone();
+        tokio::spawn(async move {
+            let notice = loop {
+                let text = match stream.next_text().await {
+                    Ok(text) => text,
+                    Err(notice) => break notice,
+                };
+                let transport = match serde_json::from_str::<Frame>(&text) {
+                    Ok(Frame::Deliver {
+                        transport,
+                        queued_at,
+                    }) => serde_json::from_value::<VaultsynTransport>(transport)
+                        .map(|transport| (transport, queued_at)),
+                    Ok(Frame::Queued { .. }) => {
+                        let mut out = stdout_reader.lock().unwrap();
+                        writeln!(
+                            &mut *out,
+                            "\r{}\n{} ❯ ",
+                            "📬 Peer is offline, message queued for delivery.".yellow(),
+                            sender_id_reader.as_str().blue()
+                        )
+                        .unwrap();
+                        continue;
+                    }
+                    Ok(Frame::Error { code, message }) => {
+                        let notice = match code {
+                            ErrorCode::UnknownRecipient => {
+                                String::from("📭 Peer is not connected, message not delivered.")
+                            }
+                            ErrorCode::QueueFull => String::from(
+                                "📪 Peer's offline queue is full, message not delivered.",
+                            ),
+                            ErrorCode::RateLimited => {
+                                String::from("⏳ Sending too fast, the relay dropped a message.")
+                            }
+                            ErrorCode::StoreFailed => String::from(
+                                "💾 The relay could not store the message, not delivered.",
+                            ),
+                            ErrorCode::FrameTooLarge => {
+                                format!("📦 Message too large for the relay: {}", message)
+                            }
+                            _ => format!("⚠️  Relay error: {}", message),
+                        };
+                        let mut out = stdout_reader.lock().unwrap();
+                        writeln!(
+                            &mut *out,
+                            "\r{}\n{} ❯ ",
+                            notice.red(),
+                            sender_id_reader.as_str().blue()
+                        )
+                        .unwrap();
+                        continue;
+                    }
+                    Ok(Frame::Presence {
+                        recipient_key_id,
+                        online,
+                    }) if recipient_key_id == peer_key_id => {
+                        let notice = if online {
+                            format!("🟢 {} is online.", peer_label).green()
+                        } else {
+                            format!("⚪ {} is offline.", peer_label).dark_grey()
+                        };
+                        let mut out = stdout_reader.lock().unwrap();
+                        writeln!(
+                            &mut *out,
+                            "\r{}\n{} ❯ ",
+                            notice,
+                            sender_id_reader.as_str().blue()
+                        )
+                        .unwrap();
+                        continue;
+                    }
+                    Ok(_) => continue,
+                    Err(e) => Err(e),
+                };
+                match transport {
+                    Ok((envelope, queued_at)) => {
+                        if envelope.sender_ed25519_pub == identity.ed25519_public.to_base64() {
+                            continue; // skip self-echo
+                        }
+                        let recipient = &envelope.envelope.header.recipient_key_id;
+                        if !recipient.is_empty() && *recipient != own_key_id {
+                            continue; // addressed to someone else
+                        }
+
+                        // Envelopes are acked once handled for good: shown,
+                        // refused, or rejected as replayed, stale or
+                        // undecryptable. The relay keeps the rest queued
+                        let mut handled = false;
+                        'handle: {
+                            // Only the peer and allowed contacts are trusted. A
+                            // stranger using a pinned contact's name, or a contact
+                            // with new keys, is always refused
+                            let trust = trusted.classify(&envelope);
+                            let pin = match check_pin(
+                                &*store,
+                                &envelope.envelope.header.from,
+                                &envelope.sender_ed25519_pub,
+                                &envelope.sender_x25519_pub,
+                            ) {
+                                Ok(pin) => pin,
+                                Err(e) => {
+                                    print_failure(
+                                        &stdout_reader,
+                                        &sender_id_reader,
+                                        "⚠️  Cannot read contacts:",
+                                        &e,
+                                    );
+                                    break 'handle;
+                                }
+                            };
+                            let display_name = match (&trust, pin) {
+                                (SenderTrust::Peer(name) | SenderTrust::Allowed(name), _) => {
+                                    name.clone()
+                                }
+                                (SenderTrust::Unverified, PinCheck::Changed(contact)) => {
+                                    let mut out = stdout_reader.lock().unwrap();
+                                    writeln!(
+                                        &mut *out,
+                                        "\r{}\n{}\n{} ❯ ",
+                                        key_change_warning(
+                                            &contact,
+                                            &envelope.sender_ed25519_pub,
+                                            &envelope.sender_x25519_pub
+                                        )
+                                        .red(),
+                                        "⚠️  Message dropped.".red(),
+                                        sender_id_reader.as_str().blue()
+                                    )
+                                    .unwrap();
+                                    handled = true;
+                                    break 'handle;
+                                }
+                                // A pinned contact's session messages are kept
+                                // queued for a chat with them
+                                (SenderTrust::Unverified, PinCheck::Match(contact))
+                                    if envelope.envelope.header.ratchet.is_some()
+                                        || unverified == UnverifiedPolicy::Drop =>
+                                {
+                                    let mut out = stdout_reader.lock().unwrap();
+                                    writeln!(
+                                        &mut *out,
+                                        "\r{}\n{} ❯ ",
+                                        format!(
+                                            "📥 Message from {} kept for your chat with them.",
+                                            contact.name
+                                        )
+                                        .yellow(),
+                                        sender_id_reader.as_str().blue()
+                                    )
+                                    .unwrap();
+                                    break 'handle;
+                                }
+                                (SenderTrust::Unverified, _)
+                                    if unverified == UnverifiedPolicy::Drop =>
+                                {
+                                    handled = true;
+                                    break 'handle;
+                                }
+                                (SenderTrust::Unverified, _) => {
+                                    format!("{}?", envelope.envelope.header.from)
+                                }
+                            };
+
+                            let mut replay = match store
+                                .load_replay_cache(&identity, &envelope.sender_ed25519_pub)
+                            {
+                                Ok(replay) => replay,
+                                Err(e) => {
+                                    print_failure(
+                                        &stdout_reader,
+                                        &sender_id_reader,
+                                        "⚠️  Cannot read replay cache:",
+                                        &e,
+                                    );
+                                    break 'handle;
+                                }
+                            };
+                            replay.max_skew = max_clock_skew;
+                            replay.queued_at = queued_at;
+
+                            let result = if envelope.envelope.header.ratchet.is_some()
+                                && !matches!(trust, SenderTrust::Unverified)
+                            {
+                                // The peer's record is shared with the writer;
+                                // allowed contacts' records are only used here
+                                let mut peer_record = record.lock().unwrap();
+                                let mut contact_record;
+                                let record = if matches!(trust, SenderTrust::Peer(_)) {
+                                    &mut *peer_record
+                                } else {
+                                    contact_record = match load_contact_record(
+                                        &*store,
+                                        &identity,
+                                        &envelope.envelope,
+                                        &envelope.sender_x25519_pub,
+                                    ) {
+                                        Ok(record) => record,
+                                        Err(e) => {
+                                            print_failure(
+                                                &stdout_reader,
+                                                &sender_id_reader,
+                                                "⚠️  Cannot read session:",
+                                                &e,
+                                            );
+                                            break 'handle;
+                                        }
+                                    };
+                                    &mut contact_record
+                                };
+                                let mut prekeys = prekeys.lock().unwrap();
+                                decrypt_with_sessions(
+                                    &envelope.envelope,
+                                    record,
+                                    &identity,
+                                    &mut prekeys,
+                                    &envelope.sender_ed25519_pub,
+                                    &envelope.sender_x25519_pub,
+                                    &mut replay,
+                                )
+                                .inspect(|_| {
+                                    let _ = store.save_session_record(
+                                        &identity,
+                                        &envelope.sender_x25519_pub,
+                                        record,
+                                    );
+                                    if envelope.envelope.header.prekey.is_some() {
+                                        let _ = store.save_prekeys(&identity, &prekeys);
+                                    }
+                                })
+                            } else {
+                                decrypt_and_verify_message(
+                                    &envelope.envelope,
+                                    &identity,
+                                    &envelope.sender_ed25519_pub,
+                                    &envelope.sender_x25519_pub,
+                                    &mut replay,
+                                )
+                            };
+                            if result.is_ok() {
+                                let _ = store.save_replay_cache(
+                                    &identity,
+                                    &envelope.sender_ed25519_pub,
+                                    &replay,
+                                );
+                            }
+
+                            handled = true;
+                            match result {
+                                Ok(decrypted) if matches!(trust, SenderTrust::Unverified) => {
+                                    let mut out = stdout_reader.lock().unwrap();
+                                    writeln!(
+                                        &mut *out,
+                                        "\r{} {} {}: {}\n{} ❯ ",
+                                        "❓".red(),
+                                        "[UNVERIFIED]".red().bold(),
+                                        display_name.red(),
+                                        decrypted,
+                                        sender_id_reader.as_str().blue()
+                                    )
+                                    .unwrap();
+                                }
+                                Ok(decrypted) => {
+                                    let mut out = stdout_reader.lock().unwrap();
+                                    writeln!(
+                                        &mut *out,
+                                        "\r{} {}: {}\n{} ❯ ",
+                                        "📨".yellow(),
+                                        display_name.green(),
+                                        decrypted,
+                                        sender_id_reader.as_str().blue()
+                                    )
+                                    .unwrap();
+                                }
+                                Err(e) => {
+                                    let mut out = stdout_reader.lock().unwrap();
+                                    writeln!(
+                                        &mut *out,
+                                        "\r{} {}\n{} ❯ ",
+                                        "⚠️  Decryption failed:".red(),
+                                        e,
+                                        sender_id_reader.as_str().blue()
+                                    )
+                                    .unwrap();
+                                }
+                            }
+                        }
+
+                        if handled {
+                            let _ = sink_reader.ack(&envelope.envelope.header.message_id).await;
+                        }
+                    }
+                    Err(_) => {
+                        let mut out = stdout_reader.lock().unwrap();
+                        writeln!(
+                            &mut *out,
+                            "\r{} {}\n{} ❯ ",
+                            "⚠️  Invalid message format.".red(),
+                            text,
+                            sender_id_reader.as_str().blue()
+                        )
+                        .unwrap();
+                    }
+                }
+            };
+            relay_lost.store(true, Ordering::SeqCst);
+            let mut out = stdout_reader.lock().unwrap();
+            writeln!(
+                &mut *out,
+                "\r{} {}",
+                notice.to_string().red(),
+                "Press Enter to exit.".dark_grey()
+            )
+            .unwrap();
+        })
+    };
+
+    // Write outgoing messages
+    let identity_writer = identity.clone();
+    let store_writer = store.clone();
+    let record_writer = record.clone();
+    let receiver_pub_x25519_writer = receiver_pub_x25519.to_string();
+    let sender_id_writer = sender_id.to_string();
+    let stdout_writer = stdout.clone();
+    let sink_writer = sink.clone();
+
+    let writer_task = task::spawn_blocking(move || {
+        let mut rl = match Editor::<(), rustyline::history::DefaultHistory>::new() {
+            Ok(rl) => rl,
+            Err(e) => {
+                println!("❌ Cannot read from the terminal: {}", e);
+                return;
+            }
+        };
+        let prompt = format!("{} ❯ ", sender_id_writer.clone().blue());
+
+        println!("💬 Encrypted chat ready. Type /exit to quit.");
+
+        while let Ok(line) = rl.readline(&prompt) {
+            if line.trim() == "/exit" || relay_lost.load(Ordering::SeqCst) {
+                break;
+            }
+
+            if !line.trim().is_empty() {
+                let _ = rl.add_history_entry(line.as_str());
+
+                let envelope = {
+                    let mut record = record_writer.lock().unwrap();
+                    let envelope = record
+                        .current
+                        .as_mut()
+                        .ok_or_else(|| Error::not_found("Session with this peer"))
+                        .and_then(|session| encrypt_with_session(&identity_writer, session, &line));
+                    if let Err(e) = store_writer.save_session_record(
+                        &identity_writer,
+                        &receiver_pub_x25519_writer,
+                        &record,
+                    ) {
+                        let mut out = stdout_writer.lock().unwrap();
+                        writeln!(&mut *out, "{} {}", "⚠️  Failed to save session:".red(), e)
+                            .unwrap();
+                    }
+                    envelope
+                };
+                let envelope = match envelope {
+                    Ok(envelope) => envelope,
+                    Err(e) => {
+                        let mut out = stdout_writer.lock().unwrap();
+                        writeln!(
+                            &mut *out,
+                            "{} {}",
+                            "⚠️  Failed to encrypt message:".red(),
+                            e
+                        )
+                        .unwrap();
+                        continue;
+                    }
+                };
+
+                let transport = VaultsynTransport {
+                    envelope,
+                    sender_ed25519_pub: identity_writer.ed25519_public.to_base64(),
+                    sender_x25519_pub: identity_writer.x25519_public.to_base64(),
+                };
+
+                if let Err(e) = futures::executor::block_on(sink_writer.deliver(&transport)) {
+                    let mut out = stdout_writer.lock().unwrap();
+                    writeln!(&mut *out, "{} {}", "⚠️  Failed to send message:".red(), e).unwrap();
+                    break;
+                }
+
+                let mut out = stdout_writer.lock().unwrap();
+                writeln!(
+                    &mut *out,
+                    "{} {}: {}",
+                    "🕒".dim(),
+                    sender_id_writer.clone().green(),
+                    line.trim()
+                )
+                .unwrap();
+            }
+        }
+    });
+
+    let _ = writer_task.await;
+    reader_task.abort();
+    heartbeat.abort();
+    sink.close().await;
+    println!("{}", "🔌 Disconnected.".dark_grey());
+    Ok(())
+}
+
+/// Authenticates as `username`, then prints whatever the relay sends and
+/// sends each line typed as is. Meant for poking at a relay.
+pub async fn connect_raw(
+    store: &dyn KeyStore,
+    url: &str,
+    username: &str,
+    tls: &TlsTrust,
+) -> Result<()> {
+    let identity = load_identity(store, username)?;
+    let (sink, mut stream) = connect(url, &identity, tls).await?.split();
+    println!(
+        "📡 Connected to Vaultsyn network at {} as {}",
+        url, username
+    );
+
+    let heartbeat = sink.spawn_heartbeat();
+
+    // Spawn task to listen
+    let reader = tokio::spawn(async move {
+        let notice = loop {
+            match stream.next_text().await {
+                Ok(text) => println!("📨 Received: {}", text),
+                Err(notice) => break notice,
+            }
+        };
+        println!("{} Press Enter to exit.", notice);
+    });
+
+    // REPL loop: send messages
+    let stdin = std::io::stdin();
+    let mut input = String::new();
+    println!("💬 Type to send messages:");
+
+    loop {
+        input.clear();
+        if stdin.read_line(&mut input)? == 0 || input.trim() == "/exit" || reader.is_finished() {
+            break;
+        }
+        if let Err(e) = sink.send_text(input.trim().to_string()).await {
+            println!("⚠️  {}", e);
+            break;
+        }
+    }
+    heartbeat.abort();
+    sink.close().await;
+
+    println!("🔌 Disconnected.");
+    Ok(())
+}
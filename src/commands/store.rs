@@ -0,0 +1,59 @@
+use crate::commands::prompt::read_store_passphrase;
+use serde::Deserialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use vaultsyn::{EncryptedFileStore, Error, FileStore, KeyStore, Result};
+
+/// How identities, contacts and session state are kept on disk.
+#[derive(Clone, Copy, PartialEq, Eq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    /// One JSON file per record; identities are encrypted under their own passphrase.
+    Files,
+    /// Every record encrypted under a store passphrase.
+    Encrypted,
+}
+
+/// The --config file. Every key is optional.
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct FileConfig {
+    home: Option<PathBuf>,
+    backend: Option<Backend>,
+}
+
+fn read_config(path: &Path) -> Result<FileConfig> {
+    let text = fs::read_to_string(path).map_err(|e| {
+        std::io::Error::new(
+            e.kind(),
+            format!("Cannot read config {}: {}", path.display(), e),
+        )
+    })?;
+    toml::from_str(&text)
+        .map_err(|e| Error::encoding(format!("Invalid config {}: {}", path.display(), e)))
+}
+
+/// Opens the store selected by the flags, falling back to the config file
+/// and then to plain files in the platform data directory.
+pub fn open(
+    home: Option<PathBuf>,
+    backend: Option<Backend>,
+    config: Option<&Path>,
+) -> Result<Arc<dyn KeyStore>> {
+    let file = match config {
+        Some(path) => read_config(path)?,
+        None => FileConfig::default(),
+    };
+    let home = match home.or(file.home) {
+        Some(home) => home,
+        None => FileStore::default_home()?,
+    };
+    Ok(match backend.or(file.backend).unwrap_or(Backend::Files) {
+        Backend::Files => Arc::new(FileStore::open(home)?),
+        Backend::Encrypted => {
+            let passphrase = read_store_passphrase(&home)?;
+            Arc::new(EncryptedFileStore::open(home, &passphrase)?)
+        }
+    })
+}
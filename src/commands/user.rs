@@ -1,40 +1,280 @@
-use crate::crypto::keygen::{generate_identity, Identity};
-use crate::io::storage::{load_identity, save_identity};
-use crate::message::{decrypt_and_verify_message, encrypt_and_sign_message, MessageEnvelope};
+use crate::commands::prompt::{
+    confirm, load_identity, load_identity_with_passphrase, read_new_passphrase, read_passphrase,
+};
+use vaultsyn::contacts::{
+    check_pin, find_by_signing_key_id, key_change_warning, resolve_x25519, Contact, PinCheck,
+    PublicInfo,
+};
+use vaultsyn::crypto::keygen::generate_identity;
+use vaultsyn::crypto::suite::CipherSuite;
+use vaultsyn::crypto::x3dh::PrekeyStore;
+use vaultsyn::io::storage::{unlock_identity, validate_username, IdentityFile};
+use vaultsyn::message::{
+    decrypt_and_verify_message, encrypt_and_sign_message, EnvelopeMode, MessageEnvelope,
+};
+use vaultsyn::{Error, KeyStore, Result};
 
-pub fn create_user(username: &str) {
+/// The given username, or else the default identity.
+pub fn or_default(store: &dyn KeyStore, username: Option<String>) -> Result<String> {
+    let username = match username {
+        Some(username) => Some(username),
+        None => store.default_identity()?,
+    };
+    username.ok_or_else(|| {
+        Error::rejected(
+            "No identity given and no default identity set. Pass a username or run: vaultsyn default-user <username>",
+        )
+    })
+}
+
+fn exists(store: &dyn KeyStore, username: &str) -> Result<bool> {
+    Ok(store.list_identities()?.iter().any(|name| name == username))
+}
+
+pub fn create_user(store: &dyn KeyStore, username: &str, force: bool) -> Result<()> {
+    validate_username(username)?;
+    let replacing = exists(store, username)?;
+    if replacing && !force {
+        return Err(Error::rejected(format!(
+            "User '{}' already exists. Pass --force to replace it",
+            username
+        )));
+    }
+
+    // Nothing is deleted until the new identity is ready to be saved, so a
+    // cancelled passphrase prompt leaves the old one in place.
     let identity = generate_identity(username.to_string());
-    save_identity(&identity).expect("Failed to save identity");
+    let passphrase = read_new_passphrase()?;
+    if passphrase.is_none() {
+        println!("⚠️  No passphrase given, private keys will be stored unencrypted.");
+    }
+    let prekeys = PrekeyStore::generate(&identity)?;
+    // The new identity replaces the old file in one write, and only then is
+    // the old identity's state removed
+    store.save_identity(&identity, passphrase.as_deref())?;
+    if replacing {
+        println!(
+            "⚠️  Replaced '{}', its old keys and sessions are deleted.",
+            username
+        );
+        store.delete_identity_state(username)?;
+    }
+    store.save_prekeys(&identity, &prekeys)?;
     println!("✅ Identity for '{}' created.", username);
+    if store.default_identity()?.is_none() {
+        store.set_default_identity(Some(username))?;
+        println!("⭐ '{}' is now the default identity.", username);
+    }
+    Ok(())
 }
 
-pub fn export_public_key(username: &str) {
-    let identity: Identity = crate::io::storage::load_identity(username).expect("User not found");
+pub fn list_users(store: &dyn KeyStore) -> Result<()> {
+    let usernames = store.list_identities()?;
+    if usernames.is_empty() {
+        println!("📭 No identities yet. Create one with: vaultsyn create-user <username>");
+        return Ok(());
+    }
+    let default = store.default_identity()?;
+    println!("👤 Identities in {}:", store.location());
+    for username in usernames {
+        let marker = if default.as_deref() == Some(username.as_str()) {
+            "⭐"
+        } else {
+            "  "
+        };
+        let protection = match store.read_identity_file(&username) {
+            Ok(IdentityFile::Encrypted(_)) => "🔐 passphrase",
+            Ok(IdentityFile::Plain(_)) => "🔓 unencrypted",
+            Err(_) => "⚠️  unreadable",
+        };
+        println!("{} {:<24} {}", marker, username, protection);
+    }
+    Ok(())
+}
+
+pub fn delete_user(store: &dyn KeyStore, username: &str, yes: bool) -> Result<()> {
+    validate_username(username)?;
+    if !exists(store, username)? {
+        return Err(Error::not_found(format!("User '{}'", username)));
+    }
+    if !yes {
+        println!(
+            "⚠️  This permanently deletes '{}' with its prekeys and sessions. Messages sent to it can no longer be read.",
+            username
+        );
+        if !confirm("Type the username to confirm: ", username)? {
+            return Err(Error::rejected("Deletion cancelled"));
+        }
+    }
+
+    store.delete_identity(username)?;
+    if store.default_identity()?.as_deref() == Some(username) {
+        store.set_default_identity(None)?;
+    }
+    println!("🗑️  Identity '{}' deleted.", username);
+    Ok(())
+}
+
+pub fn rename_user(store: &dyn KeyStore, old: &str, new: &str) -> Result<()> {
+    validate_username(new)?;
+    if exists(store, new)? {
+        return Err(Error::rejected(format!("User '{}' already exists", new)));
+    }
+    let (mut identity, passphrase) = load_identity_with_passphrase(store, old)?;
+    store.rename_identity(&mut identity, new, passphrase.as_deref())?;
+    if store.default_identity()?.as_deref() == Some(old) {
+        store.set_default_identity(Some(new))?;
+    }
+    println!("✅ '{}' renamed to '{}'.", old, new);
+    println!(
+        "ℹ️  Keys and fingerprint are unchanged; contacts see the new name on your next message."
+    );
+    Ok(())
+}
+
+/// Shows the default identity, or sets it to `username`.
+pub fn default_user(store: &dyn KeyStore, username: Option<&str>) -> Result<()> {
+    match username {
+        Some(username) => {
+            validate_username(username)?;
+            if !exists(store, username)? {
+                return Err(Error::not_found(format!("User '{}'", username)));
+            }
+            store.set_default_identity(Some(username))?;
+            println!("⭐ '{}' is now the default identity.", username);
+        }
+        None => match store.default_identity()? {
+            Some(username) => println!("⭐ {}", username),
+            None => println!("ℹ️  No default identity set."),
+        },
+    }
+    Ok(())
+}
+
+pub fn change_passphrase(store: &dyn KeyStore, username: &str) -> Result<()> {
+    let identity = match store.read_identity_file(username)? {
+        IdentityFile::Plain(identity) => {
+            println!("🔓 '{}' is currently stored unencrypted.", username);
+            identity
+        }
+        encrypted => {
+            let current = read_passphrase(&format!("🔐 Current passphrase for '{}': ", username))?;
+            unlock_identity(encrypted, &current)?
+        }
+    };
+
+    let passphrase = read_new_passphrase()?;
+    store.save_identity(&identity, passphrase.as_deref())?;
+    match passphrase {
+        Some(_) => println!("✅ Passphrase for '{}' updated.", username),
+        None => println!(
+            "⚠️  Passphrase removed, '{}' is now stored unencrypted.",
+            username
+        ),
+    }
+    Ok(())
+}
+
+pub fn export_public_key(store: &dyn KeyStore, username: &str) -> Result<()> {
+    let identity = load_identity(store, username)?;
+    let fingerprint = identity.fingerprint();
     println!(
         "🔑 Public key for {}:\n{}",
         username,
         identity.public_info_json()
     );
+    println!(
+        "🔏 Fingerprint: {}\n   Words:       {}",
+        fingerprint.digits(),
+        fingerprint.words()
+    );
+    Ok(())
 }
 
-pub fn send_message(sender: &str, receiver_pub: &str, content: &str) {
-    let identity = load_identity(sender).expect("Sender not found");
-    let envelope = encrypt_and_sign_message(&identity, receiver_pub, content);
-    println!("{}", serde_json::to_string_pretty(&envelope).unwrap());
+pub fn send_message(
+    store: &dyn KeyStore,
+    sender: &str,
+    receiver: &str,
+    content: &str,
+    static_key: bool,
+    suite: CipherSuite,
+) -> Result<()> {
+    let identity = load_identity(store, sender)?;
+    let (receiver_pub, _) = resolve_x25519(store, receiver)?;
+    let mode = if static_key {
+        EnvelopeMode::Static
+    } else {
+        EnvelopeMode::Ephemeral
+    };
+    let envelope = encrypt_and_sign_message(&identity, &receiver_pub, content, mode, suite)?;
+    println!("{}", serde_json::to_string_pretty(&envelope)?);
+    Ok(())
 }
 
-pub fn receive_message(receiver_username: &str, sender_ed: &str, sender_x25519: &str, json: &str) {
-    let receiver =
-        crate::io::storage::load_identity(receiver_username).expect("Receiver not found");
+/// The sender is taken from `from`, from explicit keys, or else looked up in
+/// the contacts by the signing key id in the envelope.
+pub fn receive_message(
+    store: &dyn KeyStore,
+    receiver_username: &str,
+    json: &str,
+    from: Option<&str>,
+    sender_keys: Option<(String, String)>,
+    max_clock_skew: i64,
+) -> Result<()> {
+    let receiver = load_identity(store, receiver_username)?;
 
-    let envelope: MessageEnvelope = serde_json::from_str(json).expect("Invalid JSON message");
+    let envelope: MessageEnvelope = serde_json::from_str(json)?;
 
-    match decrypt_and_verify_message(&envelope, &receiver, sender_ed, sender_x25519) {
-        Ok(msg) => {
-            println!("✅ Verified message:\n{}", msg);
-        }
-        Err(e) => {
-            println!("{}", e);
+    let contact = match (from, sender_keys) {
+        (Some(name), _) => store
+            .load_contact(name)?
+            .ok_or_else(|| Error::not_found(format!("Contact '{}'", name)))?,
+        (None, Some((ed, x))) => match check_pin(store, &envelope.header.from, &ed, &x)? {
+            PinCheck::Match(contact) => contact,
+            PinCheck::Changed(contact) => {
+                println!("{}", key_change_warning(&contact, &ed, &x));
+                return Err(Error::rejected(
+                    "Message rejected: sender keys do not match the pinned contact",
+                ));
+            }
+            PinCheck::Unknown => {
+                println!(
+                    "ℹ️  '{}' is not in your contacts. Pin their keys with: vaultsyn contact add",
+                    envelope.header.from
+                );
+                Contact::new(
+                    &envelope.header.from,
+                    &PublicInfo {
+                        username: envelope.header.from.clone(),
+                        ed25519_public: ed,
+                        x25519_public: x,
+                    },
+                )
+            }
+        },
+        (None, None) => {
+            find_by_signing_key_id(store, &envelope.header.sender_key_id)?.ok_or_else(|| {
+                Error::rejected(
+                    "Sender is not in your contacts. Pass --from <contact> or the sender's keys.",
+                )
+            })?
         }
+    };
+    let sender_ed = contact.ed25519_public.as_str();
+
+    let mut replay = store.load_replay_cache(&receiver, sender_ed)?;
+    replay.max_skew = max_clock_skew;
+
+    let msg = decrypt_and_verify_message(
+        &envelope,
+        &receiver,
+        sender_ed,
+        &contact.x25519_public,
+        &mut replay,
+    )?;
+    if let Err(e) = store.save_replay_cache(&receiver, sender_ed, &replay) {
+        println!("⚠️  Failed to save replay cache: {}", e);
     }
+    println!("✅ Verified message from {}:\n{}", contact.name, msg);
+    Ok(())
 }
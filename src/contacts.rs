@@ -0,0 +1,169 @@
+//! Address book of peers' public keys. The keys stored for a name are pinned
+//! the first time they are imported; later sightings of the same name with
+//! different keys are refused until the user replaces the contact.
+
+use crate::crypto::b64::decode_key;
+use crate::crypto::fingerprint::Fingerprint;
+use crate::crypto::keygen::key_id;
+use crate::io::storage::{is_safe_name, KeyStore};
+use crate::{Error, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Contact {
+    pub name: String,
+    pub ed25519_public: String,
+    pub x25519_public: String,
+    /// When these keys were first pinned for this name.
+    pub pinned_at: DateTime<Utc>,
+    /// Set once the fingerprint was compared out of band with `verify`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub verified_at: Option<DateTime<Utc>>,
+}
+
+/// The JSON printed by `export-public-key`.
+#[derive(Deserialize)]
+pub struct PublicInfo {
+    pub username: String,
+    pub ed25519_public: String,
+    pub x25519_public: String,
+}
+
+/// How a sender's keys compare to what is pinned for the name it claims.
+pub enum PinCheck {
+    Match(Contact),
+    Changed(Contact),
+    Unknown,
+}
+
+impl Contact {
+    pub fn new(name: &str, info: &PublicInfo) -> Self {
+        Contact {
+            name: name.to_string(),
+            ed25519_public: info.ed25519_public.clone(),
+            x25519_public: info.x25519_public.clone(),
+            pinned_at: Utc::now(),
+            verified_at: None,
+        }
+    }
+
+    pub fn fingerprint(&self) -> Result<Fingerprint> {
+        Fingerprint::of(&self.ed25519_public, &self.x25519_public)
+    }
+
+    pub fn matches(&self, ed25519_public: &str, x25519_public: &str) -> bool {
+        self.ed25519_public == ed25519_public && self.x25519_public == x25519_public
+    }
+}
+
+/// Contact names become file names, so keep them to a safe character set.
+pub fn validate_name(name: &str) -> Result<()> {
+    if is_safe_name(name) {
+        Ok(())
+    } else {
+        Err(Error::rejected(format!(
+            "Invalid contact name '{}': use letters, digits, '-', '_', '.' or '@'",
+            name
+        )))
+    }
+}
+
+/// Parses `export-public-key` output. The "🔑 Public key for ..." line before
+/// the JSON and the fingerprint after it may be left in.
+pub fn parse_public_info(input: &str) -> Result<PublicInfo> {
+    let start = input
+        .find('{')
+        .ok_or_else(|| Error::not_found("Public key JSON"))?;
+    let info: PublicInfo = serde_json::Deserializer::from_str(&input[start..])
+        .into_iter()
+        .next()
+        .and_then(|info| info.ok())
+        .ok_or_else(|| Error::encoding("Invalid public key JSON"))?;
+    decode_key(&info.ed25519_public)?;
+    decode_key(&info.x25519_public)?;
+    Ok(info)
+}
+
+/// Compares a sender's keys with the contacts. A contact pinned to exactly
+/// these keys matches whatever name the sender claims; otherwise a contact
+/// with the claimed name, or sharing one of the keys, means the keys changed.
+pub fn check_pin(
+    store: &dyn KeyStore,
+    claimed_name: &str,
+    ed25519_public: &str,
+    x25519_public: &str,
+) -> Result<PinCheck> {
+    let contacts = store.list_contacts()?;
+    if let Some(contact) = contacts
+        .iter()
+        .find(|c| c.matches(ed25519_public, x25519_public))
+    {
+        return Ok(PinCheck::Match(contact.clone()));
+    }
+    Ok(
+        match contacts.into_iter().find(|c| {
+            c.name == claimed_name
+                || c.ed25519_public == ed25519_public
+                || c.x25519_public == x25519_public
+        }) {
+            Some(contact) => PinCheck::Changed(contact),
+            None => PinCheck::Unknown,
+        },
+    )
+}
+
+/// Looks a contact up by the `key_id` of its Ed25519 key.
+pub fn find_by_signing_key_id(store: &dyn KeyStore, id: &str) -> Result<Option<Contact>> {
+    Ok(store
+        .list_contacts()?
+        .into_iter()
+        .find(|contact| key_id(&contact.ed25519_public).is_ok_and(|key_id| key_id == id)))
+}
+
+/// Accepts either a contact name or a raw base64 X25519 key.
+pub fn resolve_x25519(
+    store: &dyn KeyStore,
+    name_or_key: &str,
+) -> Result<(String, Option<Contact>)> {
+    // Base64 keys are never valid contact names
+    let contact = match validate_name(name_or_key) {
+        Ok(()) => store.load_contact(name_or_key)?,
+        Err(_) => None,
+    };
+    Ok(match contact {
+        Some(contact) => (contact.x25519_public.clone(), Some(contact)),
+        None => (name_or_key.to_string(), None),
+    })
+}
+
+pub fn key_change_warning(contact: &Contact, ed25519_public: &str, x25519_public: &str) -> String {
+    // The sender's keys are not checked yet
+    let id = |key: &str| key_id(key).unwrap_or_else(|_| String::from("(malformed)"));
+    let mut warning = format!(
+        "🚨 WARNING: KEYS FOR CONTACT '{}' HAVE CHANGED!\n\
+         🚨 Someone may be impersonating them, or they created a new identity.\n\
+         🚨 Pinned since {}:",
+        contact.name,
+        contact.pinned_at.format("%Y-%m-%d %H:%M UTC")
+    );
+    if contact.ed25519_public != ed25519_public {
+        warning.push_str(&format!(
+            "\n🚨   ed25519 {} -> {}",
+            id(&contact.ed25519_public),
+            id(ed25519_public)
+        ));
+    }
+    if contact.x25519_public != x25519_public {
+        warning.push_str(&format!(
+            "\n🚨   x25519  {} -> {}",
+            id(&contact.x25519_public),
+            id(x25519_public)
+        ));
+    }
+    warning.push_str(&format!(
+        "\n🚨 Confirm the new keys out of band, then run: vaultsyn contact add <file> --name {} --replace",
+        contact.name
+    ));
+    warning
+}
@@ -0,0 +1,62 @@
+//! Serde helpers that keep raw key material in the same base64 form used by
+//! the rest of Vaultsyn's JSON files, and checked decoding of such values.
+
+use base64::{prelude::BASE64_STANDARD as standard, Engine};
+use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+/// Decodes a base64 value that must be exactly `N` bytes long. `what` names
+/// it in the error.
+pub fn decode_array<const N: usize>(encoded: &str, what: &'static str) -> crate::Result<[u8; N]> {
+    let bytes = standard
+        .decode(encoded)
+        .map_err(|_| crate::Error::encoding(format!("Invalid base64 {}", what)))?;
+    let actual = bytes.len();
+    <[u8; N]>::try_from(bytes).map_err(|_| crate::Error::KeyLength {
+        what,
+        expected: N,
+        actual,
+    })
+}
+
+/// Decodes a base64 Ed25519 or X25519 key.
+pub fn decode_key(encoded: &str) -> crate::Result<[u8; 32]> {
+    decode_array(encoded, "key")
+}
+
+pub fn serialize<S: Serializer>(bytes: &[u8; 32], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&standard.encode(bytes))
+}
+
+pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<[u8; 32], D::Error> {
+    let encoded = String::deserialize(deserializer)?;
+    let bytes = standard.decode(encoded).map_err(D::Error::custom)?;
+    <[u8; 32]>::try_from(bytes).map_err(|_| D::Error::custom("expected 32 bytes"))
+}
+
+pub mod option {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(
+        bytes: &Option<[u8; 32]>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match bytes {
+            Some(bytes) => super::serialize(bytes, serializer),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<[u8; 32]>, D::Error> {
+        match Option::<String>::deserialize(deserializer)? {
+            Some(encoded) => {
+                let bytes = standard.decode(encoded).map_err(D::Error::custom)?;
+                <[u8; 32]>::try_from(bytes)
+                    .map(Some)
+                    .map_err(|_| D::Error::custom("expected 32 bytes"))
+            }
+            None => Ok(None),
+        }
+    }
+}
@@ -0,0 +1,180 @@
+//! Human-comparable fingerprints of an identity's public keys, and safety
+//! numbers for a pair of identities, for verification over the phone or in
+//! person.
+
+use crate::crypto::keys::PublicKey;
+use bip39::Language;
+use sha2::{Digest, Sha512};
+
+const FINGERPRINT_VERSION: u16 = 0;
+/// Hash iterations, making it costly to grind keys with a similar fingerprint.
+const ITERATIONS: usize = 5200;
+/// Fingerprint bytes rendered as digits: 6 groups of 5 digits.
+const DIGIT_BYTES: usize = 30;
+/// Fingerprint bytes rendered as words: 8 words of 11 bits.
+const WORD_BYTES: usize = 11;
+
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Fingerprint([u8; DIGIT_BYTES]);
+
+/// Both parties' fingerprints in a fixed order, so each side sees the same number.
+pub struct SafetyNumber {
+    first: Fingerprint,
+    second: Fingerprint,
+}
+
+// Each 5-byte chunk becomes a 5-digit group.
+fn digit_groups(bytes: &[u8]) -> Vec<String> {
+    bytes
+        .chunks(5)
+        .map(|chunk| {
+            let value = chunk.iter().fold(0u64, |acc, b| (acc << 8) | *b as u64);
+            format!("{:05}", value % 100_000)
+        })
+        .collect()
+}
+
+fn words(bytes: &[u8]) -> Vec<&'static str> {
+    let list = Language::English.word_list();
+    let bits: Vec<bool> = bytes
+        .iter()
+        .flat_map(|byte| (0..8).rev().map(move |i| (byte >> i) & 1 == 1))
+        .collect();
+    bits.chunks_exact(11)
+        .map(|chunk| {
+            list[chunk
+                .iter()
+                .fold(0usize, |acc, bit| (acc << 1) | *bit as usize)]
+        })
+        .collect()
+}
+
+/// Lowercases and drops everything but letters and digits, so user input can
+/// be compared regardless of spacing.
+fn normalize(value: &str) -> String {
+    value
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect()
+}
+
+impl Fingerprint {
+    pub fn of(ed25519_public: &str, x25519_public: &str) -> crate::Result<Self> {
+        Ok(Self::of_keys(
+            &PublicKey::from_base64(ed25519_public)?,
+            &PublicKey::from_base64(x25519_public)?,
+        ))
+    }
+
+    pub fn of_keys(ed25519_public: &PublicKey, x25519_public: &PublicKey) -> Self {
+        let ed = ed25519_public.as_bytes();
+        let x = x25519_public.as_bytes();
+
+        let mut hash = Sha512::new()
+            .chain_update(FINGERPRINT_VERSION.to_be_bytes())
+            .chain_update(ed)
+            .chain_update(x)
+            .finalize();
+        for _ in 0..ITERATIONS {
+            hash = Sha512::new()
+                .chain_update(hash)
+                .chain_update(ed)
+                .chain_update(x)
+                .finalize();
+        }
+        let mut bytes = [0u8; DIGIT_BYTES];
+        bytes.copy_from_slice(&hash[..DIGIT_BYTES]);
+        Fingerprint(bytes)
+    }
+
+    pub fn digits(&self) -> String {
+        digit_groups(&self.0).join(" ")
+    }
+
+    pub fn words(&self) -> String {
+        words(&self.0[..WORD_BYTES]).join(" ")
+    }
+
+    /// True if `value` is this fingerprint, as digits or words.
+    pub fn matches(&self, value: &str) -> bool {
+        let value = normalize(value);
+        value == normalize(&self.digits()) || value == normalize(&self.words())
+    }
+}
+
+impl SafetyNumber {
+    pub fn new(a: &Fingerprint, b: &Fingerprint) -> Self {
+        let (first, second) = if a <= b { (a, b) } else { (b, a) };
+        SafetyNumber {
+            first: first.clone(),
+            second: second.clone(),
+        }
+    }
+
+    /// 12 groups of 5 digits, in rows of 4.
+    pub fn digits(&self) -> String {
+        let mut groups = digit_groups(&self.first.0);
+        groups.extend(digit_groups(&self.second.0));
+        groups
+            .chunks(4)
+            .map(|row| row.join(" "))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    pub fn words(&self) -> String {
+        format!("{} {}", self.first.words(), self.second.words())
+    }
+
+    pub fn matches(&self, value: &str) -> bool {
+        let value = normalize(value);
+        value == normalize(&self.digits()) || value == normalize(&self.words())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::keygen::generate_identity;
+
+    #[test]
+    fn fingerprints_are_stable_and_key_specific() {
+        let alice = generate_identity("alice".into());
+        let bob = generate_identity("bob".into());
+        let fingerprint = alice.fingerprint();
+        assert!(fingerprint == alice.fingerprint());
+        assert!(fingerprint != bob.fingerprint());
+        // Swapping the keys gives another fingerprint
+        assert!(fingerprint != Fingerprint::of_keys(&alice.x25519_public, &alice.ed25519_public));
+
+        let digits = fingerprint.digits();
+        assert_eq!(digits.split(' ').count(), 6);
+        assert!(digits.split(' ').all(|group| group.len() == 5));
+        assert_eq!(fingerprint.words().split(' ').count(), 8);
+    }
+
+    #[test]
+    fn fingerprints_match_digits_or_words_however_spaced() {
+        let fingerprint = generate_identity("alice".into()).fingerprint();
+        assert!(fingerprint.matches(&fingerprint.digits().replace(' ', "")));
+        assert!(fingerprint.matches(&fingerprint.words().to_uppercase()));
+        assert!(!fingerprint.matches(&generate_identity("bob".into()).fingerprint().digits()));
+        assert!(!fingerprint.matches(""));
+    }
+
+    #[test]
+    fn both_sides_see_the_same_safety_number() {
+        let alice = generate_identity("alice".into()).fingerprint();
+        let bob = generate_identity("bob".into()).fingerprint();
+        let ours = SafetyNumber::new(&alice, &bob);
+        let theirs = SafetyNumber::new(&bob, &alice);
+        assert_eq!(ours.digits(), theirs.digits());
+        assert_eq!(ours.words(), theirs.words());
+        assert_eq!(ours.digits().lines().count(), 3);
+        assert!(theirs.matches(&ours.words()));
+
+        let carol = generate_identity("carol".into()).fingerprint();
+        assert!(!ours.matches(&SafetyNumber::new(&alice, &carol).digits()));
+    }
+}
@@ -1,16 +1,20 @@
-use base64::{prelude::BASE64_STANDARD as base64Standard, Engine};
+use crate::crypto::fingerprint::Fingerprint;
+use crate::crypto::keys::{PublicKey, SecretKey};
+pub use crate::protocol::key_id;
 use ed25519_dalek::{SigningKey, VerifyingKey};
 use rand::rngs::OsRng;
 use serde::{Deserialize, Serialize};
 use x25519_dalek::{PublicKey as X25519Public, StaticSecret};
 
-#[derive(Clone, Serialize, Deserialize)]
+/// Not `Clone`: share it by reference or behind an `Arc` rather than
+/// copying the private keys around.
+#[derive(Debug, Serialize, Deserialize)]
 pub struct Identity {
     pub username: String,
-    pub ed25519_public: String,
-    pub ed25519_private: String,
-    pub x25519_public: String,
-    pub x25519_private: String,
+    pub ed25519_public: PublicKey,
+    pub ed25519_private: SecretKey,
+    pub x25519_public: PublicKey,
+    pub x25519_private: SecretKey,
 }
 
 impl Identity {
@@ -22,6 +26,18 @@ impl Identity {
         })
         .to_string()
     }
+
+    pub fn fingerprint(&self) -> Fingerprint {
+        Fingerprint::of_keys(&self.ed25519_public, &self.x25519_public)
+    }
+
+    pub fn signing_key(&self) -> SigningKey {
+        SigningKey::from_bytes(self.ed25519_private.expose())
+    }
+
+    pub fn x25519_secret(&self) -> StaticSecret {
+        StaticSecret::from(*self.x25519_private.expose())
+    }
 }
 
 pub fn generate_identity(username: String) -> Identity {
@@ -32,14 +48,14 @@ pub fn generate_identity(username: String) -> Identity {
     let verifying_key = VerifyingKey::from(&signing_key);
 
     // ✅ X25519 Keypair
-    let x_secret = StaticSecret::random_from_rng(&mut csprng);
+    let x_secret = StaticSecret::random_from_rng(csprng);
     let x_public = X25519Public::from(&x_secret);
 
     Identity {
         username,
-        ed25519_public: base64Standard.encode(verifying_key.as_bytes()),
-        ed25519_private: base64Standard.encode(signing_key.to_bytes()),
-        x25519_public: base64Standard.encode(x_public.as_bytes()),
-        x25519_private: base64Standard.encode(x_secret.to_bytes()),
+        ed25519_public: PublicKey::from_bytes(verifying_key.to_bytes()),
+        ed25519_private: SecretKey::from_bytes(signing_key.to_bytes()),
+        x25519_public: PublicKey::from_bytes(x_public.to_bytes()),
+        x25519_private: SecretKey::from_bytes(x_secret.to_bytes()),
     }
 }
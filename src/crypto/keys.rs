@@ -0,0 +1,148 @@
+//! Typed 32-byte keys. Their length is checked once when they are decoded,
+//! and they serialize to the same base64 strings as before, so existing
+//! identity files keep working.
+//!
+//! Secret keys live in their own heap buffer, which is locked into RAM on
+//! Linux where the memlock limit allows, and wiped when dropped. Their
+//! `Debug` output never shows the key.
+
+use crate::crypto::b64::decode_array;
+use crate::Result;
+use base64::{prelude::BASE64_STANDARD as standard, Engine};
+use serde::{de::Error as _, Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use zeroize::{Zeroize, Zeroizing};
+
+pub const KEY_LEN: usize = 32;
+
+/// An Ed25519 or X25519 public key.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct PublicKey([u8; KEY_LEN]);
+
+/// An Ed25519 or X25519 private key, or other secret key material.
+pub struct SecretKey(Box<[u8; KEY_LEN]>);
+
+impl PublicKey {
+    pub fn from_bytes(bytes: [u8; KEY_LEN]) -> Self {
+        PublicKey(bytes)
+    }
+
+    pub fn from_base64(encoded: &str) -> Result<Self> {
+        decode_array(encoded, "key").map(PublicKey)
+    }
+
+    pub fn as_bytes(&self) -> &[u8; KEY_LEN] {
+        &self.0
+    }
+
+    pub fn to_base64(&self) -> String {
+        standard.encode(self.0)
+    }
+
+    /// See `protocol::key_id`.
+    pub fn key_id(&self) -> String {
+        crate::protocol::key_id_of(&self.0)
+    }
+}
+
+impl fmt::Display for PublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.to_base64())
+    }
+}
+
+impl fmt::Debug for PublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "PublicKey({})", self.to_base64())
+    }
+}
+
+impl Serialize for PublicKey {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_base64())
+    }
+}
+
+impl<'de> Deserialize<'de> for PublicKey {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        let encoded = String::deserialize(deserializer)?;
+        PublicKey::from_base64(&encoded).map_err(D::Error::custom)
+    }
+}
+
+impl SecretKey {
+    /// Takes ownership of `bytes` and wipes that copy. Copies the caller
+    /// made beforehand are the caller's to wipe.
+    pub fn from_bytes(mut bytes: [u8; KEY_LEN]) -> Self {
+        let mut key = Box::new([0u8; KEY_LEN]);
+        lock(&key);
+        key.copy_from_slice(&bytes);
+        bytes.zeroize();
+        SecretKey(key)
+    }
+
+    pub fn from_base64(encoded: &str) -> Result<Self> {
+        let bytes = Zeroizing::new(decode_array::<KEY_LEN>(encoded, "key")?);
+        Ok(SecretKey::from_bytes(*bytes))
+    }
+
+    /// The raw key. Keep what is derived from it as short-lived as possible.
+    pub fn expose(&self) -> &[u8; KEY_LEN] {
+        &self.0
+    }
+}
+
+/// A second locked buffer holding the same key.
+impl Clone for SecretKey {
+    fn clone(&self) -> Self {
+        SecretKey::from_bytes(*self.0)
+    }
+}
+
+impl Drop for SecretKey {
+    fn drop(&mut self) {
+        self.0.zeroize();
+        unlock(&self.0);
+    }
+}
+
+impl fmt::Debug for SecretKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SecretKey(<redacted>)")
+    }
+}
+
+impl Serialize for SecretKey {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.serialize_str(&Zeroizing::new(standard.encode(*self.0)))
+    }
+}
+
+impl<'de> Deserialize<'de> for SecretKey {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        let encoded = Zeroizing::new(String::deserialize(deserializer)?);
+        SecretKey::from_base64(&encoded).map_err(D::Error::custom)
+    }
+}
+
+// Keeps the buffer out of swap. Failing is fine: the default memlock limit
+// is small, and the key is still wiped on drop.
+#[cfg(target_os = "linux")]
+fn lock(buffer: &[u8; KEY_LEN]) {
+    unsafe {
+        libc::mlock(buffer.as_ptr().cast(), KEY_LEN);
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn unlock(buffer: &[u8; KEY_LEN]) {
+    unsafe {
+        libc::munlock(buffer.as_ptr().cast(), KEY_LEN);
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn lock(_buffer: &[u8; KEY_LEN]) {}
+
+#[cfg(not(target_os = "linux"))]
+fn unlock(_buffer: &[u8; KEY_LEN]) {}
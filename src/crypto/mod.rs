@@ -1 +1,8 @@
+pub mod b64;
+pub mod fingerprint;
 pub mod keygen;
+pub mod keys;
+pub mod ratchet;
+pub mod suite;
+pub mod vault;
+pub mod x3dh;
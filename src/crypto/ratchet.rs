@@ -0,0 +1,541 @@
+//! Double Ratchet sessions (Signal-style) between two Vaultsyn identities.
+//!
+//! Every message gets its own key from a symmetric chain, and every change of
+//! speaker mixes a fresh X25519 exchange into the root key, so a leaked
+//! session state neither exposes earlier messages nor stays useful after the
+//! next round trip. The AEAD itself is applied by `message`, which binds the
+//! envelope header to each ciphertext.
+
+use crate::crypto::b64::{self, decode_key};
+use crate::crypto::keygen::Identity;
+use crate::crypto::suite::CipherSuite;
+use crate::crypto::x3dh::{self, X3dhHeader};
+use crate::protocol::PrekeyBundle;
+use crate::{Error, Result};
+use aes_gcm::aead::OsRng;
+use base64::{prelude::BASE64_STANDARD as standard, Engine};
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use x25519_dalek::{PublicKey as X25519Public, StaticSecret};
+use zeroize::Zeroize;
+
+/// Most message keys we derive ahead for a single gap in the chain.
+const MAX_SKIP: u32 = 1000;
+/// Upper bound on stored keys for messages that never arrived.
+const MAX_SKIPPED_STORED: usize = 2000;
+/// Superseded sessions kept around for messages still in flight.
+const MAX_PREVIOUS_SESSIONS: usize = 4;
+
+/// Decrypts one message with a candidate message key.
+pub type OpenFn<'a> = dyn Fn(&[u8; 32]) -> Result<Vec<u8>> + 'a;
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct RatchetHeader {
+    /// Sender's current ratchet public key.
+    pub dh: String,
+    /// Length of the sender's previous sending chain.
+    pub pn: u32,
+    /// Index of this message in the current sending chain.
+    pub n: u32,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SkippedKey {
+    #[serde(with = "b64")]
+    dh: [u8; 32],
+    n: u32,
+    #[serde(with = "b64")]
+    key: [u8; 32],
+}
+
+/// Session keys are wiped when the session is dropped. It is not `Clone`, so
+/// no unmanaged copies of them exist.
+#[derive(Serialize, Deserialize)]
+pub struct Session {
+    pub local_x25519_public: String,
+    pub peer_x25519_public: String,
+    #[serde(with = "b64")]
+    root_key: [u8; 32],
+    #[serde(with = "b64")]
+    dh_self: [u8; 32],
+    #[serde(with = "b64::option")]
+    dh_remote: Option<[u8; 32]>,
+    #[serde(with = "b64::option")]
+    chain_send: Option<[u8; 32]>,
+    #[serde(with = "b64::option")]
+    chain_recv: Option<[u8; 32]>,
+    send_count: u32,
+    recv_count: u32,
+    prev_send_count: u32,
+    skipped: Vec<SkippedKey>,
+    /// Attached to outgoing messages until the peer first replies.
+    #[serde(default)]
+    pub pending_x3dh: Option<X3dhHeader>,
+    /// Initiator's X3DH ephemeral key, identifying the handshake that created us.
+    #[serde(default)]
+    pub base_key: Option<String>,
+    /// Suite our outgoing messages are sealed with.
+    #[serde(default = "CipherSuite::preferred")]
+    pub suite: CipherSuite,
+}
+
+/// All sessions with one peer. Both sides may start an X3DH handshake at the
+/// same time; keeping the superseded sessions lets either one win while
+/// messages in flight on the other still decrypt.
+#[derive(Default, Serialize, Deserialize)]
+pub struct SessionRecord {
+    pub current: Option<Session>,
+    #[serde(default)]
+    pub previous: Vec<Session>,
+}
+
+impl Drop for SkippedKey {
+    fn drop(&mut self) {
+        self.key.zeroize();
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        self.root_key.zeroize();
+        self.dh_self.zeroize();
+        self.chain_send.zeroize();
+        self.chain_recv.zeroize();
+    }
+}
+
+fn dh(secret: &[u8; 32], public: &[u8; 32]) -> [u8; 32] {
+    StaticSecret::from(*secret)
+        .diffie_hellman(&X25519Public::from(*public))
+        .to_bytes()
+}
+
+fn public_of(secret: &[u8; 32]) -> [u8; 32] {
+    X25519Public::from(&StaticSecret::from(*secret)).to_bytes()
+}
+
+fn hkdf_32(salt: Option<&[u8]>, ikm: &[u8], info: &[u8]) -> [u8; 32] {
+    let mut out = [0u8; 32];
+    Hkdf::<Sha256>::new(salt, ikm)
+        .expand(info, &mut out)
+        .expect("32 bytes is a valid HKDF output length");
+    out
+}
+
+fn kdf_root(root_key: &[u8; 32], dh_out: &[u8; 32]) -> ([u8; 32], [u8; 32]) {
+    let mut out = [0u8; 64];
+    Hkdf::<Sha256>::new(Some(root_key), dh_out)
+        .expand(b"vaultsyn-ratchet-root", &mut out)
+        .expect("64 bytes is a valid HKDF output length");
+    let mut root = [0u8; 32];
+    let mut chain = [0u8; 32];
+    root.copy_from_slice(&out[..32]);
+    chain.copy_from_slice(&out[32..]);
+    (root, chain)
+}
+
+/// Returns (next chain key, message key).
+fn kdf_chain(chain_key: &[u8; 32]) -> ([u8; 32], [u8; 32]) {
+    let step = |byte: u8| -> [u8; 32] {
+        let mut mac =
+            <Hmac<Sha256> as Mac>::new_from_slice(chain_key).expect("HMAC accepts any key length");
+        mac.update(&[byte]);
+        mac.finalize().into_bytes().into()
+    };
+    (step(0x02), step(0x01))
+}
+
+impl Session {
+    /// Bootstraps a session from both parties' static X25519 keys, for a peer
+    /// that has published no prekeys. Prefer [`Session::from_bundle`].
+    ///
+    /// Both sides derive the same secret independently; the side with the lower
+    /// public key takes the initiator role. The responder can still speak first
+    /// on a chain derived from the shared secret, until the first reply ratchets
+    /// it away.
+    ///
+    /// That chain comes from the static-static exchange alone, so it is not
+    /// forward secret: whoever later learns either static private key can read
+    /// every message the responder sent on it. The initiator's first messages
+    /// only fall to the responder's static key, since they mix in a fresh
+    /// ratchet key.
+    pub fn from_static_keys(identity: &Identity, peer_x25519_public: &str) -> Result<Self> {
+        let own_secret = *identity.x25519_private.expose();
+        let own_public = *identity.x25519_public.as_bytes();
+        let peer_public = decode_key(peer_x25519_public)?;
+
+        let (low, high) = if own_public < peer_public {
+            (own_public, peer_public)
+        } else {
+            (peer_public, own_public)
+        };
+        let mut info = b"vaultsyn-ratchet-static".to_vec();
+        info.extend_from_slice(&low);
+        info.extend_from_slice(&high);
+        let shared = hkdf_32(None, &dh(&own_secret, &peer_public), &info);
+        let responder_chain = hkdf_32(None, &shared, b"vaultsyn-ratchet-responder-chain");
+
+        if own_public < peer_public {
+            let mut session = Session::initiate(identity, peer_x25519_public, shared, peer_public)?;
+            session.chain_recv = Some(responder_chain);
+            Ok(session)
+        } else {
+            let mut session = Session::respond(identity, peer_x25519_public, shared, own_secret)?;
+            session.chain_send = Some(responder_chain);
+            Ok(session)
+        }
+    }
+
+    /// Starts a session with a possibly offline peer by running X3DH against
+    /// its published prekey bundle.
+    pub fn from_bundle(identity: &Identity, bundle: &PrekeyBundle) -> Result<Self> {
+        let init = x3dh::initiate(identity, bundle)?;
+        let mut session = Session::initiate(
+            identity,
+            &bundle.x25519_public,
+            init.shared_secret,
+            init.remote_signed_prekey,
+        )?;
+        session.base_key = Some(init.header.ephemeral.clone());
+        session.pending_x3dh = Some(init.header);
+        Ok(session)
+    }
+
+    /// Initiator side: the first sending chain comes from a fresh ratchet key
+    /// against the peer's ratchet public key.
+    pub fn initiate(
+        identity: &Identity,
+        peer_x25519_public: &str,
+        shared_secret: [u8; 32],
+        remote_ratchet_public: [u8; 32],
+    ) -> Result<Self> {
+        let ratchet_secret = StaticSecret::random_from_rng(OsRng).to_bytes();
+        let mut session =
+            Session::respond(identity, peer_x25519_public, shared_secret, ratchet_secret)?;
+        let (root, chain) = kdf_root(&shared_secret, &dh(&ratchet_secret, &remote_ratchet_public));
+        session.root_key = root;
+        session.dh_remote = Some(remote_ratchet_public);
+        session.chain_send = Some(chain);
+        Ok(session)
+    }
+
+    /// Responder side: waits for the initiator's first message, whose ratchet
+    /// key is combined with `ratchet_secret`.
+    pub fn respond(
+        identity: &Identity,
+        peer_x25519_public: &str,
+        shared_secret: [u8; 32],
+        ratchet_secret: [u8; 32],
+    ) -> Result<Self> {
+        Ok(Session {
+            local_x25519_public: identity.x25519_public.to_base64(),
+            peer_x25519_public: standard.encode(decode_key(peer_x25519_public)?),
+            root_key: shared_secret,
+            dh_self: ratchet_secret,
+            dh_remote: None,
+            chain_send: None,
+            chain_recv: None,
+            send_count: 0,
+            recv_count: 0,
+            prev_send_count: 0,
+            skipped: Vec::new(),
+            pending_x3dh: None,
+            base_key: None,
+            suite: CipherSuite::preferred(),
+        })
+    }
+
+    /// Advances the sending chain, returning the header and key for one message.
+    pub fn next_message_key(&mut self) -> Result<(RatchetHeader, [u8; 32])> {
+        let chain = self
+            .chain_send
+            .ok_or_else(|| Error::rejected("Session has no sending chain"))?;
+        let (next_chain, message_key) = kdf_chain(&chain);
+        self.chain_send = Some(next_chain);
+
+        let header = RatchetHeader {
+            dh: standard.encode(public_of(&self.dh_self)),
+            pn: self.prev_send_count,
+            n: self.send_count,
+        };
+        self.send_count += 1;
+        Ok((header, message_key))
+    }
+
+    /// Finds the key for `header` and decrypts with `open`. The session is left
+    /// untouched if anything fails, so forged or corrupted messages cannot
+    /// desynchronise it.
+    pub fn decrypt(&mut self, header: &RatchetHeader, open: &OpenFn) -> Result<Vec<u8>> {
+        let mut next = self.fork();
+        let message_key = next.message_key_for(header)?;
+        let plaintext = open(&message_key)?;
+        next.pending_x3dh = None;
+        *self = next;
+        Ok(plaintext)
+    }
+
+    // A working copy for `decrypt`, wiped on drop like the original
+    fn fork(&self) -> Session {
+        Session {
+            local_x25519_public: self.local_x25519_public.clone(),
+            peer_x25519_public: self.peer_x25519_public.clone(),
+            root_key: self.root_key,
+            dh_self: self.dh_self,
+            dh_remote: self.dh_remote,
+            chain_send: self.chain_send,
+            chain_recv: self.chain_recv,
+            send_count: self.send_count,
+            recv_count: self.recv_count,
+            prev_send_count: self.prev_send_count,
+            skipped: self
+                .skipped
+                .iter()
+                .map(|skipped| SkippedKey {
+                    dh: skipped.dh,
+                    n: skipped.n,
+                    key: skipped.key,
+                })
+                .collect(),
+            pending_x3dh: self.pending_x3dh.clone(),
+            base_key: self.base_key.clone(),
+            suite: self.suite,
+        }
+    }
+
+    fn message_key_for(&mut self, header: &RatchetHeader) -> Result<[u8; 32]> {
+        let header_dh = decode_key(&header.dh)?;
+
+        // A message from an earlier gap in the chain
+        if let Some(pos) = self
+            .skipped
+            .iter()
+            .position(|s| s.dh == header_dh && s.n == header.n)
+        {
+            return Ok(self.skipped.remove(pos).key);
+        }
+
+        if self.dh_remote != Some(header_dh) {
+            self.skip_message_keys(header.pn)?;
+            self.dh_ratchet(header_dh);
+        }
+        self.skip_message_keys(header.n)?;
+
+        let chain = self
+            .chain_recv
+            .ok_or_else(|| Error::rejected("No receiving chain for this message"))?;
+        let (next_chain, message_key) = kdf_chain(&chain);
+        self.chain_recv = Some(next_chain);
+        self.recv_count += 1;
+        Ok(message_key)
+    }
+
+    fn skip_message_keys(&mut self, until: u32) -> Result<()> {
+        let (Some(mut chain), Some(dh_remote)) = (self.chain_recv, self.dh_remote) else {
+            return Ok(());
+        };
+        if until > self.recv_count.saturating_add(MAX_SKIP) {
+            return Err(Error::rejected("Too many skipped messages"));
+        }
+        while self.recv_count < until {
+            let (next_chain, message_key) = kdf_chain(&chain);
+            self.skipped.push(SkippedKey {
+                dh: dh_remote,
+                n: self.recv_count,
+                key: message_key,
+            });
+            chain = next_chain;
+            self.recv_count += 1;
+        }
+        self.chain_recv = Some(chain);
+
+        if self.skipped.len() > MAX_SKIPPED_STORED {
+            let excess = self.skipped.len() - MAX_SKIPPED_STORED;
+            self.skipped.drain(..excess);
+        }
+        Ok(())
+    }
+
+    fn dh_ratchet(&mut self, header_dh: [u8; 32]) {
+        self.prev_send_count = self.send_count;
+        self.send_count = 0;
+        self.recv_count = 0;
+        self.dh_remote = Some(header_dh);
+
+        let (root, chain) = kdf_root(&self.root_key, &dh(&self.dh_self, &header_dh));
+        self.root_key = root;
+        self.chain_recv = Some(chain);
+
+        self.dh_self = StaticSecret::random_from_rng(OsRng).to_bytes();
+        let (root, chain) = kdf_root(&self.root_key, &dh(&self.dh_self, &header_dh));
+        self.root_key = root;
+        self.chain_send = Some(chain);
+    }
+}
+
+impl SessionRecord {
+    pub fn has_base_key(&self, base_key: &str) -> bool {
+        self.current
+            .iter()
+            .chain(self.previous.iter())
+            .any(|s| s.base_key.as_deref() == Some(base_key))
+    }
+
+    /// Makes `session` current, keeping the one it replaces for stragglers.
+    pub fn promote(&mut self, session: Session) {
+        if let Some(current) = self.current.replace(session) {
+            self.previous.insert(0, current);
+            self.previous.truncate(MAX_PREVIOUS_SESSIONS);
+        }
+    }
+
+    /// Tries the current session first, then older ones. An older session that
+    /// decrypts becomes current again, so both sides converge on it.
+    pub fn decrypt(&mut self, header: &RatchetHeader, open: &OpenFn) -> Result<Vec<u8>> {
+        let mut last_error = Error::not_found("Session with this peer");
+        if let Some(current) = self.current.as_mut() {
+            match current.decrypt(header, open) {
+                Ok(plaintext) => return Ok(plaintext),
+                Err(e) => last_error = e,
+            }
+        }
+        for i in 0..self.previous.len() {
+            if let Ok(plaintext) = self.previous[i].decrypt(header, open) {
+                let session = self.previous.remove(i);
+                self.promote(session);
+                return Ok(plaintext);
+            }
+        }
+        Err(last_error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::keygen::generate_identity;
+
+    // The AEAD lives in `message`; here a "ciphertext" is the key it was
+    // sealed under, so opening succeeds exactly when the keys match.
+    struct Sent {
+        header: RatchetHeader,
+        key: [u8; 32],
+        text: &'static str,
+    }
+
+    fn send(session: &mut Session, text: &'static str) -> Sent {
+        let (header, key) = session.next_message_key().unwrap();
+        Sent { header, key, text }
+    }
+
+    fn opener(sent: &Sent) -> impl Fn(&[u8; 32]) -> Result<Vec<u8>> + '_ {
+        |key| {
+            if *key == sent.key {
+                Ok(sent.text.as_bytes().to_vec())
+            } else {
+                Err(Error::auth("Wrong message key"))
+            }
+        }
+    }
+
+    fn receive(session: &mut Session, sent: &Sent) -> Result<String> {
+        session
+            .decrypt(&sent.header, &opener(sent))
+            .map(|plaintext| String::from_utf8(plaintext).unwrap())
+    }
+
+    fn pair() -> (Session, Session) {
+        let alice = generate_identity("alice".into());
+        let bob = generate_identity("bob".into());
+        (
+            Session::from_static_keys(&alice, &bob.x25519_public.to_base64()).unwrap(),
+            Session::from_static_keys(&bob, &alice.x25519_public.to_base64()).unwrap(),
+        )
+    }
+
+    #[test]
+    fn either_side_can_speak_first_and_turns_ratchet() {
+        let (mut alice, mut bob) = pair();
+        for _ in 0..3 {
+            let sent = send(&mut alice, "ping");
+            assert_eq!(receive(&mut bob, &sent).unwrap(), "ping");
+            let sent = send(&mut bob, "pong");
+            assert_eq!(receive(&mut alice, &sent).unwrap(), "pong");
+        }
+        let (mut alice, mut bob) = pair();
+        let sent = send(&mut bob, "first");
+        assert_eq!(receive(&mut alice, &sent).unwrap(), "first");
+    }
+
+    #[test]
+    fn out_of_order_messages_decrypt_once() {
+        let (mut alice, mut bob) = pair();
+        let sent: Vec<Sent> = ["zero", "one", "two"]
+            .into_iter()
+            .map(|text| send(&mut alice, text))
+            .collect();
+        assert_eq!(receive(&mut bob, &sent[2]).unwrap(), "two");
+        assert_eq!(receive(&mut bob, &sent[0]).unwrap(), "zero");
+        assert_eq!(receive(&mut bob, &sent[1]).unwrap(), "one");
+        // Skipped keys are used up, and the chain does not go back
+        assert!(receive(&mut bob, &sent[0]).is_err());
+        assert!(receive(&mut bob, &sent[2]).is_err());
+    }
+
+    #[test]
+    fn skipped_keys_survive_a_ratchet_step() {
+        let (mut alice, mut bob) = pair();
+        let first = send(&mut alice, "first");
+        let late = send(&mut alice, "late");
+        receive(&mut bob, &first).unwrap();
+
+        let reply = send(&mut bob, "reply");
+        receive(&mut alice, &reply).unwrap();
+        let next = send(&mut alice, "next");
+        assert_eq!(next.header.pn, 2);
+        assert_eq!(receive(&mut bob, &next).unwrap(), "next");
+        assert_eq!(receive(&mut bob, &late).unwrap(), "late");
+    }
+
+    #[test]
+    fn failed_decryption_leaves_the_session_untouched() {
+        let (mut alice, mut bob) = pair();
+        let sent = send(&mut alice, "hello");
+        let forged = Sent {
+            header: sent.header.clone(),
+            key: [0; 32],
+            text: "forged",
+        };
+        assert!(receive(&mut bob, &forged).is_err());
+        assert_eq!(receive(&mut bob, &sent).unwrap(), "hello");
+    }
+
+    #[test]
+    fn rejects_gaps_beyond_max_skip() {
+        let (mut alice, mut bob) = pair();
+        let mut sent = send(&mut alice, "far ahead");
+        sent.header.n = MAX_SKIP + 1;
+        assert!(receive(&mut bob, &sent).is_err());
+    }
+
+    #[test]
+    fn record_falls_back_to_a_previous_session() {
+        let (mut alice, bob) = pair();
+        let sent = send(&mut alice, "in flight");
+
+        let mut record = SessionRecord::default();
+        record.promote(bob.fork());
+        record.promote(pair().1);
+        assert_eq!(
+            record.decrypt(&sent.header, &opener(&sent)).unwrap(),
+            b"in flight"
+        );
+        // The session that worked is current again
+        assert_eq!(
+            record.current.as_ref().unwrap().peer_x25519_public,
+            bob.peer_x25519_public
+        );
+        assert_eq!(record.previous.len(), 1);
+    }
+}
@@ -0,0 +1,230 @@
+//! Cipher suites for message envelopes: how the AEAD key is derived from an
+//! X25519 shared secret and which AEAD seals the message.
+
+use crate::{Error, Result};
+use aes_gcm::aead::{Aead, KeyInit, Payload};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use chacha20poly1305::ChaCha20Poly1305;
+use hkdf::Hkdf;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CipherSuite {
+    /// Envelopes from before suites existed: AES-256-GCM keyed with a bare
+    /// SHA-256 of the shared secret. Only kept for decryption.
+    #[default]
+    #[serde(rename = "sha256-aes256-gcm")]
+    Legacy,
+    #[serde(rename = "hkdf-sha256-aes256-gcm")]
+    Aes256Gcm,
+    #[serde(rename = "hkdf-sha256-chacha20-poly1305")]
+    ChaCha20Poly1305,
+}
+
+impl CipherSuite {
+    /// Suites this client can send with, most preferred first.
+    pub const SUPPORTED: [CipherSuite; 2] = [CipherSuite::Aes256Gcm, CipherSuite::ChaCha20Poly1305];
+
+    /// Suite used when nothing is known about the peer.
+    pub fn preferred() -> Self {
+        CipherSuite::Aes256Gcm
+    }
+
+    pub fn is_legacy(&self) -> bool {
+        *self == CipherSuite::Legacy
+    }
+
+    pub fn id(&self) -> &'static str {
+        match self {
+            CipherSuite::Legacy => "sha256-aes256-gcm",
+            CipherSuite::Aes256Gcm => "hkdf-sha256-aes256-gcm",
+            CipherSuite::ChaCha20Poly1305 => "hkdf-sha256-chacha20-poly1305",
+        }
+    }
+
+    /// Picks our most preferred suite that the peer also advertises. Peers
+    /// that advertise nothing predate suites and only understand the legacy one.
+    pub fn negotiate(preferred: CipherSuite, peer_suites: &[String]) -> Self {
+        if peer_suites.is_empty() {
+            return CipherSuite::Legacy;
+        }
+        let offered = |suite: &CipherSuite| peer_suites.iter().any(|id| id == suite.id());
+        if offered(&preferred) {
+            return preferred;
+        }
+        CipherSuite::SUPPORTED
+            .into_iter()
+            .find(offered)
+            .unwrap_or(CipherSuite::Legacy)
+    }
+
+    /// Derives the envelope key from an X25519 shared secret. Both public keys
+    /// of the exchange and the suite id are bound in, so the key is specific
+    /// to this pair of keys and this suite.
+    pub fn envelope_key(
+        &self,
+        shared_secret: &[u8; 32],
+        sender_public: &[u8; 32],
+        receiver_public: &[u8; 32],
+    ) -> [u8; 32] {
+        if self.is_legacy() {
+            return Sha256::digest(shared_secret).into();
+        }
+        let mut info = b"vaultsyn-envelope-key".to_vec();
+        info.push(0);
+        info.extend_from_slice(self.id().as_bytes());
+        info.push(0);
+        info.extend_from_slice(sender_public);
+        info.extend_from_slice(receiver_public);
+
+        let mut key = [0u8; 32];
+        Hkdf::<Sha256>::new(None, shared_secret)
+            .expand(&info, &mut key)
+            .expect("32 bytes is a valid HKDF output length");
+        key
+    }
+
+    pub fn seal(
+        &self,
+        key: &[u8; 32],
+        nonce: &[u8; 12],
+        plaintext: &[u8],
+        aad: &[u8],
+    ) -> Result<Vec<u8>> {
+        let payload = Payload {
+            msg: plaintext,
+            aad,
+        };
+        match self {
+            CipherSuite::Legacy | CipherSuite::Aes256Gcm => {
+                Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key))
+                    .encrypt(Nonce::from_slice(nonce), payload)
+            }
+            CipherSuite::ChaCha20Poly1305 => ChaCha20Poly1305::new(key.into())
+                .encrypt(chacha20poly1305::Nonce::from_slice(nonce), payload),
+        }
+        .map_err(|_| Error::rejected("Encryption failed"))
+    }
+
+    pub fn open(
+        &self,
+        key: &[u8; 32],
+        nonce: &[u8; 12],
+        ciphertext: &[u8],
+        aad: &[u8],
+    ) -> Result<Vec<u8>> {
+        let payload = Payload {
+            msg: ciphertext,
+            aad,
+        };
+        match self {
+            CipherSuite::Legacy | CipherSuite::Aes256Gcm => {
+                Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key))
+                    .decrypt(Nonce::from_slice(nonce), payload)
+            }
+            CipherSuite::ChaCha20Poly1305 => ChaCha20Poly1305::new(key.into())
+                .decrypt(chacha20poly1305::Nonce::from_slice(nonce), payload),
+        }
+        .map_err(|_| Error::auth("Decryption failed"))
+    }
+}
+
+impl fmt::Display for CipherSuite {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.id())
+    }
+}
+
+/// Accepts full suite ids as well as the bare AEAD names.
+impl FromStr for CipherSuite {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "hkdf-sha256-aes256-gcm" | "aes256-gcm" => Ok(CipherSuite::Aes256Gcm),
+            "hkdf-sha256-chacha20-poly1305" | "chacha20-poly1305" => {
+                Ok(CipherSuite::ChaCha20Poly1305)
+            }
+            _ => Err(format!(
+                "unknown cipher suite '{}', expected aes256-gcm or chacha20-poly1305",
+                s
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ids(suites: &[CipherSuite]) -> Vec<String> {
+        suites.iter().map(|suite| suite.id().to_string()).collect()
+    }
+
+    #[test]
+    fn negotiates_the_preferred_suite_when_offered() {
+        let both = ids(&CipherSuite::SUPPORTED);
+        for preferred in CipherSuite::SUPPORTED {
+            assert_eq!(CipherSuite::negotiate(preferred, &both), preferred);
+        }
+        let chacha_only = ids(&[CipherSuite::ChaCha20Poly1305]);
+        assert_eq!(
+            CipherSuite::negotiate(CipherSuite::Aes256Gcm, &chacha_only),
+            CipherSuite::ChaCha20Poly1305
+        );
+    }
+
+    #[test]
+    fn falls_back_to_legacy_for_old_or_unknown_peers() {
+        assert_eq!(
+            CipherSuite::negotiate(CipherSuite::Aes256Gcm, &[]),
+            CipherSuite::Legacy
+        );
+        assert_eq!(
+            CipherSuite::negotiate(CipherSuite::Aes256Gcm, &["rot13".to_string()]),
+            CipherSuite::Legacy
+        );
+    }
+
+    #[test]
+    fn every_suite_round_trips_and_binds_the_associated_data() {
+        let nonce = [7; 12];
+        for suite in [
+            CipherSuite::Legacy,
+            CipherSuite::Aes256Gcm,
+            CipherSuite::ChaCha20Poly1305,
+        ] {
+            let key = suite.envelope_key(&[1; 32], &[2; 32], &[3; 32]);
+            let sealed = suite.seal(&key, &nonce, b"hello", b"header").unwrap();
+            assert_eq!(
+                suite.open(&key, &nonce, &sealed, b"header").unwrap(),
+                b"hello"
+            );
+            assert!(suite.open(&key, &nonce, &sealed, b"other header").is_err());
+        }
+    }
+
+    #[test]
+    fn envelope_keys_depend_on_the_suite_and_the_public_keys() {
+        let aes = CipherSuite::Aes256Gcm.envelope_key(&[1; 32], &[2; 32], &[3; 32]);
+        let chacha = CipherSuite::ChaCha20Poly1305.envelope_key(&[1; 32], &[2; 32], &[3; 32]);
+        let swapped = CipherSuite::Aes256Gcm.envelope_key(&[1; 32], &[3; 32], &[2; 32]);
+        assert_ne!(aes, chacha);
+        assert_ne!(aes, swapped);
+    }
+
+    #[test]
+    fn parses_ids_and_short_names() {
+        for suite in CipherSuite::SUPPORTED {
+            assert_eq!(suite.id().parse::<CipherSuite>().unwrap(), suite);
+        }
+        assert_eq!(
+            "chacha20-poly1305".parse::<CipherSuite>().unwrap(),
+            CipherSuite::ChaCha20Poly1305
+        );
+        assert!("sha256-aes256-gcm".parse::<CipherSuite>().is_err());
+    }
+}
@@ -0,0 +1,224 @@
+use crate::crypto::b64::decode_array;
+use crate::crypto::keygen::Identity;
+use crate::crypto::keys::SecretKey;
+use crate::{Error, Result};
+use aes_gcm::aead::{Aead, KeyInit, OsRng, Payload};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use argon2::{Algorithm, Argon2, Params, Version};
+use base64::{prelude::BASE64_STANDARD as standard, Engine};
+use hkdf::Hkdf;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use zeroize::Zeroizing;
+
+pub const VAULT_VERSION: u8 = 1;
+
+/// Argon2id parameters stored alongside the ciphertext so they can be raised later
+/// without breaking existing files.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct KdfParams {
+    pub algorithm: String,
+    pub m_cost: u32,
+    pub t_cost: u32,
+    pub p_cost: u32,
+    pub salt: String,
+}
+
+impl KdfParams {
+    pub(crate) fn generate() -> Self {
+        let mut salt = [0u8; 16];
+        OsRng.fill_bytes(&mut salt);
+        KdfParams {
+            algorithm: String::from("argon2id"),
+            m_cost: 64 * 1024,
+            t_cost: 3,
+            p_cost: 1,
+            salt: standard.encode(salt),
+        }
+    }
+
+    pub(crate) fn derive_key(&self, passphrase: &str) -> Result<[u8; 32]> {
+        if self.algorithm != "argon2id" {
+            return Err(Error::rejected(format!(
+                "Unsupported KDF: {}",
+                self.algorithm
+            )));
+        }
+        let params = Params::new(self.m_cost, self.t_cost, self.p_cost, Some(32))
+            .map_err(|_| Error::rejected("Invalid KDF parameters"))?;
+        let salt = standard
+            .decode(&self.salt)
+            .map_err(|_| Error::encoding("Invalid KDF salt"))?;
+
+        let mut key = [0u8; 32];
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+            .hash_password_into(passphrase.as_bytes(), &salt, &mut key)
+            .map_err(|_| Error::rejected("Key derivation failed"))?;
+        Ok(key)
+    }
+}
+
+/// AES-256-GCM ciphertext with its random nonce.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct SealedData {
+    pub nonce: String,
+    pub ciphertext: String,
+}
+
+pub fn seal_data(key: &[u8; 32], plaintext: &[u8], aad: &[u8]) -> Result<SealedData> {
+    let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key));
+
+    let mut nonce_bytes = [0u8; 12];
+    OsRng.fill_bytes(&mut nonce_bytes);
+
+    let ciphertext = cipher
+        .encrypt(
+            Nonce::from_slice(&nonce_bytes),
+            Payload {
+                msg: plaintext,
+                aad,
+            },
+        )
+        .map_err(|_| Error::rejected("Encryption failed"))?;
+
+    Ok(SealedData {
+        nonce: standard.encode(nonce_bytes),
+        ciphertext: standard.encode(ciphertext),
+    })
+}
+
+pub fn open_data(key: &[u8; 32], sealed: &SealedData, aad: &[u8]) -> Result<Vec<u8>> {
+    let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key));
+
+    let nonce_bytes: [u8; 12] = decode_array(&sealed.nonce, "nonce")?;
+    let ciphertext = standard
+        .decode(&sealed.ciphertext)
+        .map_err(|_| Error::encoding("Invalid ciphertext encoding"))?;
+
+    cipher
+        .decrypt(
+            Nonce::from_slice(&nonce_bytes),
+            Payload {
+                msg: &ciphertext,
+                aad,
+            },
+        )
+        .map_err(|_| Error::auth("Decryption failed"))
+}
+
+/// Key for local state that belongs to an identity (sessions and the like), so
+/// that state is exactly as protected as the identity file itself.
+pub fn identity_storage_key(identity: &Identity, label: &str) -> SecretKey {
+    let mut info = b"vaultsyn-local-storage:".to_vec();
+    info.extend_from_slice(label.as_bytes());
+
+    let mut key = [0u8; 32];
+    Hkdf::<Sha256>::new(None, identity.x25519_private.expose())
+        .expand(&info, &mut key)
+        .expect("32 bytes is a valid HKDF output length");
+    SecretKey::from_bytes(key)
+}
+
+/// On-disk form of a passphrase-protected identity.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct EncryptedIdentity {
+    pub version: u8,
+    pub username: String,
+    pub kdf: KdfParams,
+    #[serde(flatten)]
+    pub sealed: SealedData,
+}
+
+// The username and format version are bound as associated data so a file
+// cannot be silently renamed or downgraded.
+fn associated_data(version: u8, username: &str) -> Vec<u8> {
+    let mut aad = format!("vaultsyn-identity-v{}:", version).into_bytes();
+    aad.extend_from_slice(username.as_bytes());
+    aad
+}
+
+pub fn seal_identity(identity: &Identity, passphrase: &str) -> Result<EncryptedIdentity> {
+    let kdf = KdfParams::generate();
+    let key = Zeroizing::new(kdf.derive_key(passphrase)?);
+
+    let plaintext = Zeroizing::new(serde_json::to_vec(identity)?);
+    let aad = associated_data(VAULT_VERSION, &identity.username);
+
+    Ok(EncryptedIdentity {
+        version: VAULT_VERSION,
+        username: identity.username.clone(),
+        kdf,
+        sealed: seal_data(&key, &plaintext, &aad)?,
+    })
+}
+
+pub fn open_identity(sealed: &EncryptedIdentity, passphrase: &str) -> Result<Identity> {
+    if sealed.version != VAULT_VERSION {
+        return Err(Error::rejected(format!(
+            "Unsupported identity file version: {}",
+            sealed.version
+        )));
+    }
+
+    let key = Zeroizing::new(sealed.kdf.derive_key(passphrase)?);
+    let aad = associated_data(sealed.version, &sealed.username);
+    let plaintext = Zeroizing::new(
+        open_data(&key, &sealed.sealed, &aad)
+            .map_err(|_| Error::auth("Wrong passphrase or corrupted identity file"))?,
+    );
+
+    serde_json::from_slice(&plaintext).map_err(|_| Error::encoding("Corrupted identity data"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::keygen::generate_identity;
+
+    #[test]
+    fn sealed_identities_open_with_the_passphrase_only() {
+        let identity = generate_identity("alice".into());
+        let sealed = seal_identity(&identity, "correct horse").unwrap();
+        assert_eq!(sealed.kdf.algorithm, "argon2id");
+
+        let opened = open_identity(&sealed, "correct horse").unwrap();
+        assert_eq!(opened.username, "alice");
+        assert_eq!(
+            opened.x25519_private.expose(),
+            identity.x25519_private.expose()
+        );
+        assert_eq!(
+            opened.ed25519_public.to_base64(),
+            identity.ed25519_public.to_base64()
+        );
+
+        let error = open_identity(&sealed, "wrong horse").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Wrong passphrase or corrupted identity file"
+        );
+    }
+
+    #[test]
+    fn renamed_or_downgraded_files_do_not_open() {
+        let identity = generate_identity("alice".into());
+        let sealed = seal_identity(&identity, "passphrase").unwrap();
+
+        let mut renamed = sealed.clone();
+        renamed.username = "mallory".into();
+        assert!(open_identity(&renamed, "passphrase").is_err());
+
+        let mut downgraded = sealed;
+        downgraded.version = 0;
+        assert!(open_identity(&downgraded, "passphrase").is_err());
+    }
+
+    #[test]
+    fn sealed_data_is_bound_to_its_key_and_associated_data() {
+        let sealed = seal_data(&[1; 32], b"secret", b"aad").unwrap();
+        assert_eq!(open_data(&[1; 32], &sealed, b"aad").unwrap(), b"secret");
+        assert!(open_data(&[2; 32], &sealed, b"aad").is_err());
+        assert!(open_data(&[1; 32], &sealed, b"other").is_err());
+    }
+}
@@ -0,0 +1,318 @@
+//! X3DH prekeys and handshake, so a session can be started while the peer is
+//! offline. The resulting shared secret seeds a Double Ratchet session.
+
+use crate::crypto::b64::{decode_array, decode_key};
+use crate::crypto::keygen::Identity;
+use crate::crypto::keys::SecretKey;
+use crate::crypto::suite::CipherSuite;
+use crate::protocol::{PrekeyBundle, PublicPrekey};
+use crate::{Error, Result};
+use aes_gcm::aead::OsRng;
+use base64::{prelude::BASE64_STANDARD as standard, Engine};
+use ed25519_dalek::{Signature, Signer, Verifier, VerifyingKey};
+use hkdf::Hkdf;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use x25519_dalek::{PublicKey as X25519Public, StaticSecret};
+
+/// Number of one-time prekeys kept available on the relay.
+pub const ONE_TIME_PREKEY_TARGET: usize = 20;
+
+#[derive(Clone, Serialize, Deserialize)]
+struct PrekeySecret {
+    id: u32,
+    secret: SecretKey,
+}
+
+impl PrekeySecret {
+    fn generate(id: u32) -> Self {
+        PrekeySecret {
+            id,
+            secret: SecretKey::from_bytes(StaticSecret::random_from_rng(OsRng).to_bytes()),
+        }
+    }
+
+    fn public(&self) -> PublicPrekey {
+        PublicPrekey {
+            id: self.id,
+            public: standard
+                .encode(X25519Public::from(&StaticSecret::from(*self.secret.expose())).as_bytes()),
+        }
+    }
+}
+
+/// Private halves of an identity's prekeys, kept next to the identity.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct PrekeyStore {
+    signed_prekey: PrekeySecret,
+    signed_prekey_signature: String,
+    one_time_prekeys: Vec<PrekeySecret>,
+    next_id: u32,
+}
+
+/// Sent in the first messages of an X3DH-initiated session until the peer replies.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct X3dhHeader {
+    pub identity_x25519: String,
+    pub ephemeral: String,
+    pub signed_prekey_id: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub one_time_prekey_id: Option<u32>,
+}
+
+pub struct X3dhInit {
+    pub shared_secret: [u8; 32],
+    pub header: X3dhHeader,
+    /// The peer's signed prekey, which doubles as its first ratchet key.
+    pub remote_signed_prekey: [u8; 32],
+}
+
+fn dh(secret: &[u8; 32], public: &[u8; 32]) -> [u8; 32] {
+    StaticSecret::from(*secret)
+        .diffie_hellman(&X25519Public::from(*public))
+        .to_bytes()
+}
+
+fn signed_prekey_message(prekey: &PublicPrekey) -> Result<Vec<u8>> {
+    let mut message = b"vaultsyn-signed-prekey".to_vec();
+    message.extend_from_slice(&prekey.id.to_be_bytes());
+    message.extend_from_slice(&decode_key(&prekey.public)?);
+    Ok(message)
+}
+
+fn derive_shared_secret(
+    dh_outputs: &[[u8; 32]],
+    initiator_x25519: &[u8; 32],
+    responder_x25519: &[u8; 32],
+) -> [u8; 32] {
+    let mut ikm = vec![0xFF; 32];
+    for output in dh_outputs {
+        ikm.extend_from_slice(output);
+    }
+    let mut info = b"vaultsyn-x3dh".to_vec();
+    info.extend_from_slice(initiator_x25519);
+    info.extend_from_slice(responder_x25519);
+
+    let mut shared = [0u8; 32];
+    Hkdf::<Sha256>::new(Some(&[0u8; 32]), &ikm)
+        .expand(&info, &mut shared)
+        .expect("32 bytes is a valid HKDF output length");
+    shared
+}
+
+impl PrekeyStore {
+    pub fn generate(identity: &Identity) -> Result<Self> {
+        let signed_prekey = PrekeySecret::generate(0);
+        let signature = identity
+            .signing_key()
+            .sign(&signed_prekey_message(&signed_prekey.public())?);
+
+        let mut store = PrekeyStore {
+            signed_prekey,
+            signed_prekey_signature: standard.encode(signature.to_bytes()),
+            one_time_prekeys: Vec::new(),
+            next_id: 1,
+        };
+        store.replenish();
+        Ok(store)
+    }
+
+    /// Tops the one-time prekeys back up after some were consumed.
+    pub fn replenish(&mut self) {
+        let missing = ONE_TIME_PREKEY_TARGET.saturating_sub(self.one_time_prekeys.len());
+        for _ in 0..missing {
+            self.one_time_prekeys
+                .push(PrekeySecret::generate(self.next_id));
+            self.next_id += 1;
+        }
+    }
+
+    pub fn bundle(&self, identity: &Identity) -> PrekeyBundle {
+        PrekeyBundle {
+            ed25519_public: identity.ed25519_public.to_base64(),
+            x25519_public: identity.x25519_public.to_base64(),
+            signed_prekey: self.signed_prekey.public(),
+            signed_prekey_signature: self.signed_prekey_signature.clone(),
+            one_time_prekeys: self.one_time_prekeys.iter().map(|k| k.public()).collect(),
+            suites: CipherSuite::SUPPORTED
+                .iter()
+                .map(|suite| suite.id().to_string())
+                .collect(),
+        }
+    }
+}
+
+/// Checks that the signed prekey really belongs to the bundle's Ed25519 identity.
+pub fn verify_bundle(bundle: &PrekeyBundle) -> Result<()> {
+    let verifying_key = VerifyingKey::from_bytes(&decode_key(&bundle.ed25519_public)?)
+        .map_err(|_| Error::encoding("Invalid public key"))?;
+    let signature =
+        Signature::from_bytes(&decode_array(&bundle.signed_prekey_signature, "signature")?);
+    verifying_key
+        .verify(&signed_prekey_message(&bundle.signed_prekey)?, &signature)
+        .map_err(|_| Error::auth("Signed prekey verification failed"))
+}
+
+/// Initiator side: runs X3DH against a fetched bundle.
+pub fn initiate(identity: &Identity, bundle: &PrekeyBundle) -> Result<X3dhInit> {
+    verify_bundle(bundle)?;
+
+    let identity_secret = identity.x25519_private.expose();
+    let identity_public = identity.x25519_public.as_bytes();
+    let remote_identity = decode_key(&bundle.x25519_public)?;
+    let remote_signed_prekey = decode_key(&bundle.signed_prekey.public)?;
+    let one_time_prekey = bundle.one_time_prekeys.first();
+
+    let ephemeral_secret = StaticSecret::random_from_rng(OsRng).to_bytes();
+    let ephemeral_public = X25519Public::from(&StaticSecret::from(ephemeral_secret));
+
+    let mut dh_outputs = vec![
+        dh(identity_secret, &remote_signed_prekey),
+        dh(&ephemeral_secret, &remote_identity),
+        dh(&ephemeral_secret, &remote_signed_prekey),
+    ];
+    if let Some(prekey) = one_time_prekey {
+        dh_outputs.push(dh(&ephemeral_secret, &decode_key(&prekey.public)?));
+    }
+
+    Ok(X3dhInit {
+        shared_secret: derive_shared_secret(&dh_outputs, identity_public, &remote_identity),
+        header: X3dhHeader {
+            identity_x25519: identity.x25519_public.to_base64(),
+            ephemeral: standard.encode(ephemeral_public.as_bytes()),
+            signed_prekey_id: bundle.signed_prekey.id,
+            one_time_prekey_id: one_time_prekey.map(|k| k.id),
+        },
+        remote_signed_prekey,
+    })
+}
+
+/// Responder side: returns the shared secret and the signed prekey secret,
+/// which becomes the first ratchet key. The one-time prekey is consumed.
+pub fn respond(
+    identity: &Identity,
+    store: &mut PrekeyStore,
+    header: &X3dhHeader,
+) -> Result<([u8; 32], [u8; 32])> {
+    if header.signed_prekey_id != store.signed_prekey.id {
+        return Err(Error::not_found("Signed prekey"));
+    }
+
+    let identity_secret = identity.x25519_private.expose();
+    let identity_public = identity.x25519_public.as_bytes();
+    let remote_identity = decode_key(&header.identity_x25519)?;
+    let remote_ephemeral = decode_key(&header.ephemeral)?;
+    let signed_prekey = *store.signed_prekey.secret.expose();
+
+    let mut dh_outputs = vec![
+        dh(&signed_prekey, &remote_identity),
+        dh(identity_secret, &remote_ephemeral),
+        dh(&signed_prekey, &remote_ephemeral),
+    ];
+    if let Some(id) = header.one_time_prekey_id {
+        let pos = store
+            .one_time_prekeys
+            .iter()
+            .position(|k| k.id == id)
+            .ok_or_else(|| Error::rejected("One-time prekey already used or unknown"))?;
+        let one_time_prekey = store.one_time_prekeys.remove(pos);
+        dh_outputs.push(dh(one_time_prekey.secret.expose(), &remote_ephemeral));
+    }
+
+    Ok((
+        derive_shared_secret(&dh_outputs, &remote_identity, identity_public),
+        signed_prekey,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::keygen::generate_identity;
+
+    fn public_of(secret: &[u8; 32]) -> [u8; 32] {
+        X25519Public::from(&StaticSecret::from(*secret)).to_bytes()
+    }
+
+    #[test]
+    fn both_sides_agree_using_a_one_time_prekey() {
+        let alice = generate_identity("alice".into());
+        let bob = generate_identity("bob".into());
+        let mut prekeys = PrekeyStore::generate(&bob).unwrap();
+        let bundle = prekeys.bundle(&bob);
+        assert_eq!(bundle.one_time_prekeys.len(), ONE_TIME_PREKEY_TARGET);
+
+        let init = initiate(&alice, &bundle).unwrap();
+        assert_eq!(
+            init.header.one_time_prekey_id,
+            Some(bundle.one_time_prekeys[0].id)
+        );
+        let (shared, ratchet_secret) = respond(&bob, &mut prekeys, &init.header).unwrap();
+        assert_eq!(shared, init.shared_secret);
+        assert_eq!(public_of(&ratchet_secret), init.remote_signed_prekey);
+
+        // The one-time prekey is gone, so the same handshake cannot be replayed
+        assert_eq!(prekeys.one_time_prekeys.len(), ONE_TIME_PREKEY_TARGET - 1);
+        assert!(respond(&bob, &mut prekeys, &init.header).is_err());
+    }
+
+    #[test]
+    fn both_sides_agree_without_one_time_prekeys() {
+        let alice = generate_identity("alice".into());
+        let bob = generate_identity("bob".into());
+        let mut prekeys = PrekeyStore::generate(&bob).unwrap();
+        let mut bundle = prekeys.bundle(&bob);
+        bundle.one_time_prekeys.clear();
+
+        let init = initiate(&alice, &bundle).unwrap();
+        assert!(init.header.one_time_prekey_id.is_none());
+        let (shared, _) = respond(&bob, &mut prekeys, &init.header).unwrap();
+        assert_eq!(shared, init.shared_secret);
+        assert_eq!(prekeys.one_time_prekeys.len(), ONE_TIME_PREKEY_TARGET);
+    }
+
+    #[test]
+    fn a_different_one_time_prekey_gives_a_different_secret() {
+        let alice = generate_identity("alice".into());
+        let bob = generate_identity("bob".into());
+        let mut prekeys = PrekeyStore::generate(&bob).unwrap();
+        let bundle = prekeys.bundle(&bob);
+
+        let mut init = initiate(&alice, &bundle).unwrap();
+        init.header.one_time_prekey_id = Some(bundle.one_time_prekeys[1].id);
+        let (shared, _) = respond(&bob, &mut prekeys, &init.header).unwrap();
+        assert_ne!(shared, init.shared_secret);
+    }
+
+    #[test]
+    fn rejects_bundles_with_a_foreign_signed_prekey() {
+        let alice = generate_identity("alice".into());
+        let bob = generate_identity("bob".into());
+        let mallory = generate_identity("mallory".into());
+        let mut bundle = PrekeyStore::generate(&bob).unwrap().bundle(&bob);
+        let forged = PrekeyStore::generate(&mallory).unwrap().bundle(&mallory);
+        bundle.signed_prekey = forged.signed_prekey;
+        bundle.signed_prekey_signature = forged.signed_prekey_signature;
+
+        assert!(verify_bundle(&bundle).is_err());
+        assert!(initiate(&alice, &bundle).is_err());
+    }
+
+    #[test]
+    fn replenish_tops_up_with_fresh_ids() {
+        let bob = generate_identity("bob".into());
+        let mut prekeys = PrekeyStore::generate(&bob).unwrap();
+        let used: Vec<u32> = prekeys
+            .one_time_prekeys
+            .drain(..5)
+            .map(|prekey| prekey.id)
+            .collect();
+        prekeys.replenish();
+
+        assert_eq!(prekeys.one_time_prekeys.len(), ONE_TIME_PREKEY_TARGET);
+        assert!(prekeys
+            .one_time_prekeys
+            .iter()
+            .all(|prekey| !used.contains(&prekey.id)));
+    }
+}
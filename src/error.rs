@@ -0,0 +1,95 @@
+//! The error type returned throughout the library.
+
+use std::fmt;
+
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    /// Base64, JSON or UTF-8 that does not decode.
+    Encoding(String),
+    /// A key, nonce or signature of the wrong size.
+    KeyLength {
+        what: &'static str,
+        expected: usize,
+        actual: usize,
+    },
+    /// A signature, AEAD tag, passphrase or relay challenge that does not
+    /// check out.
+    Auth(String),
+    /// A file or directory that cannot be read, written or removed.
+    Io(std::io::Error),
+    /// A user, contact or other named item that does not exist.
+    NotFound(String),
+    /// Well-formed input that is refused: replays, stale timestamps,
+    /// unsupported versions, mismatched keys.
+    Rejected(String),
+    /// The relay could not be reached, refused us, or broke the protocol.
+    Relay(String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    pub fn encoding(message: impl Into<String>) -> Self {
+        Error::Encoding(message.into())
+    }
+
+    pub fn auth(message: impl Into<String>) -> Self {
+        Error::Auth(message.into())
+    }
+
+    pub fn not_found(what: impl Into<String>) -> Self {
+        Error::NotFound(what.into())
+    }
+
+    pub fn rejected(message: impl Into<String>) -> Self {
+        Error::Rejected(message.into())
+    }
+
+    pub fn relay(message: impl Into<String>) -> Self {
+        Error::Relay(message.into())
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Encoding(message)
+            | Error::Auth(message)
+            | Error::Rejected(message)
+            | Error::Relay(message) => write!(f, "{}", message),
+            Error::KeyLength {
+                what,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "Invalid {} length: expected {} bytes, got {}",
+                what, expected, actual
+            ),
+            Error::Io(e) => write!(f, "{}", e),
+            Error::NotFound(what) => write!(f, "{} not found", what),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Error::Encoding(format!("Invalid JSON: {}", e))
+    }
+}
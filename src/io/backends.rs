@@ -0,0 +1,441 @@
+//! The [`KeyStore`] backends: one JSON file per record, the same files
+//! encrypted under a store passphrase, and memory.
+
+use crate::crypto::keys::SecretKey;
+use crate::crypto::vault::{open_data, seal_data, KdfParams, SealedData};
+use crate::io::storage::KeyStore;
+use crate::{Error, Result};
+use directories::ProjectDirs;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use zeroize::Zeroizing;
+
+/// Marks a directory as an encrypted store and holds its KDF parameters.
+const STORE_HEADER: &str = "store.json";
+const STORE_VERSION: u8 = 1;
+
+/// Records as files under a root directory, identities and per-identity state
+/// under `users/`, contacts under `contacts/`.
+pub struct FileStore {
+    root: PathBuf,
+}
+
+/// A [`FileStore`] whose records are sealed with AES-256-GCM under a key
+/// derived from a store passphrase. Contacts and identities stored without
+/// a passphrase are protected too; file names are not.
+pub struct EncryptedFileStore {
+    files: FileStore,
+    key: SecretKey,
+}
+
+/// Records kept in memory and lost on drop. Meant for tests and for
+/// embedding without touching the disk.
+#[derive(Default)]
+pub struct MemoryStore {
+    records: Mutex<BTreeMap<String, Zeroizing<Vec<u8>>>>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct StoreHeader {
+    version: u8,
+    kdf: KdfParams,
+    /// A known plaintext, to tell a wrong passphrase from corrupted records.
+    check: SealedData,
+}
+
+// Write to a sibling temp file and rename, so an interrupted write never
+// leaves a half-written record behind. The temp file is created owner-only
+// and synced before the rename, so the record is never readable by others
+// and never renamed into place before its contents reach the disk.
+fn write_private_file(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    // Left over from an interrupted write
+    match fs::remove_file(&tmp) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(&tmp)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    fs::rename(tmp, path)
+}
+
+// Create missing directories owner-only, so nobody else can list the
+// records in them.
+fn create_private_dir(dir: &Path) -> std::io::Result<()> {
+    let mut builder = fs::DirBuilder::new();
+    builder.recursive(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::DirBuilderExt;
+        builder.mode(0o700);
+    }
+    builder.create(dir)
+}
+
+// Overwrite with zeros and flush before unlinking, recursing into
+// directories. This does not reach copies kept by SSD wear levelling,
+// copy-on-write filesystems or backups.
+fn shred(path: &Path) -> std::io::Result<()> {
+    let metadata = fs::symlink_metadata(path)?;
+    if metadata.is_dir() {
+        for entry in fs::read_dir(path)? {
+            shred(&entry?.path())?;
+        }
+        return fs::remove_dir(path);
+    }
+    if metadata.is_file() {
+        let mut file = fs::OpenOptions::new().write(true).open(path)?;
+        file.write_all(&vec![0u8; metadata.len() as usize])?;
+        file.sync_all()?;
+    }
+    fs::remove_file(path)
+}
+
+impl FileStore {
+    /// The platform's local data directory, e.g. `~/.local/share/vaultsyn`
+    /// on Linux.
+    pub fn default_home() -> Result<PathBuf> {
+        Ok(ProjectDirs::from("com", "vaultsyn", "vaultsyn")
+            .ok_or_else(|| Error::not_found("Home directory"))?
+            .data_local_dir()
+            .to_path_buf())
+    }
+
+    /// Uses `root`, which is created on the first write.
+    pub fn open(root: impl Into<PathBuf>) -> Result<Self> {
+        let root = root.into();
+        if EncryptedFileStore::exists(&root) {
+            return Err(Error::rejected(format!(
+                "{} is an encrypted store, open it with the encrypted backend",
+                root.display()
+            )));
+        }
+        Ok(FileStore { root })
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+}
+
+impl KeyStore for FileStore {
+    fn read(&self, path: &str) -> Result<Option<Zeroizing<Vec<u8>>>> {
+        match fs::read(self.root.join(path)) {
+            Ok(data) => Ok(Some(Zeroizing::new(data))),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn write(&self, path: &str, data: &[u8]) -> Result<()> {
+        let path = self.root.join(path);
+        if let Some(dir) = path.parent() {
+            create_private_dir(dir)?;
+        }
+        Ok(write_private_file(&path, data)?)
+    }
+
+    fn remove(&self, path: &str) -> Result<()> {
+        match shred(&self.root.join(path)) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    fn list(&self, dir: &str) -> Result<Vec<String>> {
+        let entries = match fs::read_dir(self.root.join(dir)) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+        let mut names: Vec<String> = entries
+            .filter_map(|entry| {
+                let path = entry.ok()?.path();
+                if !path.is_file() || path.extension()? != "json" {
+                    return None;
+                }
+                Some(path.file_stem()?.to_str()?.to_string())
+            })
+            .collect();
+        names.sort();
+        Ok(names)
+    }
+
+    fn location(&self) -> String {
+        self.root.display().to_string()
+    }
+}
+
+fn record_aad(path: &str) -> Vec<u8> {
+    format!("vaultsyn-store-record:{}", path).into_bytes()
+}
+
+impl EncryptedFileStore {
+    /// True if `root` already holds an encrypted store.
+    pub fn exists(root: &Path) -> bool {
+        root.join(STORE_HEADER).is_file()
+    }
+
+    /// Opens the store at `root`, or creates one protected by `passphrase`
+    /// if there is none yet. A directory holding unencrypted records is
+    /// refused rather than mixed with encrypted ones.
+    pub fn open(root: impl Into<PathBuf>, passphrase: &str) -> Result<Self> {
+        let files = FileStore { root: root.into() };
+        match files.read(STORE_HEADER)? {
+            Some(json) => {
+                let header: StoreHeader = serde_json::from_slice(&json)
+                    .map_err(|_| Error::encoding("Corrupted store header"))?;
+                if header.version != STORE_VERSION {
+                    return Err(Error::rejected(format!(
+                        "Unsupported store version: {}",
+                        header.version
+                    )));
+                }
+                let key = SecretKey::from_bytes(header.kdf.derive_key(passphrase)?);
+                open_data(key.expose(), &header.check, &record_aad(STORE_HEADER))
+                    .map_err(|_| Error::auth("Wrong store passphrase"))?;
+                Ok(EncryptedFileStore { files, key })
+            }
+            None => {
+                if passphrase.is_empty() {
+                    return Err(Error::rejected("The store passphrase must not be empty"));
+                }
+                if !files.list("users")?.is_empty() || !files.list("contacts")?.is_empty() {
+                    return Err(Error::rejected(format!(
+                        "{} holds an unencrypted store",
+                        files.location()
+                    )));
+                }
+                let kdf = KdfParams::generate();
+                let key = SecretKey::from_bytes(kdf.derive_key(passphrase)?);
+                let header = StoreHeader {
+                    version: STORE_VERSION,
+                    check: seal_data(
+                        key.expose(),
+                        STORE_HEADER.as_bytes(),
+                        &record_aad(STORE_HEADER),
+                    )?,
+                    kdf,
+                };
+                files.write(STORE_HEADER, &serde_json::to_vec_pretty(&header)?)?;
+                Ok(EncryptedFileStore { files, key })
+            }
+        }
+    }
+
+    pub fn root(&self) -> &Path {
+        self.files.root()
+    }
+}
+
+impl KeyStore for EncryptedFileStore {
+    // The path is bound in as associated data, so records cannot be swapped.
+    fn read(&self, path: &str) -> Result<Option<Zeroizing<Vec<u8>>>> {
+        let Some(json) = self.files.read(path)? else {
+            return Ok(None);
+        };
+        let sealed: SealedData = serde_json::from_slice(&json)
+            .map_err(|_| Error::encoding(format!("Corrupted store record {}", path)))?;
+        open_data(self.key.expose(), &sealed, &record_aad(path))
+            .map(|data| Some(Zeroizing::new(data)))
+            .map_err(|_| Error::auth(format!("Store record {} does not decrypt", path)))
+    }
+
+    fn write(&self, path: &str, data: &[u8]) -> Result<()> {
+        let sealed = seal_data(self.key.expose(), data, &record_aad(path))?;
+        self.files.write(path, &serde_json::to_vec_pretty(&sealed)?)
+    }
+
+    fn remove(&self, path: &str) -> Result<()> {
+        self.files.remove(path)
+    }
+
+    fn list(&self, dir: &str) -> Result<Vec<String>> {
+        self.files.list(dir)
+    }
+
+    fn location(&self) -> String {
+        format!("{} (encrypted)", self.files.location())
+    }
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        MemoryStore::default()
+    }
+}
+
+impl KeyStore for MemoryStore {
+    fn read(&self, path: &str) -> Result<Option<Zeroizing<Vec<u8>>>> {
+        Ok(self.records.lock().unwrap().get(path).cloned())
+    }
+
+    fn write(&self, path: &str, data: &[u8]) -> Result<()> {
+        self.records
+            .lock()
+            .unwrap()
+            .insert(path.to_string(), Zeroizing::new(data.to_vec()));
+        Ok(())
+    }
+
+    fn remove(&self, path: &str) -> Result<()> {
+        let prefix = format!("{}/", path);
+        self.records
+            .lock()
+            .unwrap()
+            .retain(|key, _| key != path && !key.starts_with(&prefix));
+        Ok(())
+    }
+
+    fn list(&self, dir: &str) -> Result<Vec<String>> {
+        let prefix = format!("{}/", dir);
+        Ok(self
+            .records
+            .lock()
+            .unwrap()
+            .keys()
+            .filter_map(|key| key.strip_prefix(&prefix)?.strip_suffix(".json"))
+            .filter(|name| !name.contains('/'))
+            .map(str::to_string)
+            .collect())
+    }
+
+    fn location(&self) -> String {
+        "memory".to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::RngCore;
+
+    // A fresh directory under the system temp dir, removed on drop.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new() -> Self {
+            let mut suffix = [0u8; 8];
+            rand::thread_rng().fill_bytes(&mut suffix);
+            let name: String = suffix.iter().map(|b| format!("{:02x}", b)).collect();
+            TempDir(std::env::temp_dir().join(format!("vaultsyn-test-{}", name)))
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn encrypted_records_cannot_be_swapped() {
+        let dir = TempDir::new();
+        let store = EncryptedFileStore::open(&dir.0, "store passphrase").unwrap();
+        store.write("contacts/alice.json", b"alice").unwrap();
+        store.write("contacts/bob.json", b"bob").unwrap();
+        let raw = fs::read(dir.0.join("contacts/alice.json")).unwrap();
+        assert!(!raw.windows(5).any(|window| window == b"alice"));
+
+        fs::write(dir.0.join("contacts/bob.json"), &raw).unwrap();
+        assert!(store.read("contacts/bob.json").is_err());
+        assert_eq!(
+            store
+                .read("contacts/alice.json")
+                .unwrap()
+                .unwrap()
+                .as_slice(),
+            b"alice"
+        );
+        assert!(store.read("contacts/carol.json").unwrap().is_none());
+    }
+
+    #[test]
+    fn encrypted_stores_need_their_passphrase() {
+        let dir = TempDir::new();
+        EncryptedFileStore::open(&dir.0, "store passphrase").unwrap();
+        assert!(EncryptedFileStore::exists(&dir.0));
+        assert!(EncryptedFileStore::open(&dir.0, "wrong").is_err());
+        assert!(EncryptedFileStore::open(&dir.0, "store passphrase").is_ok());
+        // Nor can the plain backend open it
+        assert!(FileStore::open(&dir.0).is_err());
+    }
+
+    #[test]
+    fn plain_stores_are_not_encrypted_in_place() {
+        let dir = TempDir::new();
+        FileStore::open(&dir.0)
+            .unwrap()
+            .write("contacts/alice.json", b"{}")
+            .unwrap();
+        assert!(EncryptedFileStore::open(&dir.0, "store passphrase").is_err());
+        assert!(!EncryptedFileStore::exists(&dir.0));
+    }
+
+    #[test]
+    fn records_are_written_owner_only_and_replace_stale_temp_files() {
+        let dir = TempDir::new();
+        let store = FileStore::open(&dir.0).unwrap();
+        fs::create_dir_all(dir.0.join("users")).unwrap();
+        fs::write(dir.0.join("users/alice.tmp"), b"interrupted").unwrap();
+
+        store.write("users/alice.json", b"record").unwrap();
+        assert!(!dir.0.join("users/alice.tmp").exists());
+        assert_eq!(
+            store.read("users/alice.json").unwrap().unwrap().as_slice(),
+            b"record"
+        );
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(dir.0.join("users/alice.json"))
+                .unwrap()
+                .permissions()
+                .mode();
+            assert_eq!(mode & 0o777, 0o600);
+
+            store.write("users/alice/prekeys.json", b"record").unwrap();
+            let mode = fs::metadata(dir.0.join("users/alice"))
+                .unwrap()
+                .permissions()
+                .mode();
+            assert_eq!(mode & 0o777, 0o700);
+        }
+    }
+
+    #[test]
+    fn remove_deletes_directories_recursively() {
+        let dir = TempDir::new();
+        let store = FileStore::open(&dir.0).unwrap();
+        store.write("users/alice/sessions/peer.json", b"x").unwrap();
+        store.write("users/alice.json", b"x").unwrap();
+        store.remove("users/alice").unwrap();
+        assert!(!dir.0.join("users/alice").exists());
+        assert_eq!(store.list("users").unwrap(), vec!["alice".to_string()]);
+        // Removing what is not there is fine
+        store.remove("users/bob").unwrap();
+    }
+
+    #[test]
+    fn memory_store_lists_direct_records_only() {
+        let store = MemoryStore::new();
+        store.write("users/alice.json", b"x").unwrap();
+        store.write("users/alice/prekeys.json", b"x").unwrap();
+        assert_eq!(store.list("users").unwrap(), vec!["alice".to_string()]);
+        store.remove("users/alice").unwrap();
+        assert!(store.read("users/alice/prekeys.json").unwrap().is_none());
+        assert!(store.read("users/alice.json").unwrap().is_some());
+    }
+}
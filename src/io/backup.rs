@@ -0,0 +1,347 @@
+//! Backups of one identity together with its prekeys, sessions, replay
+//! caches and the contacts, as a single passphrase-encrypted JSON archive.
+//! Message history is not part of a backup: Vaultsyn keeps none locally.
+
+use crate::contacts::Contact;
+use crate::crypto::keygen::Identity;
+use crate::crypto::vault::{open_data, seal_data, KdfParams, SealedData};
+use crate::io::storage::{validate_username, IdentityState, KeyStore};
+use crate::{Error, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use zeroize::Zeroizing;
+
+pub const BACKUP_FORMAT: &str = "vaultsyn-backup";
+pub const BACKUP_VERSION: u8 = 1;
+
+/// The archive as written to disk. Only the format, the username and the
+/// creation time are readable without the passphrase.
+#[derive(Serialize, Deserialize)]
+pub struct BackupArchive {
+    pub format: String,
+    pub version: u8,
+    pub username: String,
+    pub created_at: DateTime<Utc>,
+    pub kdf: KdfParams,
+    #[serde(flatten)]
+    pub sealed: SealedData,
+    /// SHA-256 over the fields above, so a damaged file is reported as such
+    /// instead of as a wrong passphrase, and before the slow key derivation.
+    pub checksum: String,
+}
+
+/// The decrypted contents of an archive.
+#[derive(Serialize, Deserialize)]
+pub struct Backup {
+    pub identity: Identity,
+    pub state: IdentityState,
+    pub contacts: Vec<Contact>,
+}
+
+/// What [`restore_backup`] did with the contacts.
+pub struct RestoreReport {
+    pub contacts_added: usize,
+    /// Contacts whose backed up keys differ from the ones pinned locally.
+    /// The local keys are kept.
+    pub contacts_kept: Vec<String>,
+}
+
+// Version, username and creation time are bound as associated data, like
+// the identity file's.
+fn associated_data(version: u8, username: &str, created_at: &DateTime<Utc>) -> Vec<u8> {
+    format!(
+        "{}-v{}:{}:{}",
+        BACKUP_FORMAT,
+        version,
+        username,
+        created_at.timestamp()
+    )
+    .into_bytes()
+}
+
+impl BackupArchive {
+    fn compute_checksum(&self) -> Result<String> {
+        let mut hasher = Sha256::new();
+        for field in [
+            self.format.clone(),
+            self.version.to_string(),
+            self.username.clone(),
+            self.created_at.timestamp().to_string(),
+            serde_json::to_string(&self.kdf)?,
+            self.sealed.nonce.clone(),
+            self.sealed.ciphertext.clone(),
+        ] {
+            hasher.update((field.len() as u64).to_be_bytes());
+            hasher.update(field.as_bytes());
+        }
+        Ok(hasher
+            .finalize()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect())
+    }
+
+    /// Parses an archive and checks its format, version and checksum.
+    pub fn from_json(json: &[u8]) -> Result<Self> {
+        let archive: BackupArchive = serde_json::from_slice(json)
+            .map_err(|_| Error::encoding("Not a Vaultsyn backup, or the file is damaged"))?;
+        if archive.format != BACKUP_FORMAT {
+            return Err(Error::encoding("Not a Vaultsyn backup"));
+        }
+        if archive.version != BACKUP_VERSION {
+            return Err(Error::rejected(format!(
+                "Unsupported backup version: {}",
+                archive.version
+            )));
+        }
+        if archive.checksum != archive.compute_checksum()? {
+            return Err(Error::encoding(
+                "Backup checksum mismatch, the file is damaged",
+            ));
+        }
+        validate_username(&archive.username)?;
+        Ok(archive)
+    }
+
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+
+    pub fn open(&self, passphrase: &str) -> Result<Backup> {
+        let key = Zeroizing::new(self.kdf.derive_key(passphrase)?);
+        let aad = associated_data(self.version, &self.username, &self.created_at);
+        let plaintext = Zeroizing::new(
+            open_data(&key, &self.sealed, &aad)
+                .map_err(|_| Error::auth("Wrong backup passphrase"))?,
+        );
+        let backup: Backup = serde_json::from_slice(&plaintext)
+            .map_err(|_| Error::encoding("Corrupted backup contents"))?;
+        if backup.identity.username != self.username {
+            return Err(Error::encoding("Corrupted backup contents"));
+        }
+        Ok(backup)
+    }
+}
+
+/// Seals an unlocked identity, its state from
+/// [`KeyStore::load_identity_state`] and the contacts under `passphrase`.
+pub fn create_backup(
+    identity: &Identity,
+    state: &IdentityState,
+    contacts: &[Contact],
+    passphrase: &str,
+) -> Result<BackupArchive> {
+    if passphrase.is_empty() {
+        return Err(Error::rejected("The backup passphrase must not be empty"));
+    }
+    let plaintext = Zeroizing::new(serde_json::to_vec(&BackupRef {
+        identity,
+        state,
+        contacts,
+    })?);
+
+    let kdf = KdfParams::generate();
+    let key = Zeroizing::new(kdf.derive_key(passphrase)?);
+    let created_at = Utc::now();
+    let aad = associated_data(BACKUP_VERSION, &identity.username, &created_at);
+    let mut archive = BackupArchive {
+        format: BACKUP_FORMAT.to_string(),
+        version: BACKUP_VERSION,
+        username: identity.username.clone(),
+        created_at,
+        kdf,
+        sealed: seal_data(&key, &plaintext, &aad)?,
+        checksum: String::new(),
+    };
+    archive.checksum = archive.compute_checksum()?;
+    Ok(archive)
+}
+
+// Serializes like `Backup` without taking ownership of the identity.
+#[derive(Serialize)]
+struct BackupRef<'a> {
+    identity: &'a Identity,
+    state: &'a IdentityState,
+    contacts: &'a [Contact],
+}
+
+/// Writes a backup into `store`, encrypting the identity under `passphrase`
+/// when one is given. An existing identity of the same name is only
+/// replaced, state and all, when `force` is set. Contacts missing locally are
+/// added; pinned keys are never overwritten.
+pub fn restore_backup(
+    store: &dyn KeyStore,
+    backup: &Backup,
+    passphrase: Option<&str>,
+    force: bool,
+) -> Result<RestoreReport> {
+    let username = &backup.identity.username;
+    let replacing = store.list_identities()?.contains(username);
+    if replacing && !force {
+        return Err(Error::rejected(format!(
+            "User '{}' already exists. Pass --force to overwrite it",
+            username
+        )));
+    }
+    // The identity file is replaced in one write before the old state goes,
+    // so a failed restore never leaves the name without an identity
+    store.save_identity(&backup.identity, passphrase)?;
+    if replacing {
+        store.delete_identity_state(username)?;
+    }
+    store.save_identity_state(&backup.identity, &backup.state)?;
+
+    let mut report = RestoreReport {
+        contacts_added: 0,
+        contacts_kept: Vec::new(),
+    };
+    for contact in &backup.contacts {
+        match store.load_contact(&contact.name)? {
+            None => {
+                store.save_contact(contact)?;
+                report.contacts_added += 1;
+            }
+            Some(local)
+                if local.ed25519_public != contact.ed25519_public
+                    || local.x25519_public != contact.x25519_public =>
+            {
+                report.contacts_kept.push(contact.name.clone())
+            }
+            Some(_) => {}
+        }
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::contacts::PublicInfo;
+    use crate::crypto::keygen::generate_identity;
+    use crate::crypto::ratchet::{Session, SessionRecord};
+    use crate::crypto::x3dh::PrekeyStore;
+    use crate::io::backends::MemoryStore;
+
+    fn contact_for(identity: &Identity) -> Contact {
+        Contact::new(
+            &identity.username,
+            &PublicInfo {
+                username: identity.username.clone(),
+                ed25519_public: identity.ed25519_public.to_base64(),
+                x25519_public: identity.x25519_public.to_base64(),
+            },
+        )
+    }
+
+    // A store holding alice with prekeys, a session with bob, and bob pinned.
+    fn populated_store() -> (MemoryStore, Identity, Identity) {
+        let store = MemoryStore::new();
+        let alice = generate_identity("alice".into());
+        let bob = generate_identity("bob".into());
+        let bob_x25519 = bob.x25519_public.to_base64();
+        store.save_identity(&alice, None).unwrap();
+        store
+            .save_prekeys(&alice, &PrekeyStore::generate(&alice).unwrap())
+            .unwrap();
+        let mut record = SessionRecord::default();
+        record.promote(Session::from_static_keys(&alice, &bob_x25519).unwrap());
+        store
+            .save_session_record(&alice, &bob_x25519, &record)
+            .unwrap();
+        store.save_contact(&contact_for(&bob)).unwrap();
+        (store, alice, bob)
+    }
+
+    fn backup_of(store: &MemoryStore, identity: &Identity, passphrase: &str) -> String {
+        let state = store.load_identity_state(identity).unwrap();
+        let contacts = store.list_contacts().unwrap();
+        create_backup(identity, &state, &contacts, passphrase)
+            .unwrap()
+            .to_json()
+            .unwrap()
+    }
+
+    #[test]
+    fn backups_restore_identity_state_and_contacts() {
+        let (store, alice, bob) = populated_store();
+        let json = backup_of(&store, &alice, "backup passphrase");
+
+        let archive = BackupArchive::from_json(json.as_bytes()).unwrap();
+        assert_eq!(archive.username, "alice");
+        let backup = archive.open("backup passphrase").unwrap();
+
+        let restored = MemoryStore::new();
+        let report = restore_backup(&restored, &backup, Some("new passphrase"), false).unwrap();
+        assert_eq!(report.contacts_added, 1);
+        assert!(report.contacts_kept.is_empty());
+
+        let identity = restored.load_identity("alice", "new passphrase").unwrap();
+        assert_eq!(
+            identity.x25519_private.expose(),
+            alice.x25519_private.expose()
+        );
+        let state = restored.load_identity_state(&identity).unwrap();
+        assert!(state.prekeys.is_some());
+        assert_eq!(state.sessions.len(), 1);
+        assert_eq!(state.sessions[0].0, bob.x25519_public.to_base64());
+        assert!(state.sessions[0].1.current.is_some());
+        assert!(restored.load_contact("bob").unwrap().is_some());
+    }
+
+    #[test]
+    fn wrong_passphrases_and_damage_are_told_apart() {
+        let (store, alice, _) = populated_store();
+        let json = backup_of(&store, &alice, "backup passphrase");
+
+        let archive = BackupArchive::from_json(json.as_bytes()).unwrap();
+        let error = archive.open("wrong").err().unwrap();
+        assert_eq!(error.to_string(), "Wrong backup passphrase");
+
+        let damaged = json.replace("\"alice\"", "\"mallory\"");
+        let error = BackupArchive::from_json(damaged.as_bytes()).err().unwrap();
+        assert_eq!(
+            error.to_string(),
+            "Backup checksum mismatch, the file is damaged"
+        );
+        assert!(BackupArchive::from_json(b"{}").is_err());
+    }
+
+    #[test]
+    fn empty_backup_passphrases_are_refused() {
+        let (store, alice, _) = populated_store();
+        let state = store.load_identity_state(&alice).unwrap();
+        assert!(create_backup(&alice, &state, &[], "").is_err());
+    }
+
+    #[test]
+    fn restore_keeps_existing_identities_and_pinned_keys() {
+        let (store, alice, _) = populated_store();
+        let backup = BackupArchive::from_json(backup_of(&store, &alice, "pw").as_bytes())
+            .unwrap()
+            .open("pw")
+            .unwrap();
+
+        // bob is pinned to other keys on the machine restored to
+        let target = MemoryStore::new();
+        let other_bob = generate_identity("bob".into());
+        target.save_contact(&contact_for(&other_bob)).unwrap();
+        target
+            .save_identity(&generate_identity("alice".into()), None)
+            .unwrap();
+
+        assert!(restore_backup(&target, &backup, None, false).is_err());
+        let report = restore_backup(&target, &backup, None, true).unwrap();
+        assert_eq!(report.contacts_added, 0);
+        assert_eq!(report.contacts_kept, vec!["bob".to_string()]);
+        assert_eq!(
+            target.load_contact("bob").unwrap().unwrap().x25519_public,
+            other_bob.x25519_public.to_base64()
+        );
+        let identity = target.load_identity("alice", "").unwrap();
+        assert_eq!(
+            identity.x25519_private.expose(),
+            alice.x25519_private.expose()
+        );
+    }
+}
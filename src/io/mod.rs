@@ -1 +1,3 @@
+pub mod backends;
+pub mod backup;
 pub mod storage;
@@ -1,24 +1,579 @@
+//! Where identities, contacts and per-identity state are kept. A [`KeyStore`]
+//! backend only stores opaque records under relative paths; the provided
+//! methods decide the layout and seal per-identity state.
+
+use crate::contacts::Contact;
 use crate::crypto::keygen::Identity;
-use directories::ProjectDirs;
-use std::fs;
-use std::path::PathBuf;
+use crate::crypto::ratchet::{Session, SessionRecord};
+use crate::crypto::vault::{
+    identity_storage_key, open_data, open_identity, seal_data, seal_identity, EncryptedIdentity,
+    SealedData,
+};
+use crate::crypto::x3dh::PrekeyStore;
+use crate::message::ReplayCache;
+use crate::{Error, Result};
+use base64::{prelude::BASE64_URL_SAFE_NO_PAD as url_safe, Engine};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use zeroize::Zeroizing;
+
+/// Either format that may be found under `users/`. Plaintext files predate
+/// passphrase protection and are still accepted.
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum IdentityFile {
+    Encrypted(EncryptedIdentity),
+    Plain(Identity),
+}
+
+/// An identity's prekeys, sessions and replay caches, keyed by peer public
+/// key, e.g. to move them to another name or into a backup.
+#[derive(Default, Serialize, Deserialize)]
+pub struct IdentityState {
+    pub prekeys: Option<PrekeyStore>,
+    pub sessions: Vec<(String, SessionRecord)>,
+    pub replay: Vec<(String, ReplayCache)>,
+}
+
+/// Storage for identities, contacts and per-identity state.
+///
+/// Records are addressed by paths such as `users/alice.json` or
+/// `contacts/bob.json`. A backend implements the five raw operations and may
+/// keep records anywhere, as long as `read` returns exactly what was last
+/// written. The remaining methods are built on those and rarely need
+/// overriding. See [`crate::io::backends`] for the backends that ship with
+/// the crate.
+pub trait KeyStore: Send + Sync {
+    /// The record at `path`, or `None` if there is none.
+    fn read(&self, path: &str) -> Result<Option<Zeroizing<Vec<u8>>>>;
+
+    /// Creates or replaces the record at `path`. Readers never see a
+    /// partially written record.
+    fn write(&self, path: &str, data: &[u8]) -> Result<()>;
+
+    /// Removes the record at `path` and every record under `path/`. Removing
+    /// something that does not exist is not an error.
+    fn remove(&self, path: &str) -> Result<()>;
+
+    /// Names of the `.json` records directly in `dir`, without the
+    /// extension, sorted.
+    fn list(&self, dir: &str) -> Result<Vec<String>>;
+
+    /// Where the records are kept, for messages.
+    fn location(&self) -> String;
+
+    /// Saves the identity, encrypted under `passphrase` when one is given.
+    fn save_identity(&self, identity: &Identity, passphrase: Option<&str>) -> Result<()> {
+        let json = Zeroizing::new(match passphrase {
+            Some(passphrase) => {
+                serde_json::to_string_pretty(&seal_identity(identity, passphrase)?)?
+            }
+            None => serde_json::to_string_pretty(identity)?,
+        });
+        self.write(&identity_path(&identity.username)?, json.as_bytes())
+    }
+
+    fn read_identity_file(&self, username: &str) -> Result<IdentityFile> {
+        let json = self
+            .read(&identity_path(username)?)?
+            .ok_or_else(|| Error::not_found(format!("User '{}'", username)))?;
+        serde_json::from_slice(&json)
+            .map_err(|_| Error::encoding(format!("Corrupted identity file for '{}'", username)))
+    }
+
+    /// Loads an identity, unlocking it with `passphrase` if it is encrypted.
+    fn load_identity(&self, username: &str, passphrase: &str) -> Result<Identity> {
+        unlock_identity(self.read_identity_file(username)?, passphrase)
+    }
+
+    /// Usernames of all stored identities, sorted.
+    fn list_identities(&self) -> Result<Vec<String>> {
+        self.list("users")
+    }
+
+    /// Deletes an identity together with its sessions, prekeys and replay
+    /// caches.
+    fn delete_identity(&self, username: &str) -> Result<()> {
+        let path = identity_path(username)?;
+        if self.read(&path)?.is_none() {
+            return Err(Error::not_found(format!("User '{}'", username)));
+        }
+        self.delete_identity_state(username)?;
+        self.remove(&path)
+    }
+
+    /// Removes everything kept for an identity besides the identity itself.
+    fn delete_identity_state(&self, username: &str) -> Result<()> {
+        identity_path(username)?;
+        self.remove(&format!("users/{}", username))
+    }
+
+    /// Moves an unlocked identity and its state to `new_username`, saving it
+    /// under `passphrase`. Local state is bound to the username, so it is
+    /// sealed again rather than moved.
+    fn rename_identity(
+        &self,
+        identity: &mut Identity,
+        new_username: &str,
+        passphrase: Option<&str>,
+    ) -> Result<()> {
+        let old_username = identity.username.clone();
+        if self.read(&identity_path(new_username)?)?.is_some() {
+            return Err(Error::rejected(format!(
+                "User '{}' already exists",
+                new_username
+            )));
+        }
+
+        let state = self.load_identity_state(identity)?;
+        identity.username = new_username.to_string();
+        self.save_identity(identity, passphrase)?;
+        self.save_identity_state(identity, &state)?;
+        self.delete_identity(&old_username)
+    }
+
+    /// Everything kept for an unlocked identity besides the identity itself.
+    fn load_identity_state(&self, identity: &Identity) -> Result<IdentityState> {
+        let sessions = peers(self, &identity.username, "sessions")?
+            .into_iter()
+            .map(|peer| {
+                let record = self.load_session_record(identity, &peer)?;
+                Ok((peer, record))
+            })
+            .collect::<Result<_>>()?;
+        let replay = peers(self, &identity.username, "replay")?
+            .into_iter()
+            .map(|peer| {
+                let cache = self.load_replay_cache(identity, &peer)?;
+                Ok((peer, cache))
+            })
+            .collect::<Result<_>>()?;
+        Ok(IdentityState {
+            prekeys: self.load_prekeys(identity)?,
+            sessions,
+            replay,
+        })
+    }
+
+    /// Saves state from [`KeyStore::load_identity_state`], sealed for
+    /// `identity`.
+    fn save_identity_state(&self, identity: &Identity, state: &IdentityState) -> Result<()> {
+        if let Some(prekeys) = &state.prekeys {
+            self.save_prekeys(identity, prekeys)?;
+        }
+        for (peer, record) in &state.sessions {
+            self.save_session_record(identity, peer, record)?;
+        }
+        for (peer, cache) in &state.replay {
+            self.save_replay_cache(identity, peer, cache)?;
+        }
+        Ok(())
+    }
+
+    /// The identity used when a command is not given one.
+    fn default_identity(&self) -> Result<Option<String>> {
+        let Some(json) = self.read(SETTINGS_PATH)? else {
+            return Ok(None);
+        };
+        let settings: Settings = serde_json::from_slice(&json)
+            .map_err(|_| Error::encoding(format!("Corrupted {}", SETTINGS_PATH)))?;
+        Ok(settings.default_identity)
+    }
+
+    fn set_default_identity(&self, username: Option<&str>) -> Result<()> {
+        if let Some(username) = username {
+            identity_path(username)?;
+        }
+        let settings = Settings {
+            default_identity: username.map(str::to_string),
+        };
+        self.write(SETTINGS_PATH, &serde_json::to_vec_pretty(&settings)?)
+    }
+
+    fn save_session_record(
+        &self,
+        identity: &Identity,
+        peer_x25519_public: &str,
+        record: &SessionRecord,
+    ) -> Result<()> {
+        save_sealed(
+            self,
+            identity,
+            "sessions",
+            &session_path(&identity.username, peer_x25519_public)?,
+            &session_aad(&identity.username, peer_x25519_public),
+            record,
+        )
+    }
+
+    /// Returns an empty record if there is no session with this peer yet.
+    fn load_session_record(
+        &self,
+        identity: &Identity,
+        peer_x25519_public: &str,
+    ) -> Result<SessionRecord> {
+        let path = session_path(&identity.username, peer_x25519_public)?;
+        let aad = session_aad(&identity.username, peer_x25519_public);
+        let Some(plaintext) = open_sealed(self, identity, "sessions", &path, &aad)? else {
+            return Ok(SessionRecord::default());
+        };
+        // Files written before session records held a single session. Tried
+        // first: every field of a record is optional, so a bare session would
+        // parse as an empty record.
+        if let Ok(session) = serde_json::from_slice::<Session>(&plaintext) {
+            return Ok(SessionRecord {
+                current: Some(session),
+                previous: Vec::new(),
+            });
+        }
+        serde_json::from_slice(&plaintext)
+            .map_err(|_| Error::encoding(format!("Corrupted store record {}", path)))
+    }
+
+    fn save_prekeys(&self, identity: &Identity, store: &PrekeyStore) -> Result<()> {
+        save_sealed(
+            self,
+            identity,
+            "prekeys",
+            &prekeys_path(&identity.username)?,
+            &prekeys_aad(&identity.username),
+            store,
+        )
+    }
+
+    fn load_prekeys(&self, identity: &Identity) -> Result<Option<PrekeyStore>> {
+        load_sealed(
+            self,
+            identity,
+            "prekeys",
+            &prekeys_path(&identity.username)?,
+            &prekeys_aad(&identity.username),
+        )
+    }
+
+    /// Replay caches are keyed by the peer's signing key.
+    fn save_replay_cache(
+        &self,
+        identity: &Identity,
+        peer_ed25519_public: &str,
+        cache: &ReplayCache,
+    ) -> Result<()> {
+        save_sealed(
+            self,
+            identity,
+            "replay",
+            &peer_path(&identity.username, "replay", peer_ed25519_public)?,
+            &replay_aad(&identity.username, peer_ed25519_public),
+            cache,
+        )
+    }
+
+    /// Returns an empty cache if nothing was received from this peer yet.
+    fn load_replay_cache(
+        &self,
+        identity: &Identity,
+        peer_ed25519_public: &str,
+    ) -> Result<ReplayCache> {
+        Ok(load_sealed(
+            self,
+            identity,
+            "replay",
+            &peer_path(&identity.username, "replay", peer_ed25519_public)?,
+            &replay_aad(&identity.username, peer_ed25519_public),
+        )?
+        .unwrap_or_default())
+    }
+
+    // Contacts hold only public keys, so they are stored as plain JSON.
+    fn save_contact(&self, contact: &Contact) -> Result<()> {
+        let json = serde_json::to_string_pretty(contact)?;
+        self.write(&contact_path(&contact.name)?, json.as_bytes())
+    }
+
+    fn load_contact(&self, name: &str) -> Result<Option<Contact>> {
+        let path = contact_path(name)?;
+        let Some(json) = self.read(&path)? else {
+            return Ok(None);
+        };
+        serde_json::from_slice(&json)
+            .map(Some)
+            .map_err(|_| Error::encoding(format!("Corrupted contact {}", path)))
+    }
+
+    /// All contacts, sorted by name.
+    fn list_contacts(&self) -> Result<Vec<Contact>> {
+        let mut contacts = Vec::new();
+        for name in self.list("contacts")? {
+            // Files the store did not write, e.g. with unsafe names, are not
+            // contacts
+            if !is_safe_name(&name) {
+                continue;
+            }
+            if let Some(contact) = self.load_contact(&name)? {
+                contacts.push(contact);
+            }
+        }
+        Ok(contacts)
+    }
+
+    fn remove_contact(&self, name: &str) -> Result<()> {
+        self.remove(&contact_path(name)?)
+    }
+}
+
+pub fn unlock_identity(file: IdentityFile, passphrase: &str) -> Result<Identity> {
+    match file {
+        IdentityFile::Encrypted(sealed) => open_identity(&sealed, passphrase),
+        IdentityFile::Plain(identity) => Ok(identity),
+    }
+}
+
+/// Usernames and contact names become file names, so they are limited to
+/// a safe character set.
+pub(crate) fn is_safe_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 64
+        && !name.starts_with('.')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | '@'))
+}
+
+pub fn validate_username(username: &str) -> Result<()> {
+    if is_safe_name(username) {
+        Ok(())
+    } else {
+        Err(Error::rejected(format!(
+            "Invalid username '{}': use letters, digits, '-', '_', '.' or '@'",
+            username
+        )))
+    }
+}
+
+const SETTINGS_PATH: &str = "settings.json";
+
+#[derive(Default, Serialize, Deserialize)]
+struct Settings {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    default_identity: Option<String>,
+}
+
+fn identity_path(username: &str) -> Result<String> {
+    validate_username(username)?;
+    Ok(format!("users/{}.json", username))
+}
+
+fn contact_path(name: &str) -> Result<String> {
+    crate::contacts::validate_name(name)?;
+    Ok(format!("contacts/{}.json", name))
+}
+
+// Per-peer state lives next to the identity, one record per peer key. Peer
+// keys are re-encoded URL-safe so they are valid file names.
+fn peer_path(username: &str, kind: &str, peer_public: &str) -> Result<String> {
+    validate_username(username)?;
+    let peer = base64::prelude::BASE64_STANDARD
+        .decode(peer_public)
+        .map(|bytes| url_safe.encode(bytes))
+        .unwrap_or_else(|_| url_safe.encode(peer_public));
+    Ok(format!("users/{}/{}/{}.json", username, kind, peer))
+}
+
+// The peer keys that have state of this kind, back in standard base64.
+fn peers<S: KeyStore + ?Sized>(store: &S, username: &str, kind: &str) -> Result<Vec<String>> {
+    validate_username(username)?;
+    Ok(store
+        .list(&format!("users/{}/{}", username, kind))?
+        .iter()
+        .filter_map(|name| url_safe.decode(name).ok())
+        .map(|bytes| base64::prelude::BASE64_STANDARD.encode(bytes))
+        .collect())
+}
+
+fn session_path(username: &str, peer_x25519_public: &str) -> Result<String> {
+    peer_path(username, "sessions", peer_x25519_public)
+}
+
+fn session_aad(username: &str, peer_x25519_public: &str) -> Vec<u8> {
+    format!("vaultsyn-session:{}:{}", username, peer_x25519_public).into_bytes()
+}
 
-fn identity_path(username: &str) -> PathBuf {
-    let proj_dirs = ProjectDirs::from("com", "vaultsyn", "vaultsyn").unwrap();
-    let dir = proj_dirs.data_local_dir().join("users");
-    fs::create_dir_all(&dir).unwrap();
-    dir.join(format!("{}.json", username))
+fn prekeys_path(username: &str) -> Result<String> {
+    validate_username(username)?;
+    Ok(format!("users/{}/prekeys.json", username))
 }
 
-pub fn save_identity(identity: &Identity) -> std::io::Result<()> {
-    let path = identity_path(&identity.username);
-    let json = serde_json::to_string_pretty(identity)?;
-    fs::write(path, json)?;
-    Ok(())
+fn prekeys_aad(username: &str) -> Vec<u8> {
+    format!("vaultsyn-prekeys:{}", username).into_bytes()
 }
 
-pub fn load_identity(username: &str) -> Option<Identity> {
-    let path = identity_path(username);
-    let json = fs::read_to_string(path).ok()?;
-    serde_json::from_str(&json).ok()
+fn replay_aad(username: &str, peer_ed25519_public: &str) -> Vec<u8> {
+    format!("vaultsyn-replay:{}:{}", username, peer_ed25519_public).into_bytes()
+}
+
+// Sealed under a key derived from the identity, so local state is exactly as
+// protected as the identity itself.
+fn save_sealed<S: KeyStore + ?Sized, T: Serialize>(
+    store: &S,
+    identity: &Identity,
+    label: &str,
+    path: &str,
+    aad: &[u8],
+    value: &T,
+) -> Result<()> {
+    let key = identity_storage_key(identity, label);
+    let plaintext = Zeroizing::new(serde_json::to_vec(value)?);
+    let json = serde_json::to_string_pretty(&seal_data(key.expose(), &plaintext, aad)?)?;
+    store.write(path, json.as_bytes())
+}
+
+fn load_sealed<S: KeyStore + ?Sized, T: DeserializeOwned>(
+    store: &S,
+    identity: &Identity,
+    label: &str,
+    path: &str,
+    aad: &[u8],
+) -> Result<Option<T>> {
+    let Some(plaintext) = open_sealed(store, identity, label, path, aad)? else {
+        return Ok(None);
+    };
+    serde_json::from_slice(&plaintext)
+        .map(Some)
+        .map_err(|_| Error::encoding(format!("Corrupted store record {}", path)))
+}
+
+// `None` only when there is no record at `path`; anything unreadable is an
+// error rather than silently treated as missing.
+fn open_sealed<S: KeyStore + ?Sized>(
+    store: &S,
+    identity: &Identity,
+    label: &str,
+    path: &str,
+    aad: &[u8],
+) -> Result<Option<Zeroizing<Vec<u8>>>> {
+    let Some(json) = store.read(path)? else {
+        return Ok(None);
+    };
+    let sealed: SealedData = serde_json::from_slice(&json)
+        .map_err(|_| Error::encoding(format!("Corrupted store record {}", path)))?;
+    let key = identity_storage_key(identity, label);
+    open_data(key.expose(), &sealed, aad)
+        .map(|plaintext| Some(Zeroizing::new(plaintext)))
+        .map_err(|_| Error::auth(format!("Store record {} does not decrypt", path)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::contacts::PublicInfo;
+    use crate::crypto::keygen::generate_identity;
+    use crate::io::backends::MemoryStore;
+
+    fn contact(name: &str) -> Contact {
+        let identity = generate_identity(name.into());
+        Contact::new(
+            name,
+            &PublicInfo {
+                username: name.into(),
+                ed25519_public: identity.ed25519_public.to_base64(),
+                x25519_public: identity.x25519_public.to_base64(),
+            },
+        )
+    }
+
+    #[test]
+    fn missing_records_load_as_empty() {
+        let store = MemoryStore::new();
+        let identity = generate_identity("alice".into());
+        let peer = generate_identity("bob".into());
+        let peer_x25519 = peer.x25519_public.to_base64();
+
+        assert!(store.default_identity().unwrap().is_none());
+        assert!(store.load_contact("bob").unwrap().is_none());
+        assert!(store.list_contacts().unwrap().is_empty());
+        assert!(store.load_prekeys(&identity).unwrap().is_none());
+        assert!(store
+            .load_session_record(&identity, &peer_x25519)
+            .unwrap()
+            .current
+            .is_none());
+        store
+            .load_replay_cache(&identity, &peer.ed25519_public.to_base64())
+            .unwrap();
+    }
+
+    #[test]
+    fn unreadable_records_are_errors_not_missing() {
+        let store = MemoryStore::new();
+        let identity = generate_identity("alice".into());
+        let peer_x25519 = generate_identity("bob".into()).x25519_public.to_base64();
+        store.save_contact(&contact("bob")).unwrap();
+        store
+            .save_prekeys(&identity, &PrekeyStore::generate(&identity).unwrap())
+            .unwrap();
+        store
+            .save_session_record(&identity, &peer_x25519, &SessionRecord::default())
+            .unwrap();
+        for path in store.list("contacts").unwrap() {
+            store
+                .write(&contact_path(&path).unwrap(), b"garbage")
+                .unwrap();
+        }
+        store.write(SETTINGS_PATH, b"garbage").unwrap();
+
+        assert!(store.load_contact("bob").is_err());
+        assert!(store.list_contacts().is_err());
+        assert!(store.default_identity().is_err());
+
+        // State sealed for someone else does not open
+        let mallory = generate_identity("alice".into());
+        assert!(store.load_prekeys(&mallory).is_err());
+        assert!(store.load_session_record(&mallory, &peer_x25519).is_err());
+        assert!(store.load_identity_state(&mallory).is_err());
+    }
+
+    #[test]
+    fn identity_state_survives_a_rename() {
+        let store = MemoryStore::new();
+        let mut identity = generate_identity("alice".into());
+        let peer_x25519 = generate_identity("bob".into()).x25519_public.to_base64();
+        store.save_identity(&identity, None).unwrap();
+        store
+            .save_prekeys(&identity, &PrekeyStore::generate(&identity).unwrap())
+            .unwrap();
+        let mut record = SessionRecord::default();
+        record.promote(Session::from_static_keys(&identity, &peer_x25519).unwrap());
+        store
+            .save_session_record(&identity, &peer_x25519, &record)
+            .unwrap();
+
+        store.rename_identity(&mut identity, "carol", None).unwrap();
+        assert_eq!(store.list_identities().unwrap(), vec!["carol".to_string()]);
+        let state = store.load_identity_state(&identity).unwrap();
+        assert!(state.prekeys.is_some());
+        assert_eq!(state.sessions.len(), 1);
+        assert_eq!(state.sessions[0].0, peer_x25519);
+        assert!(state.sessions[0].1.current.is_some());
+    }
+
+    #[test]
+    fn single_session_files_still_load() {
+        let store = MemoryStore::new();
+        let identity = generate_identity("alice".into());
+        let peer_x25519 = generate_identity("bob".into()).x25519_public.to_base64();
+        let session = Session::from_static_keys(&identity, &peer_x25519).unwrap();
+        save_sealed(
+            &store,
+            &identity,
+            "sessions",
+            &session_path("alice", &peer_x25519).unwrap(),
+            &session_aad("alice", &peer_x25519),
+            &session,
+        )
+        .unwrap();
+
+        let record = store.load_session_record(&identity, &peer_x25519).unwrap();
+        assert!(record.current.is_some());
+        assert!(record.previous.is_empty());
+    }
 }
@@ -0,0 +1,21 @@
+//! Vaultsyn: end-to-end encrypted messaging through an untrusted relay.
+//!
+//! Identities are generated with [`generate_identity`] and kept in a
+//! [`KeyStore`] such as a [`FileStore`], messages are sealed into signed [`MessageEnvelope`]s by
+//! [`message`], and [`RelayClient`] carries them to other identities through
+//! a relay speaking [`protocol`]. Every fallible call returns [`Error`].
+
+pub mod client;
+pub mod contacts;
+pub mod crypto;
+mod error;
+pub mod io;
+pub mod message;
+pub mod protocol;
+
+pub use client::{RelayClient, RelaySink, RelayStream, TlsTrust};
+pub use crypto::keygen::{generate_identity, Identity};
+pub use error::{Error, Result};
+pub use io::backends::{EncryptedFileStore, FileStore, MemoryStore};
+pub use io::storage::KeyStore;
+pub use message::{EnvelopeMode, MessageEnvelope, VaultsynTransport};
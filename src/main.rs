@@ -1,56 +1,128 @@
 mod commands;
-mod crypto;
-mod io;
-mod message;
-mod network;
 
 use clap::Parser;
-use commands::cli::{Commands, VaultsynCli};
-use commands::user;
+use commands::cli::{Commands, ContactCommand, VaultsynCli};
+use commands::{backup, contact, relay, store, user};
+use vaultsyn::{Error, Result, TlsTrust};
 
-#[tokio::main]
-async fn main() {
-    let args = VaultsynCli::parse();
+async fn run(args: VaultsynCli) -> Result<()> {
+    let tls = TlsTrust {
+        ca_cert: args.tls_ca,
+        pinned_sha256: args.tls_pin,
+    };
+    let store = store::open(args.home, args.backend, args.config.as_deref())?;
 
     match args.command {
-        Commands::CreateUser { username } => {
-            user::create_user(&username);
+        Commands::CreateUser { username, force } => user::create_user(&*store, &username, force),
+        Commands::ListUsers => user::list_users(&*store),
+        Commands::DeleteUser { username, yes } => user::delete_user(&*store, &username, yes),
+        Commands::RenameUser { old, new } => user::rename_user(&*store, &old, &new),
+        Commands::DefaultUser { username } => user::default_user(&*store, username.as_deref()),
+        Commands::BackupIdentity {
+            username,
+            output,
+            force,
+        } => {
+            let username = user::or_default(&*store, username)?;
+            backup::backup_identity(&*store, &username, &output, force)
+        }
+        Commands::RestoreIdentity { input, force } => {
+            backup::restore_identity(&*store, &input, force)
+        }
+        Commands::ChangePassphrase { username } => {
+            user::change_passphrase(&*store, &user::or_default(&*store, username)?)
         }
         Commands::ExportPublicKey { username } => {
-            user::export_public_key(&username);
+            user::export_public_key(&*store, &user::or_default(&*store, username)?)
         }
         Commands::SendMessage {
             sender,
-            receiver_public_key,
+            receiver,
             message,
+            static_key,
+            suite,
         } => {
-            user::send_message(&sender, &receiver_public_key, &message);
+            // With one name given, clap fills the first slot; it is the receiver.
+            let (sender, receiver) = match (sender, receiver) {
+                (sender, Some(receiver)) => (sender, receiver),
+                (Some(receiver), None) => (None, receiver),
+                (None, None) => return Err(Error::rejected("No receiver given")),
+            };
+            let sender = user::or_default(&*store, sender)?;
+            user::send_message(&*store, &sender, &receiver, &message, static_key, suite)
         }
         Commands::ReceiveMessage {
             receiver,
+            envelope_json,
+            from,
             sender_ed25519_pub,
             sender_x25519_pub,
-            envelope_json,
+            max_clock_skew,
         } => {
+            let sender_keys = sender_ed25519_pub.zip(sender_x25519_pub);
             user::receive_message(
-                &receiver,
-                &sender_ed25519_pub,
-                &sender_x25519_pub,
+                &*store,
+                &user::or_default(&*store, receiver)?,
                 &envelope_json,
-            );
+                from.as_deref(),
+                sender_keys,
+                max_clock_skew,
+            )
+        }
+        Commands::Connect { url, username } => {
+            let username = user::or_default(&*store, username)?;
+            relay::connect_raw(&*store, &url, &username, &tls).await
         }
-        Commands::Connect { url } => {
-            network::vaultsyn_ws_client(&url, |msg| {
-                println!("📨 Received: {}", msg);
-            })
-            .await;
+        Commands::PublishPrekeys { url, username } => {
+            let username = user::or_default(&*store, username)?;
+            relay::publish_prekeys(&*store, &url, &username, &tls).await
         }
+        Commands::Verify {
+            username,
+            contact,
+            fingerprint,
+        } => {
+            let username = user::or_default(&*store, username)?;
+            contact::verify(&*store, &username, &contact, fingerprint.as_deref())
+        }
+        Commands::Contact { command } => match command {
+            ContactCommand::Add {
+                source,
+                name,
+                replace,
+            } => contact::add(&*store, &source, name.as_deref(), replace),
+            ContactCommand::List => contact::list(&*store),
+            ContactCommand::Show { name } => contact::show(&*store, &name),
+            ContactCommand::Remove { name } => contact::remove(&*store, &name),
+        },
         Commands::Chat {
             url,
             sender,
-            receiver_x25519_pub,
+            receiver,
+            suite,
+            max_clock_skew,
+            allow,
+            unverified,
+            static_session,
         } => {
-            network::vaultsyn_secure_chat(&url, &sender, &receiver_x25519_pub).await;
+            let options = relay::ChatOptions {
+                suite,
+                max_clock_skew,
+                allow,
+                unverified,
+                static_session,
+                tls,
+            };
+            let sender = user::or_default(&*store, sender)?;
+            relay::chat(store, &url, &sender, &receiver, options).await
         }
     }
 }
+
+#[tokio::main]
+async fn main() {
+    if let Err(e) = run(VaultsynCli::parse()).await {
+        eprintln!("❌ {}", e);
+        std::process::exit(1);
+    }
+}
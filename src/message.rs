@@ -1,25 +1,85 @@
-use crate::crypto::keygen::Identity;
-use aes_gcm::aead::{Aead, KeyInit, OsRng};
-use aes_gcm::{Aes256Gcm, Key, Nonce}; // GCM = AES-GCM
-use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use crate::crypto::b64::{decode_array, decode_key};
+use crate::crypto::keygen::{key_id, Identity};
+use crate::crypto::ratchet::{RatchetHeader, Session, SessionRecord};
+use crate::crypto::suite::CipherSuite;
+use crate::crypto::x3dh::{self, PrekeyStore, X3dhHeader};
+use crate::{Error, Result};
+use aes_gcm::aead::OsRng;
+use ed25519_dalek::{Signature, Signer, Verifier, VerifyingKey};
 use rand::RngCore;
-use sha2::{Digest, Sha256};
-use x25519_dalek::{PublicKey as X25519Public, StaticSecret};
+use x25519_dalek::{EphemeralSecret, PublicKey as X25519Public};
 
-// use crate::message::MessageEnvelope;
 use base64::{prelude::BASE64_STANDARD as standard, Engine};
 
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
 
-#[derive(Serialize, Deserialize)]
-pub struct MessageEnvelope {
+pub const ENVELOPE_VERSION: u8 = 1;
+
+/// How far, in seconds, a message timestamp may be from the receiver's clock.
+pub const DEFAULT_MAX_CLOCK_SKEW: i64 = 300;
+
+/// Oldest message, in seconds, accepted from a relay's offline queue: the
+/// relay's default time to live.
+pub const MAX_QUEUED_AGE: i64 = 7 * 24 * 60 * 60;
+
+/// Everything about a message except its ciphertext. From version 1 on, the
+/// whole header is AEAD associated data and covered by the Ed25519 signature,
+/// so a relay cannot rewrite any of it.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct EnvelopeHeader {
+    /// 0 for legacy envelopes, which authenticate only the ciphertext.
+    #[serde(default)]
+    pub version: u8,
+    /// Omitted for the legacy suite, so older headers keep their encoding.
+    #[serde(default, skip_serializing_if = "CipherSuite::is_legacy")]
+    pub suite: CipherSuite,
     pub from: String,
+    /// The recipient's X25519 public key, base64. Older envelopes carry the
+    /// placeholder `receiver`; receivers check `recipient_key_id` instead.
     pub to: String,
-    pub ciphertext: String,
+    /// `key_id` of the sender's Ed25519 key.
+    #[serde(default)]
+    pub sender_key_id: String,
+    /// `key_id` of the X25519 key the message is encrypted to.
+    #[serde(default)]
+    pub recipient_key_id: String,
+    /// Random id, unique per message, used to detect replays.
+    #[serde(default)]
+    pub message_id: String,
     pub nonce: String,
+    /// Unix time in seconds at which the sender created the message.
+    #[serde(default)]
+    pub timestamp: i64,
+    /// Present when the sender used a one-off X25519 key for this message.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ephemeral_public: Option<String>,
+    /// Present when the message belongs to a Double Ratchet session.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ratchet: Option<RatchetHeader>,
+    /// Present on ratchet messages that start a session via X3DH.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prekey: Option<X3dhHeader>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct MessageEnvelope {
+    #[serde(flatten)]
+    pub header: EnvelopeHeader,
+    pub ciphertext: String,
     pub signature: String,
 }
 
+/// How the sender side of the ECDH is chosen.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum EnvelopeMode {
+    /// Sender's long-term X25519 key; every message to a peer shares one AES key.
+    Static,
+    /// Fresh X25519 key per message, so a leaked sender key exposes no past traffic.
+    Ephemeral,
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct VaultsynTransport {
     pub envelope: MessageEnvelope,
@@ -27,57 +87,260 @@ pub struct VaultsynTransport {
     pub sender_x25519_pub: String,
 }
 
-pub fn encrypt_and_sign_message(
+/// Message ids already accepted from one peer. Entries are only kept while
+/// their timestamp would still pass the checks below; anything older is
+/// rejected by those alone.
+///
+/// A message's timestamp must be within the clock skew of the receiver's
+/// clock or, for one delivered from the relay's offline queue, of the time
+/// the relay queued it, and then no older than [`MAX_QUEUED_AGE`].
+///
+/// Legacy (version 0) envelopes carry neither an id nor a timestamp. They
+/// are recognised by a hash of their ciphertext instead, which is kept for
+/// good since there is no time after which they could be dropped.
+#[derive(Serialize, Deserialize)]
+pub struct ReplayCache {
+    seen: HashMap<String, i64>,
+    #[serde(default, skip_serializing_if = "HashSet::is_empty")]
+    seen_legacy: HashSet<String>,
+    #[serde(skip, default = "default_max_skew")]
+    pub max_skew: i64,
+    /// When the relay queued the envelope being checked, if it came from the
+    /// relay's offline queue.
+    #[serde(skip)]
+    pub queued_at: Option<i64>,
+}
+
+fn default_max_skew() -> i64 {
+    DEFAULT_MAX_CLOCK_SKEW
+}
+
+impl Default for ReplayCache {
+    fn default() -> Self {
+        ReplayCache {
+            seen: HashMap::new(),
+            seen_legacy: HashSet::new(),
+            max_skew: DEFAULT_MAX_CLOCK_SKEW,
+            queued_at: None,
+        }
+    }
+}
+
+fn legacy_digest(envelope: &MessageEnvelope) -> String {
+    Sha256::digest(envelope.ciphertext.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+impl ReplayCache {
+    /// Rejects duplicates and messages outside the clock-skew window.
+    pub fn check(&self, envelope: &MessageEnvelope) -> Result<()> {
+        let header = &envelope.header;
+        if header.version == 0 {
+            if self.seen_legacy.contains(&legacy_digest(envelope)) {
+                return Err(Error::rejected("Replayed message rejected"));
+            }
+            return Ok(());
+        }
+        if header.message_id.is_empty() {
+            return Err(Error::rejected(
+                "Envelope has no message id and cannot be checked for replay",
+            ));
+        }
+        let now = chrono::Utc::now().timestamp();
+        let reference = match self.queued_at {
+            Some(queued_at) if now - header.timestamp <= MAX_QUEUED_AGE => queued_at,
+            _ => now,
+        };
+        if (reference - header.timestamp).abs() > self.max_skew {
+            return Err(Error::rejected(format!(
+                "Message timestamp is outside the allowed clock skew of {}s",
+                self.max_skew
+            )));
+        }
+        if self.seen.contains_key(&header.message_id) {
+            return Err(Error::rejected("Replayed message rejected"));
+        }
+        Ok(())
+    }
+
+    /// Remembers an accepted message and forgets ids that have aged out.
+    pub fn record(&mut self, envelope: &MessageEnvelope) {
+        let header = &envelope.header;
+        if header.version == 0 {
+            self.seen_legacy.insert(legacy_digest(envelope));
+            return;
+        }
+        let cutoff = chrono::Utc::now().timestamp() - self.max_skew - MAX_QUEUED_AGE;
+        self.seen.retain(|_, timestamp| *timestamp >= cutoff);
+        self.seen
+            .insert(header.message_id.clone(), header.timestamp);
+    }
+}
+
+impl EnvelopeHeader {
+    fn new(sender: &Identity, recipient_x25519_pub: &str, suite: CipherSuite) -> Result<Self> {
+        let mut nonce_bytes = [0u8; 12];
+        OsRng.fill_bytes(&mut nonce_bytes);
+        let mut message_id = [0u8; 16];
+        OsRng.fill_bytes(&mut message_id);
+
+        Ok(EnvelopeHeader {
+            version: ENVELOPE_VERSION,
+            suite,
+            from: sender.username.clone(),
+            to: recipient_x25519_pub.to_string(),
+            sender_key_id: sender.ed25519_public.key_id(),
+            recipient_key_id: key_id(recipient_x25519_pub)?,
+            message_id: message_id.iter().map(|b| format!("{:02x}", b)).collect(),
+            nonce: standard.encode(nonce_bytes),
+            timestamp: chrono::Utc::now().timestamp(),
+            ephemeral_public: None,
+            ratchet: None,
+            prekey: None,
+        })
+    }
+
+    /// Canonical encoding used as AEAD associated data.
+    fn associated_data(&self) -> Vec<u8> {
+        if self.version == 0 {
+            return Vec::new();
+        }
+        serde_json::to_vec(self).expect("header serializes")
+    }
+
+    // Legacy envelopes signed the bare ciphertext.
+    fn signed_payload(&self, ciphertext: &[u8]) -> Vec<u8> {
+        if self.version == 0 {
+            return ciphertext.to_vec();
+        }
+        let aad = self.associated_data();
+        let mut payload = format!("vaultsyn-envelope-v{}", self.version).into_bytes();
+        payload.extend_from_slice(&(aad.len() as u64).to_be_bytes());
+        payload.extend_from_slice(&aad);
+        payload.extend_from_slice(ciphertext);
+        payload
+    }
+
+    fn nonce_bytes(&self) -> Result<[u8; 12]> {
+        decode_array(&self.nonce, "nonce")
+    }
+}
+
+fn seal(key: &[u8; 32], header: &EnvelopeHeader, plaintext: &[u8]) -> Result<Vec<u8>> {
+    let nonce_bytes = header.nonce_bytes()?;
+    header
+        .suite
+        .seal(key, &nonce_bytes, plaintext, &header.associated_data())
+}
+
+fn open(key: &[u8; 32], header: &EnvelopeHeader, ciphertext: &[u8]) -> Result<Vec<u8>> {
+    let nonce_bytes = header.nonce_bytes()?;
+    header
+        .suite
+        .open(key, &nonce_bytes, ciphertext, &header.associated_data())
+}
+
+fn sign_envelope(
     sender: &Identity,
-    receiver_public: &str,
-    message: &str,
-) -> MessageEnvelope {
-    // Decode receiver’s x25519 public key
-    let receiver_key_bytes = standard
-        .decode(receiver_public)
-        .expect("Invalid base64 public key");
-    let receiver_public = X25519Public::from(<[u8; 32]>::try_from(receiver_key_bytes).unwrap());
-
-    // Decode sender’s x25519 private key
-    let sender_secret_bytes = standard.decode(&sender.x25519_private).unwrap();
-    let sender_secret = StaticSecret::from(<[u8; 32]>::try_from(sender_secret_bytes).unwrap());
-
-    // Shared key via ECDH
-    let shared_secret = sender_secret.diffie_hellman(&receiver_public);
-    let shared_bytes = shared_secret.as_bytes();
-
-    // Derive AES key from shared secret
-    let aes_key = Sha256::digest(shared_bytes);
-    let key = Key::<Aes256Gcm>::from_slice(&aes_key[..32]);
-
-    // Create cipher
-    let cipher = Aes256Gcm::new(key);
-
-    // Random nonce
-    let mut nonce_bytes = [0u8; 12];
-    OsRng.fill_bytes(&mut nonce_bytes);
-    let nonce = Nonce::from_slice(&nonce_bytes);
-
-    // Encrypt
-    let ciphertext = cipher
-        .encrypt(nonce, message.as_bytes())
-        .expect("encryption failed");
-
-    // Create Ed25519 keypair
-    let ed_bytes = standard.decode(&sender.ed25519_private).unwrap();
-    let ed_keypair = SigningKey::from_bytes(&<[u8; 32]>::try_from(ed_bytes).unwrap());
-
-    // Sign the ciphertext
-    let signature = ed_keypair.sign(&ciphertext);
-
-    // Return envelope
-    MessageEnvelope {
-        from: sender.username.clone(),
-        to: String::from("receiver"),
+    header: EnvelopeHeader,
+    ciphertext: Vec<u8>,
+) -> Result<MessageEnvelope> {
+    let signature = sender
+        .signing_key()
+        .sign(&header.signed_payload(&ciphertext));
+
+    Ok(MessageEnvelope {
+        header,
         ciphertext: standard.encode(ciphertext),
-        nonce: standard.encode(nonce_bytes),
         signature: standard.encode(signature.to_bytes()),
+    })
+}
+
+/// Checks the signature and, for versioned envelopes, that the header names
+/// this sender and receiver. Nothing is decrypted before this passes.
+fn verify_envelope(
+    envelope: &MessageEnvelope,
+    receiver: &Identity,
+    sender_ed25519_pub: &str,
+) -> Result<Vec<u8>> {
+    let header = &envelope.header;
+    if header.version > ENVELOPE_VERSION {
+        return Err(Error::rejected(format!(
+            "Unsupported envelope version: {}",
+            header.version
+        )));
     }
+
+    let ciphertext = standard
+        .decode(&envelope.ciphertext)
+        .map_err(|_| Error::encoding("Invalid ciphertext encoding"))?;
+
+    let verifying_key = VerifyingKey::from_bytes(&decode_key(sender_ed25519_pub)?)
+        .map_err(|_| Error::encoding("Invalid public key"))?;
+    let signature = Signature::from_bytes(&decode_array(&envelope.signature, "signature")?);
+    verifying_key
+        .verify(&header.signed_payload(&ciphertext), &signature)
+        .map_err(|_| Error::auth("Signature verification failed"))?;
+
+    if header.version > 0 {
+        if header.sender_key_id != key_id(sender_ed25519_pub)? {
+            return Err(Error::rejected(
+                "Envelope sender does not match signing key",
+            ));
+        }
+        if header.recipient_key_id != receiver.x25519_public.key_id() {
+            return Err(Error::rejected(
+                "Envelope is not addressed to this identity",
+            ));
+        }
+    }
+    Ok(ciphertext)
+}
+
+pub fn encrypt_and_sign_message(
+    sender: &Identity,
+    receiver_public_b64: &str,
+    message: &str,
+    mode: EnvelopeMode,
+    suite: CipherSuite,
+) -> Result<MessageEnvelope> {
+    // Decode receiver’s x25519 public key
+    let receiver_public = X25519Public::from(decode_key(receiver_public_b64)?);
+
+    let mut header = EnvelopeHeader::new(sender, receiver_public_b64, suite)?;
+
+    // Shared key via ECDH, from either a one-off or the sender's static secret
+    let (shared_secret, sender_public) = match mode {
+        EnvelopeMode::Ephemeral => {
+            let ephemeral_secret = EphemeralSecret::random_from_rng(OsRng);
+            let ephemeral_public = X25519Public::from(&ephemeral_secret);
+            header.ephemeral_public = Some(standard.encode(ephemeral_public.as_bytes()));
+            (
+                ephemeral_secret.diffie_hellman(&receiver_public),
+                ephemeral_public,
+            )
+        }
+        EnvelopeMode::Static => {
+            let sender_secret = sender.x25519_secret();
+            (
+                sender_secret.diffie_hellman(&receiver_public),
+                X25519Public::from(&sender_secret),
+            )
+        }
+    };
+
+    // Derive the message key from the shared secret and both public keys
+    let message_key = suite.envelope_key(
+        shared_secret.as_bytes(),
+        sender_public.as_bytes(),
+        receiver_public.as_bytes(),
+    );
+
+    // Encrypt with the header as associated data, then sign both
+    let ciphertext = seal(&message_key, &header, message.as_bytes())?;
+    sign_envelope(sender, header, ciphertext)
 }
 
 pub fn decrypt_and_verify_message(
@@ -85,46 +348,336 @@ pub fn decrypt_and_verify_message(
     receiver: &Identity,
     sender_ed25519_pub: &str,
     sender_x25519_pub: &str,
-) -> Result<String, String> {
+    replay: &mut ReplayCache,
+) -> Result<String> {
+    if envelope.header.ratchet.is_some() {
+        return Err(Error::rejected("Message belongs to a ratchet session"));
+    }
+
+    // Verify signature before touching the ciphertext
+    let ciphertext = verify_envelope(envelope, receiver, sender_ed25519_pub)?;
+    replay.check(envelope)?;
+
     // Decode keys
-    let receiver_secret_bytes = standard.decode(&receiver.x25519_private).unwrap();
-    let receiver_secret = StaticSecret::from(<[u8; 32]>::try_from(receiver_secret_bytes).unwrap());
+    let receiver_secret = receiver.x25519_secret();
+    let receiver_public = X25519Public::from(&receiver_secret);
 
-    let sender_pub_bytes = standard.decode(sender_x25519_pub).unwrap();
-    let sender_public = X25519Public::from(<[u8; 32]>::try_from(sender_pub_bytes).unwrap());
+    // Ephemeral envelopes carry their own sender-side key; legacy ones use the static one
+    let sender_public = X25519Public::from(decode_key(
+        envelope
+            .header
+            .ephemeral_public
+            .as_deref()
+            .unwrap_or(sender_x25519_pub),
+    )?);
 
     // Perform X25519 ECDH
     let shared_secret = receiver_secret.diffie_hellman(&sender_public);
-    let aes_key = Sha256::digest(shared_secret.as_bytes());
-    let key = Key::<Aes256Gcm>::from_slice(&aes_key);
+    let message_key = envelope.header.suite.envelope_key(
+        shared_secret.as_bytes(),
+        sender_public.as_bytes(),
+        receiver_public.as_bytes(),
+    );
+
+    let decrypted = open(&message_key, &envelope.header, &ciphertext)?;
+    replay.record(envelope);
+    String::from_utf8(decrypted).map_err(|_| Error::encoding("Message is not valid UTF-8"))
+}
 
-    let cipher = Aes256Gcm::new(key);
+pub fn encrypt_with_session(
+    sender: &Identity,
+    session: &mut Session,
+    message: &str,
+) -> Result<MessageEnvelope> {
+    let (ratchet_header, message_key) = session.next_message_key()?;
 
-    // Fix: avoid temporary value dropped while borrowed
-    let nonce_bytes = standard.decode(&envelope.nonce).unwrap();
-    let nonce = Nonce::from_slice(&nonce_bytes);
+    let mut header = EnvelopeHeader::new(sender, &session.peer_x25519_public, session.suite)?;
+    header.ratchet = Some(ratchet_header);
+    header.prekey = session.pending_x3dh.clone();
+
+    let ciphertext = seal(&message_key, &header, message.as_bytes())?;
+    sign_envelope(sender, header, ciphertext)
+}
 
-    // Decrypt
-    let decrypted = cipher
-        .decrypt(
-            nonce,
-            standard.decode(&envelope.ciphertext).unwrap().as_ref(),
+/// Verifies the sender's signature, then decrypts with the peer's sessions.
+/// A message carrying a new X3DH handshake first builds the responding
+/// session, consuming the one-time prekey it names.
+pub fn decrypt_with_sessions(
+    envelope: &MessageEnvelope,
+    record: &mut SessionRecord,
+    receiver: &Identity,
+    prekeys: &mut PrekeyStore,
+    sender_ed25519_pub: &str,
+    sender_x25519_pub: &str,
+    replay: &mut ReplayCache,
+) -> Result<String> {
+    let header = &envelope.header;
+    let ratchet_header = header
+        .ratchet
+        .as_ref()
+        .ok_or_else(|| Error::rejected("Message is not part of a ratchet session"))?;
+
+    let ciphertext = verify_envelope(envelope, receiver, sender_ed25519_pub)?;
+    replay.check(envelope)?;
+    let open_with = |key: &[u8; 32]| open(key, header, &ciphertext);
+
+    let decrypted = match &header.prekey {
+        Some(init) if !record.has_base_key(&init.ephemeral) => {
+            if init.identity_x25519 != sender_x25519_pub {
+                return Err(Error::rejected("Handshake identity does not match sender"));
+            }
+            // Work on a copy so a failed handshake does not burn the prekey
+            let mut next_prekeys = prekeys.clone();
+            let (shared_secret, ratchet_secret) = x3dh::respond(receiver, &mut next_prekeys, init)?;
+            let mut session =
+                Session::respond(receiver, sender_x25519_pub, shared_secret, ratchet_secret)?;
+            session.base_key = Some(init.ephemeral.clone());
+            // Answer in the suite the initiator negotiated
+            if !header.suite.is_legacy() {
+                session.suite = header.suite;
+            }
+
+            let decrypted = session.decrypt(ratchet_header, &open_with)?;
+            *prekeys = next_prekeys;
+            record.promote(session);
+            decrypted
+        }
+        _ => record.decrypt(ratchet_header, &open_with)?,
+    };
+    replay.record(envelope);
+    String::from_utf8(decrypted).map_err(|_| Error::encoding("Message is not valid UTF-8"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::keygen::generate_identity;
+
+    fn send(
+        sender: &Identity,
+        receiver: &Identity,
+        text: &str,
+        mode: EnvelopeMode,
+    ) -> MessageEnvelope {
+        encrypt_and_sign_message(
+            sender,
+            &receiver.x25519_public.to_base64(),
+            text,
+            mode,
+            CipherSuite::preferred(),
         )
-        .map_err(|_| "❌ Decryption failed")?;
+        .unwrap()
+    }
 
-    // Verify signature
-    let verifying_key_bytes = standard.decode(sender_ed25519_pub).unwrap();
-    let verifying_key =
-        VerifyingKey::from_bytes(&<[u8; 32]>::try_from(verifying_key_bytes).unwrap())
-            .map_err(|_| "❌ Invalid public key")?;
+    fn receive(
+        receiver: &Identity,
+        sender: &Identity,
+        envelope: &MessageEnvelope,
+        replay: &mut ReplayCache,
+    ) -> Result<String> {
+        decrypt_and_verify_message(
+            envelope,
+            receiver,
+            &sender.ed25519_public.to_base64(),
+            &sender.x25519_public.to_base64(),
+            replay,
+        )
+    }
 
-    let signature_bytes = standard.decode(&envelope.signature).unwrap();
-    let signature = Signature::from_bytes(&<[u8; 64]>::try_from(signature_bytes).unwrap());
-    // .map_err(|_| "❌ Invalid signature format")?;
+    #[test]
+    fn ephemeral_envelopes_round_trip_with_a_fresh_key_each() {
+        let alice = generate_identity("alice".into());
+        let bob = generate_identity("bob".into());
+        let first = send(&alice, &bob, "hello", EnvelopeMode::Ephemeral);
+        let second = send(&alice, &bob, "hello", EnvelopeMode::Ephemeral);
 
-    verifying_key
-        .verify(&standard.decode(&envelope.ciphertext).unwrap(), &signature)
-        .map_err(|_| "❌ Signature verification failed")?;
+        let ephemeral = first.header.ephemeral_public.as_deref().unwrap();
+        assert_ne!(ephemeral, alice.x25519_public.to_base64());
+        assert_ne!(Some(ephemeral), second.header.ephemeral_public.as_deref());
 
-    Ok(String::from_utf8(decrypted).unwrap())
+        let mut replay = ReplayCache::default();
+        assert_eq!(receive(&bob, &alice, &first, &mut replay).unwrap(), "hello");
+        assert_eq!(
+            receive(&bob, &alice, &second, &mut replay).unwrap(),
+            "hello"
+        );
+    }
+
+    #[test]
+    fn static_envelopes_still_round_trip() {
+        let alice = generate_identity("alice".into());
+        let bob = generate_identity("bob".into());
+        let envelope = send(&alice, &bob, "hello", EnvelopeMode::Static);
+        assert!(envelope.header.ephemeral_public.is_none());
+        let mut replay = ReplayCache::default();
+        assert_eq!(
+            receive(&bob, &alice, &envelope, &mut replay).unwrap(),
+            "hello"
+        );
+    }
+
+    #[test]
+    fn only_the_recipient_can_decrypt() {
+        let alice = generate_identity("alice".into());
+        let bob = generate_identity("bob".into());
+        let eve = generate_identity("eve".into());
+        let envelope = send(&alice, &bob, "hello", EnvelopeMode::Ephemeral);
+        assert!(receive(&eve, &alice, &envelope, &mut ReplayCache::default()).is_err());
+    }
+
+    #[test]
+    fn any_header_change_breaks_the_signature() {
+        let alice = generate_identity("alice".into());
+        let bob = generate_identity("bob".into());
+        let tampers: [fn(&mut EnvelopeHeader); 5] = [
+            |header| header.from = "mallory".into(),
+            |header| header.to = "receiver".into(),
+            |header| header.timestamp += 1,
+            |header| header.message_id = "00".repeat(16),
+            |header| header.suite = CipherSuite::ChaCha20Poly1305,
+        ];
+        let envelope = send(&alice, &bob, "hello", EnvelopeMode::Ephemeral);
+        assert_eq!(envelope.header.to, bob.x25519_public.to_base64());
+        for tamper in tampers {
+            let mut envelope = send(&alice, &bob, "hello", EnvelopeMode::Ephemeral);
+            tamper(&mut envelope.header);
+            let error = receive(&bob, &alice, &envelope, &mut ReplayCache::default()).unwrap_err();
+            assert_eq!(error.to_string(), "Signature verification failed");
+        }
+    }
+
+    #[test]
+    fn rejects_envelopes_signed_by_someone_else() {
+        let alice = generate_identity("alice".into());
+        let bob = generate_identity("bob".into());
+        let mallory = generate_identity("mallory".into());
+        let envelope = send(&mallory, &bob, "hello", EnvelopeMode::Ephemeral);
+        assert!(receive(&bob, &alice, &envelope, &mut ReplayCache::default()).is_err());
+    }
+
+    #[test]
+    fn rejects_newer_envelope_versions() {
+        let alice = generate_identity("alice".into());
+        let bob = generate_identity("bob".into());
+        let mut envelope = send(&alice, &bob, "hello", EnvelopeMode::Ephemeral);
+        envelope.header.version = ENVELOPE_VERSION + 1;
+        let error = receive(&bob, &alice, &envelope, &mut ReplayCache::default()).unwrap_err();
+        assert!(error.to_string().contains("Unsupported envelope version"));
+    }
+
+    // As written before envelopes were versioned: static ECDH, the legacy
+    // suite, no id or timestamp, and a signature over the bare ciphertext.
+    fn legacy_envelope(sender: &Identity, receiver: &Identity, text: &str) -> MessageEnvelope {
+        let mut header = EnvelopeHeader::new(
+            sender,
+            &receiver.x25519_public.to_base64(),
+            CipherSuite::Legacy,
+        )
+        .unwrap();
+        header.version = 0;
+        header.to = String::from("receiver");
+        header.sender_key_id.clear();
+        header.recipient_key_id.clear();
+        header.message_id.clear();
+        header.timestamp = 0;
+        let shared = sender
+            .x25519_secret()
+            .diffie_hellman(&X25519Public::from(*receiver.x25519_public.as_bytes()));
+        let key = CipherSuite::Legacy.envelope_key(shared.as_bytes(), &[0; 32], &[0; 32]);
+        let ciphertext = seal(&key, &header, text.as_bytes()).unwrap();
+        sign_envelope(sender, header, ciphertext).unwrap()
+    }
+
+    #[test]
+    fn replayed_envelopes_are_rejected() {
+        let alice = generate_identity("alice".into());
+        let bob = generate_identity("bob".into());
+        let envelope = send(&alice, &bob, "hello", EnvelopeMode::Ephemeral);
+        let mut replay = ReplayCache::default();
+        receive(&bob, &alice, &envelope, &mut replay).unwrap();
+        let error = receive(&bob, &alice, &envelope, &mut replay).unwrap_err();
+        assert_eq!(error.to_string(), "Replayed message rejected");
+    }
+
+    #[test]
+    fn rejects_envelopes_outside_the_clock_skew_or_without_an_id() {
+        let alice = generate_identity("alice".into());
+        let bob = generate_identity("bob".into());
+        let replay = ReplayCache::default();
+
+        let mut envelope = send(&alice, &bob, "hello", EnvelopeMode::Ephemeral);
+        envelope.header.timestamp -= DEFAULT_MAX_CLOCK_SKEW + 1;
+        assert!(replay.check(&envelope).is_err());
+        envelope.header.timestamp += 2 * DEFAULT_MAX_CLOCK_SKEW + 2;
+        assert!(replay.check(&envelope).is_err());
+
+        let mut envelope = send(&alice, &bob, "hello", EnvelopeMode::Ephemeral);
+        envelope.header.message_id.clear();
+        assert!(replay.check(&envelope).is_err());
+    }
+
+    #[test]
+    fn checks_queued_envelopes_against_their_enqueue_time() {
+        let alice = generate_identity("alice".into());
+        let bob = generate_identity("bob".into());
+        let mut replay = ReplayCache::default();
+
+        // Queued a day ago, within the skew of the sender's clock
+        let mut envelope = send(&alice, &bob, "hello", EnvelopeMode::Ephemeral);
+        envelope.header.timestamp -= 24 * 60 * 60;
+        replay.queued_at = Some(envelope.header.timestamp + 10);
+        assert!(replay.check(&envelope).is_ok());
+
+        // The relay's stamp does not vouch for an envelope sent long before it
+        replay.queued_at = Some(envelope.header.timestamp + DEFAULT_MAX_CLOCK_SKEW + 1);
+        assert!(replay.check(&envelope).is_err());
+
+        // Nor for one older than the relay keeps them
+        envelope.header.timestamp -= MAX_QUEUED_AGE;
+        replay.queued_at = Some(envelope.header.timestamp);
+        assert!(replay.check(&envelope).is_err());
+    }
+
+    #[test]
+    fn legacy_envelopes_decrypt_once() {
+        let alice = generate_identity("alice".into());
+        let bob = generate_identity("bob".into());
+        let envelope = legacy_envelope(&alice, &bob, "hello");
+        let mut replay = ReplayCache::default();
+        assert_eq!(
+            receive(&bob, &alice, &envelope, &mut replay).unwrap(),
+            "hello"
+        );
+
+        // The digest of the ciphertext is kept with the cache
+        let mut replay: ReplayCache =
+            serde_json::from_str(&serde_json::to_string(&replay).unwrap()).unwrap();
+        let error = receive(&bob, &alice, &envelope, &mut replay).unwrap_err();
+        assert_eq!(error.to_string(), "Replayed message rejected");
+
+        let other = legacy_envelope(&alice, &bob, "hello again");
+        assert!(receive(&bob, &alice, &other, &mut replay).is_ok());
+    }
+
+    #[test]
+    fn envelopes_round_trip_in_every_suite() {
+        let alice = generate_identity("alice".into());
+        let bob = generate_identity("bob".into());
+        for suite in CipherSuite::SUPPORTED {
+            let envelope = encrypt_and_sign_message(
+                &alice,
+                &bob.x25519_public.to_base64(),
+                "hello",
+                EnvelopeMode::Ephemeral,
+                suite,
+            )
+            .unwrap();
+            assert_eq!(envelope.header.suite, suite);
+            let mut replay = ReplayCache::default();
+            assert_eq!(
+                receive(&bob, &alice, &envelope, &mut replay).unwrap(),
+                "hello"
+            );
+        }
+    }
 }
@@ -0,0 +1,180 @@
+//! Frames exchanged between clients and the relay, shared by the client in
+//! `client` and the relay binary (`src/bin/server.rs`).
+
+use base64::{prelude::BASE64_STANDARD as standard, Engine};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::crypto::b64::decode_key;
+use crate::Result;
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct PublicPrekey {
+    pub id: u32,
+    pub public: String,
+}
+
+/// Everything needed to start an X3DH handshake with an offline peer.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct PrekeyBundle {
+    pub ed25519_public: String,
+    pub x25519_public: String,
+    pub signed_prekey: PublicPrekey,
+    /// Ed25519 signature over the signed prekey, see `crypto::x3dh`.
+    pub signed_prekey_signature: String,
+    #[serde(default)]
+    pub one_time_prekeys: Vec<PublicPrekey>,
+    /// Cipher suite ids the owner accepts, most preferred first. Empty for
+    /// clients that only know the legacy suite.
+    #[serde(default)]
+    pub suites: Vec<String>,
+}
+
+/// Protocol versions this build speaks, oldest first.
+pub const PROTOCOL_VERSIONS: [u32; 1] = [1];
+
+/// The newest version both sides speak.
+pub fn negotiate_version(ours: &[u32], theirs: &[u32]) -> Option<u32> {
+    ours.iter().filter(|v| theirs.contains(v)).max().copied()
+}
+
+/// Every frame on a client's connection to the relay, in both directions.
+///
+/// The relay greets with `Hello`, listing the versions it speaks, and the
+/// client answers with `Auth` in a version both speak. Nothing else is
+/// accepted before that. `T` is the transport carried by `Deliver`: the
+/// relay only needs its routing fields, so it reads it as a JSON value.
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Frame<T = serde_json::Value> {
+    /// Relay greeting, with the auth challenge and a one-off relay key.
+    Hello {
+        versions: Vec<u32>,
+        challenge: String,
+        relay_x25519: String,
+    },
+    /// Answer to `Hello`. `signature` is the Ed25519 signature over
+    /// `auth_message`, and `x25519_proof` is `x25519_proof` of the X25519
+    /// secret shared with the relay key.
+    Auth {
+        version: u32,
+        ed25519_public: String,
+        x25519_public: String,
+        signature: String,
+        x25519_proof: String,
+    },
+    /// The connection now receives envelopes for this recipient key id,
+    /// starting with any queued while offline.
+    Authenticated {
+        recipient_key_id: String,
+    },
+    /// An envelope, from its sender to the relay and from the relay to the
+    /// recipient.
+    Deliver {
+        transport: T,
+        /// When the relay queued the envelope, for one delivered from its
+        /// offline queue.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        queued_at: Option<i64>,
+    },
+    /// The recipient is offline; the envelope is kept until they fetch it.
+    Queued {
+        message_id: String,
+    },
+    /// A delivered envelope was handled and can be removed from the queue.
+    Ack {
+        message_id: String,
+    },
+    /// Follow whether a recipient is connected. Answered with `Presence`
+    /// now and on every change.
+    Watch {
+        recipient_key_id: String,
+    },
+    Presence {
+        recipient_key_id: String,
+        online: bool,
+    },
+    Ping,
+    Pong,
+    /// Replace the relay's copy of the sender's bundle.
+    PublishPrekeys {
+        bundle: PrekeyBundle,
+    },
+    /// Ask for a bundle by Ed25519 or X25519 public key.
+    FetchPrekeys {
+        identity: String,
+    },
+    /// Carries at most one one-time prekey, which the relay then forgets.
+    PrekeyBundle {
+        bundle: PrekeyBundle,
+    },
+    PrekeysNotFound {
+        identity: String,
+    },
+    /// A frame from this connection could not be handled.
+    Error {
+        code: ErrorCode,
+        message: String,
+    },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    BadFrame,
+    UnsupportedVersion,
+    AuthFailed,
+    Forbidden,
+    SenderMismatch,
+    UnknownRecipient,
+    QueueFull,
+    RateLimited,
+    FrameTooLarge,
+    TooManyConnections,
+    StoreFailed,
+    /// A code from a newer relay.
+    #[serde(other)]
+    Other,
+}
+
+/// Short identifier for a public key: the first 8 bytes of its SHA-256, in hex.
+/// Anything but a base64 32-byte key is rejected.
+pub fn key_id(public_key: &str) -> Result<String> {
+    decode_key(public_key).map(|bytes| key_id_of(&bytes))
+}
+
+/// `key_id` of an already decoded key.
+pub(crate) fn key_id_of(public_key: &[u8; 32]) -> String {
+    Sha256::digest(public_key)[..8]
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// What a client signs to answer a relay challenge. Binds both of the
+/// client's keys and the chosen protocol version to this challenge and this
+/// relay key.
+pub fn auth_message(
+    version: u32,
+    challenge: &str,
+    relay_x25519: &str,
+    ed25519_public: &str,
+    x25519_public: &str,
+) -> Vec<u8> {
+    format!(
+        "vaultsyn-relay-auth-v{}\n{}\n{}\n{}\n{}",
+        version, challenge, relay_x25519, ed25519_public, x25519_public
+    )
+    .into_bytes()
+}
+
+/// Shows the client holds the private half of its X25519 key, from the
+/// secret it shares with the relay's one-off key.
+pub fn x25519_proof(shared_secret: &[u8; 32], challenge: &str) -> String {
+    let digest = Sha256::new()
+        .chain_update(b"vaultsyn-relay-auth-x25519")
+        .chain_update(shared_secret)
+        .chain_update(challenge.as_bytes())
+        .finalize();
+    standard.encode(digest)
+}